@@ -0,0 +1,105 @@
+//! Time full runs of the engine over a CSV input, sending transactions to the workers
+//! one by one and in batches, then parsing its lines with `FromStr` and with
+//! `Transaction::parse_into`.
+//!
+//! Runs on generated transactions by default, or on the CSV file at `PENGUIN_BENCH_INPUT`
+//! for realistic data: `cargo bench -p libpenguin --features test-utils`.
+
+use libpenguin::prelude::*;
+use std::{
+    env, fs,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+/// Clients and transactions of the generated input.
+const CLIENTS: u16 = 1_000;
+const TRANSACTIONS: usize = 200_000;
+/// Timed runs, after one warm-up run.
+const RUNS: u32 = 10;
+/// Transactions per channel message in the batched variant.
+const BATCH_SIZE: NonZeroUsize = NonZeroUsize::new(64).unwrap();
+
+fn input() -> String {
+    match env::var_os("PENGUIN_BENCH_INPUT") {
+        Some(path) => fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("can't read {}: {err}", path.to_string_lossy())),
+        None => generate_csv(CLIENTS, TRANSACTIONS, 42),
+    }
+}
+
+async fn run(input: &str, num_workers: NonZeroUsize, batch_size: NonZeroUsize) -> usize {
+    let reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(input.as_bytes());
+    let mut penguin = PenguinBuilder::from_csv_reader(reader)
+        .with_num_workers(num_workers)
+        .with_batch_size(batch_size)
+        .build()
+        .expect("engine should build");
+
+    penguin.run().await.expect("run should succeed").len()
+}
+
+/// Mean time to parse every line of `lines` over `RUNS` passes, after a warm-up one.
+fn parse_all(lines: &[&str], mut parse: impl FnMut(&str) -> Transaction) -> Duration {
+    let mut elapsed = Duration::ZERO;
+    for run in 0..=RUNS {
+        let started = Instant::now();
+        for line in lines {
+            std::hint::black_box(parse(line));
+        }
+        if run > 0 {
+            elapsed += started.elapsed();
+        }
+    }
+
+    elapsed / RUNS
+}
+
+fn main() {
+    let input = input();
+    let rows = input.lines().count().saturating_sub(1);
+    let num_workers = std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("runtime should start");
+
+    for batch_size in [NonZeroUsize::MIN, BATCH_SIZE] {
+        let clients = runtime.block_on(run(&input, num_workers, batch_size));
+        let mut elapsed = Duration::ZERO;
+        for _ in 0..RUNS {
+            let started = Instant::now();
+            runtime.block_on(run(&input, num_workers, batch_size));
+            elapsed += started.elapsed();
+        }
+
+        let mean = elapsed / RUNS;
+        println!(
+            "engine: {rows} rows, {clients} clients, {num_workers} workers, \
+             {batch_size} per send: {mean:?} per run, {:.0} rows/s",
+            rows as f64 / mean.as_secs_f64()
+        );
+    }
+
+    let lines: Vec<&str> = input.lines().skip(1).collect();
+    let mut scratch = ParseScratch::new();
+    for (parser, mean) in [
+        (
+            "FromStr",
+            parse_all(&lines, |line| line.parse().expect("valid line")),
+        ),
+        (
+            "parse_into",
+            parse_all(&lines, |line| {
+                Transaction::parse_into(line, &mut scratch).expect("valid line")
+            }),
+        ),
+    ] {
+        println!(
+            "parse: {rows} rows with {parser}: {mean:?} per pass, {:.0} rows/s",
+            rows as f64 / mean.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{
+    columnar::{decimal_from_be_bytes, transaction_from_columns},
+    types::{PenguinError, Transaction},
+};
+use apache_avro::{Decimal as AvroDecimal, Reader, Schema, types::Value};
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+};
+
+/// Iterator over the records of an Avro Object Container File with `type`, `client`, `tx`
+/// and `amount` fields (plus an optional `currency` one), usable with
+/// [`PenguinBuilder::from_reader`](crate::prelude::PenguinBuilder::from_reader).
+///
+/// Amounts of the `decimal` logical type are converted exactly, using the scale of the
+/// file's schema.
+pub struct AvroReader {
+    records: Reader<'static, BufReader<File>>,
+    /// Scale of the `amount` field when it is a decimal.
+    amount_scale: Option<u32>,
+}
+
+impl AvroReader {
+    /// Open an Avro Object Container File for reading.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, PenguinError> {
+        let records = Reader::new(BufReader::new(File::open(path)?)).map_err(io::Error::other)?;
+        let amount_scale = amount_scale(records.writer_schema());
+
+        Ok(Self {
+            records,
+            amount_scale,
+        })
+    }
+}
+
+impl Iterator for AvroReader {
+    type Item = Result<Transaction, PenguinError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.records.next()?;
+
+        Some(
+            record
+                .map_err(|err| PenguinError::IO(io::Error::other(err)))
+                .and_then(|record| transaction_from_record(&record, self.amount_scale)),
+        )
+    }
+}
+
+/// Find the scale of the `amount` field, possibly in a union with `null`.
+fn amount_scale(schema: &Schema) -> Option<u32> {
+    fn decimal_scale(schema: &Schema) -> Option<u32> {
+        match schema {
+            Schema::Decimal(decimal) => u32::try_from(decimal.scale).ok(),
+            Schema::Union(union) => union.variants().iter().find_map(decimal_scale),
+            _ => None,
+        }
+    }
+
+    let Schema::Record(record) = schema else {
+        return None;
+    };
+    record
+        .fields
+        .iter()
+        .find(|field| field.name == "amount")
+        .and_then(|field| decimal_scale(&field.schema))
+}
+
+/// Map a record to a [`Transaction`] by field name.
+fn transaction_from_record(
+    record: &Value,
+    amount_scale: Option<u32>,
+) -> Result<Transaction, PenguinError> {
+    let Value::Record(record) = record else {
+        return Err(PenguinError::TransactionParse(Cow::Borrowed(
+            "avro value is not a record",
+        )));
+    };
+
+    transaction_from_columns(record.iter().map(|(name, value)| (name, value)), |value| {
+        value_to_string(value, amount_scale)
+    })
+}
+
+fn value_to_string(value: &Value, amount_scale: Option<u32>) -> Result<String, PenguinError> {
+    Ok(match value {
+        Value::Null => String::new(),
+        Value::Union(_, value) => value_to_string(value, amount_scale)?,
+        Value::String(value) | Value::Enum(_, value) => value.clone(),
+        Value::Int(value) => value.to_string(),
+        Value::Long(value) => value.to_string(),
+        Value::Decimal(value) => decimal_to_string(value, amount_scale)?,
+        other => {
+            return Err(PenguinError::TransactionParse(Cow::Owned(format!(
+                "unsupported avro value: {other:?}"
+            ))));
+        }
+    })
+}
+
+/// Convert an Avro decimal without loss.
+fn decimal_to_string(value: &AvroDecimal, scale: Option<u32>) -> Result<String, PenguinError> {
+    let bytes = Vec::<u8>::try_from(value)
+        .map_err(|_| PenguinError::TransactionParse(Cow::Borrowed("amount out of range")))?;
+
+    decimal_from_be_bytes(&bytes, scale.unwrap_or(0)).map(|amount| amount.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::columnar::assert_matches_csv_equivalent;
+    use apache_avro::{Writer, types::Record};
+
+    fn write_fixture(path: &Path) {
+        let schema = Schema::parse_str(
+            r#"{
+                "type": "record",
+                "name": "transaction",
+                "fields": [
+                    {"name": "type", "type": "string"},
+                    {"name": "client", "type": "int"},
+                    {"name": "tx", "type": "long"},
+                    {"name": "amount", "type": ["null", {
+                        "type": "bytes",
+                        "logicalType": "decimal",
+                        "precision": 18,
+                        "scale": 5
+                    }]}
+                ]
+            }"#,
+        )
+        .expect("valid schema");
+        let mut writer = Writer::new(&schema, Vec::new());
+
+        for (tx_type, client, tx, amount) in [
+            ("deposit", 1, 1, Some(123_456_i64)),
+            ("deposit", 2, 2, Some(200_000)),
+            ("withdrawal", 1, 3, Some(50_000)),
+            ("dispute", 2, 2, None),
+        ] {
+            let mut record = Record::new(writer.schema()).expect("record schema");
+            record.put("type", tx_type);
+            record.put("client", client);
+            record.put("tx", tx);
+            record.put(
+                "amount",
+                match amount {
+                    Some(amount) => Value::Union(
+                        1,
+                        Box::new(Value::Decimal(AvroDecimal::from(amount.to_be_bytes()))),
+                    ),
+                    None => Value::Union(0, Box::new(Value::Null)),
+                },
+            );
+            writer.append(record).expect("write record");
+        }
+
+        std::fs::write(path, writer.into_inner().expect("flush writer")).expect("write fixture");
+    }
+
+    #[tokio::test]
+    async fn avro_records_match_csv_equivalent() {
+        let path = std::env::temp_dir().join(format!("penguin-{}.avro", std::process::id()));
+        write_fixture(&path);
+
+        assert_matches_csv_equivalent(AvroReader::from_path(&path).expect("open fixture")).await;
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,144 @@
+use crate::types::{PenguinError, Transaction, TransactionFormat};
+use rust_decimal::Decimal;
+use std::borrow::Cow;
+
+/// Columns read from each row of a columnar file, in the order [`Transaction`] expects
+/// them.
+const COLUMNS: [&str; 5] = ["type", "client", "tx", "amount", "currency"];
+
+/// Map the named values of a Parquet row or an Avro record to a [`Transaction`], reusing
+/// the text parser so errors match other inputs.
+///
+/// Only the [`COLUMNS`] are converted with `to_string`, other values are left alone.
+/// Missing columns are read as empty values.
+pub(crate) fn transaction_from_columns<N, V>(
+    values: impl IntoIterator<Item = (N, V)>,
+    mut to_string: impl FnMut(V) -> Result<String, PenguinError>,
+) -> Result<Transaction, PenguinError>
+where
+    N: AsRef<str>,
+{
+    let mut fields = [const { String::new() }; COLUMNS.len()];
+    for (name, value) in values {
+        if let Some(index) = COLUMNS.iter().position(|column| *column == name.as_ref()) {
+            fields[index] = to_string(value)?;
+        }
+    }
+
+    Transaction::from_fields(
+        fields.iter().map(String::as_str),
+        &TransactionFormat::default(),
+    )
+}
+
+/// Convert the big-endian two's complement unscaled value of an Avro or Parquet decimal
+/// to a [`Decimal`] with `scale` places, without loss.
+pub(crate) fn decimal_from_be_bytes(bytes: &[u8], scale: u32) -> Result<Decimal, PenguinError> {
+    let out_of_range = || PenguinError::TransactionParse(Cow::Borrowed("amount out of range"));
+
+    if bytes.len() > 16 {
+        return Err(out_of_range());
+    }
+    let fill = if bytes.first().is_some_and(|byte| byte & 0x80 != 0) {
+        0xFF
+    } else {
+        0x00
+    };
+    let mut unscaled = [fill; 16];
+    unscaled[16 - bytes.len()..].copy_from_slice(bytes);
+
+    Decimal::try_from_i128_with_scale(i128::from_be_bytes(unscaled), scale)
+        .map_err(|_| out_of_range())
+}
+
+/// Run `reader` and the CSV lines its fixture holds through the engine, and check both
+/// end with the same balances.
+///
+/// Fixtures hold a deposit of 1.23456 for client 1, one of 2.0 for client 2, a
+/// withdrawal of 0.5 by client 1 and a dispute of client 2's deposit.
+#[cfg(test)]
+pub(crate) async fn assert_matches_csv_equivalent(
+    reader: impl Iterator<Item = Result<Transaction, PenguinError>> + Send + 'static,
+) {
+    use crate::prelude::{ClientState, PenguinBuilder};
+
+    let summary = |mut states: Vec<ClientState>| {
+        states.sort_by_key(|state| state.client);
+        states
+            .into_iter()
+            .map(|state| {
+                (
+                    state.client,
+                    state.available,
+                    state.held,
+                    state.total,
+                    state.locked,
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let from_columns = PenguinBuilder::from_reader(reader)
+        .build()
+        .expect("valid builder")
+        .run()
+        .await
+        .expect("valid rows");
+
+    let lines = [
+        "deposit, 1, 1, 1.23456",
+        "deposit, 2, 2, 2.0",
+        "withdrawal, 1, 3, 0.5",
+        "dispute, 2, 2,",
+    ];
+    let from_csv = PenguinBuilder::from_reader(lines.into_iter().map(str::parse::<Transaction>))
+        .build()
+        .expect("valid builder")
+        .run()
+        .await
+        .expect("valid lines");
+
+    let from_columns = summary(from_columns);
+    assert_eq!(from_columns, summary(from_csv));
+    assert_eq!(from_columns[0].1, Decimal::new(7346, 4));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decimals_from_be_bytes_keep_sign_and_scale() {
+        let decode = |bytes: &[u8], scale| decimal_from_be_bytes(bytes, scale).ok();
+
+        assert_eq!(
+            decode(&123_456_i64.to_be_bytes(), 5),
+            Some(Decimal::new(123_456, 5))
+        );
+        assert_eq!(
+            decode(&(-5_i16).to_be_bytes(), 1),
+            Some(Decimal::new(-5, 1))
+        );
+        assert_eq!(decode(&[], 0), Some(Decimal::ZERO));
+        assert_eq!(decode(&[0; 17], 0), None);
+        assert_eq!(decode(&i128::MAX.to_be_bytes(), 0), None);
+    }
+
+    #[test]
+    fn columns_are_matched_by_name_and_others_ignored() {
+        let values = [
+            ("tx", "7"),
+            ("note", "not converted"),
+            ("type", "deposit"),
+            ("client", "3"),
+            ("amount", "1.5"),
+        ];
+        let tx = transaction_from_columns(values, |value| {
+            assert_ne!(value, "not converted");
+            Ok(value.to_owned())
+        })
+        .expect("valid columns");
+
+        assert_eq!(tx, Transaction::deposit(3, 7, Decimal::new(15, 1)));
+    }
+}
@@ -0,0 +1,122 @@
+use crate::types::{PenguinError, Transaction};
+use csv_async::{AsyncReaderBuilder, DeserializeRecordsIntoStream, Trim};
+use futures_util::{Stream, StreamExt};
+use std::{
+    borrow::Cow,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{fs::File, io::AsyncRead};
+
+/// Stream of the transactions in a CSV source with `type, client, tx, amount` headers
+/// (plus an optional `currency` one), read without blocking the runtime, usable with
+/// [`Penguin::run_stream`](crate::prelude::Penguin::run_stream).
+///
+/// Fields are trimmed and records deserialized like with
+/// [`PenguinBuilder::from_csv_reader`](crate::prelude::PenguinBuilder::from_csv_reader),
+/// and `csv` errors are turned into [`PenguinError::TransactionParse`].
+pub struct AsyncCsvReader<R>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    records: DeserializeRecordsIntoStream<'static, R, Transaction>,
+}
+
+impl<R> AsyncCsvReader<R>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    /// Read transactions from an already opened source.
+    pub fn from_reader(reader: R) -> Self {
+        Self {
+            records: AsyncReaderBuilder::new()
+                .trim(Trim::All)
+                .create_deserializer(reader)
+                .into_deserialize(),
+        }
+    }
+}
+
+impl AsyncCsvReader<File> {
+    /// Open a CSV file for reading.
+    pub async fn from_path(path: impl AsRef<Path>) -> Result<Self, PenguinError> {
+        Ok(Self::from_reader(File::open(path).await?))
+    }
+}
+
+impl<R> Stream for AsyncCsvReader<R>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    type Item = Result<Transaction, PenguinError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.records.poll_next_unpin(cx).map(|record| {
+            record.map(|record| {
+                record.map_err(|err| PenguinError::TransactionParse(Cow::Owned(err.to_string())))
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::{ClientState, PenguinBuilder};
+
+    const CSV: &str = "type, client, tx, amount\n\
+                       deposit, 1, 1, 10.0\n\
+                       deposit, 2, 2, 5.0\n\
+                       withdrawal, 1, 3, 2.5\n\
+                       dispute, 2, 2,\n\
+                       deposit, 3, 4, 1.2345\n";
+
+    fn summary(mut states: Vec<ClientState>) -> Vec<(u16, String, String, bool)> {
+        states.sort_by_key(|state| state.client);
+        states
+            .into_iter()
+            .map(|state| {
+                (
+                    state.client,
+                    state.available.to_string(),
+                    state.held.to_string(),
+                    state.locked,
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn async_csv_matches_sync_csv() {
+        let path = std::env::temp_dir().join(format!("penguin-async-{}.csv", std::process::id()));
+        std::fs::write(&path, CSV).expect("write fixture");
+
+        let sync = PenguinBuilder::from_csv_reader(
+            csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_path(&path)
+                .expect("open fixture"),
+        )
+        .without_log_file()
+        .build()
+        .expect("valid builder")
+        .run()
+        .await
+        .expect("sync run should succeed");
+
+        let reader = AsyncCsvReader::from_path(&path)
+            .await
+            .expect("open fixture");
+        let streamed = PenguinBuilder::from_transactions(Vec::<Transaction>::new())
+            .without_log_file()
+            .build()
+            .expect("valid builder")
+            .run_stream(reader)
+            .await
+            .expect("streamed run should succeed");
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(summary(streamed), summary(sync));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::types::Transaction;
+use rust_decimal::Decimal;
+use std::{fmt::Write, iter};
+
+/// Generate `num_txs` transactions spread over clients `1..=num_clients`, the same ones
+/// for the same `seed`, to benchmark or test the engine without an input file.
+///
+/// Roughly 60% are deposits, 25% withdrawals and 15% disputes. Withdrawals never exceed
+/// the client's available funds, and disputes always reference one of the client's
+/// earlier deposits, each disputed at most once.
+///
+/// ```
+/// # use libpenguin::prelude::*;
+/// let txs: Vec<_> = generate_transactions(10, 1_000, 42).collect();
+///
+/// assert_eq!(txs.len(), 1_000);
+/// assert!(txs.iter().all(|tx| (1..=10).contains(&tx.client)));
+/// ```
+pub fn generate_transactions(
+    num_clients: u16,
+    num_txs: usize,
+    seed: u64,
+) -> impl Iterator<Item = Transaction> {
+    let mut rng = SplitMix64(seed);
+    let mut clients = vec![Generated::default(); usize::from(num_clients)];
+    let mut next_tx = 1_u32;
+
+    let next = move || {
+        let index = rng.below(clients.len() as u64) as usize;
+        let client_id = index as u16 + 1;
+        let client = &mut clients[index];
+        let roll = rng.below(100);
+
+        if roll >= 85 && !client.undisputed.is_empty() {
+            let pick = rng.below(client.undisputed.len() as u64) as usize;
+            let (tx, cents) = client.undisputed.swap_remove(pick);
+            client.balance = client.balance.saturating_sub(cents);
+            return Transaction::dispute(client_id, tx);
+        }
+
+        let tx = next_tx;
+        next_tx = next_tx.wrapping_add(1);
+        if roll >= 60 && client.balance > 0 {
+            let cents = 1 + rng.below(client.balance);
+            client.balance -= cents;
+            return Transaction::withdrawal(client_id, tx, Decimal::new(cents as i64, 2));
+        }
+
+        let cents = 1 + rng.below(100_000);
+        client.balance += cents;
+        client.undisputed.push((tx, cents));
+        Transaction::deposit(client_id, tx, Decimal::new(cents as i64, 2))
+    };
+
+    let num_txs = if num_clients == 0 { 0 } else { num_txs };
+    iter::repeat_with(next).take(num_txs)
+}
+
+/// The transactions of [`generate_transactions`] as a CSV file with a
+/// `type,client,tx,amount` header, e.g. to benchmark the CSV path too.
+///
+/// ```
+/// # use libpenguin::prelude::*;
+/// let csv = generate_csv(10, 100, 42);
+///
+/// assert_eq!(csv.lines().count(), 101);
+/// ```
+pub fn generate_csv(num_clients: u16, num_txs: usize, seed: u64) -> String {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx in generate_transactions(num_clients, num_txs, seed) {
+        let amount = tx
+            .amount
+            .map(|amount| amount.to_string())
+            .unwrap_or_default();
+        // Writing to a `String` can't fail.
+        let _ = writeln!(csv, "{},{},{},{amount}", tx.tx_type, tx.client, tx.tx);
+    }
+
+    csv
+}
+
+/// What the generator tracks of a client to keep its transactions valid.
+#[derive(Clone, Default)]
+struct Generated {
+    /// Funds available to withdraw, in cents.
+    balance: u64,
+    /// Deposits not disputed yet, with their amount in cents.
+    undisputed: Vec<(u32, u64)>,
+}
+
+/// SplitMix64, small and stable across releases, unlike an external RNG crate.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A value in `0..bound`, with `bound` greater than zero.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TransactionType;
+    use std::collections::HashSet;
+
+    #[test]
+    fn same_seed_generates_the_same_transactions() {
+        let first: Vec<_> = generate_transactions(50, 5_000, 7).collect();
+        let second: Vec<_> = generate_transactions(50, 5_000, 7).collect();
+        let other: Vec<_> = generate_transactions(50, 5_000, 8).collect();
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn generated_csv_reads_back_as_the_generated_transactions() {
+        let csv = generate_csv(5, 200, 3);
+        let reader = csv::ReaderBuilder::new().from_reader(csv.as_bytes());
+        let read: Vec<Transaction> = reader
+            .into_deserialize()
+            .collect::<Result<_, _>>()
+            .expect("valid csv");
+
+        assert!(!read.is_empty());
+        assert_eq!(read, generate_transactions(5, 200, 3).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn disputes_reference_earlier_deposits() {
+        let mut deposits = HashSet::new();
+        let mut disputes = 0;
+        for tx in generate_transactions(20, 2_000, 1) {
+            match tx.tx_type {
+                TransactionType::Deposit => {
+                    deposits.insert((tx.client, tx.tx));
+                }
+                TransactionType::Dispute => {
+                    disputes += 1;
+                    assert!(deposits.contains(&(tx.client, tx.tx)), "{tx:?}");
+                }
+                _ => {}
+            }
+        }
+
+        assert!(disputes > 0);
+    }
+}
@@ -33,22 +33,162 @@
 //! let _output = penguin.run().await?;
 //! ```
 //!
+//! ## Push-style ingestion
+//!
+//! When transactions arrive from elsewhere (e.g. a network stream) instead of an
+//! iterator, [`Penguin::sink`] returns a [`prelude::PenguinSink`] implementing
+//! `futures::Sink<Transaction>`. Push transactions into it, then await
+//! [`prelude::PenguinSink::finish`] to get the client states. [`Penguin::run_stream`]
+//! does the same for a whole `Stream` of transactions.
+//!
 //! ## Logging
 //!
-//! If you want background logs while piping stdout, set a log file with
-//! [`PenguinBuilder::with_logger`]. Logs use `tracing` and respect `RUST_LOG`.
+//! Nothing is logged by default, and no global `tracing` subscriber is installed. If you
+//! want background logs while piping stdout, set a log file with
+//! [`PenguinBuilder::with_logger`]; [`PenguinBuilder::without_logger`] turns every log
+//! output off again. Logs use `tracing` and respect `RUST_LOG`.
+//! [`PenguinBuilder::with_log_format`] switches to JSON lines for log aggregation, and
+//! [`PenguinBuilder::with_stderr_logs`] also (or, with
+//! [`PenguinBuilder::without_log_file`], only) writes them to stderr.
+//! [`PenguinBuilder::with_log_rotation`] rolls the log file over hourly or daily instead
+//! of truncating it on every run; [`prelude::Logger::builder`] offers the same options,
+//! plus a cap on the number of rolled files, outside the engine.
+//!
+//! ## Run reports
+//!
+//! [`Penguin::run_stats`] summarizes the last run: rows read and skipped, transactions
+//! per type, distinct clients, locked accounts and duration.
+//! [`PenguinBuilder::with_report_path`] also writes it as JSON after every run.
 //!
 //! ## Error handling
 //!
 //! `PenguinError` captures I/O, parsing, and transaction errors. Invalid business
 //! operations (like disputes of unknown transactions) are ignored and logged.
+//!
+//! Reader errors fail the run on their line, as a `PenguinError::InvalidRow` wrapping
+//! the reader's error, or a `PenguinError::FieldCount` for rows with a wrong number of
+//! fields.
+//!
+//! Breaking change: the reader's error type, for the engine and `summarize_input`,
+//! must now convert into `PenguinError` (`E: Into<PenguinError>`) so its details reach
+//! the caller. Readers that can't fail use `Infallible`; other error types need a
+//! `From` implementation, or a `map_err` on the reader, e.g. into
+//! `PenguinError::TransactionParse`.
+//!
+//! ## Partial disputes
+//!
+//! A dispute may carry an amount to hold only part of the referenced deposit; the rest
+//! can be disputed later. A resolve may likewise release part of what is held, while a
+//! chargeback reverses everything held for the deposit. Without an amount, disputes and
+//! resolves apply to the whole remaining amount, as before.
+//!
+//! ## Currencies
+//!
+//! Transactions may carry an optional `currency` (fifth column). Each client keeps
+//! separate balances per currency in [`prelude::ClientState::currencies`], and disputes,
+//! resolves and chargebacks only apply to a deposit in the same currency (or when they
+//! name no currency at all). Use [`prelude::ClientState::rows`] to emit one output row per
+//! client and currency, and add [`prelude::Column::Currency`] to a [`prelude::StateSchema`]
+//! to write which currency each row is in.
+//!
+//! Migration note: inputs without a currency behave exactly as before, and their
+//! balances are read through [`prelude::ClientState::available`],
+//! [`prelude::ClientState::held`] and [`prelude::ClientState::total`]. The serialized
+//! output doesn't change, the `currency` column is opt-in.
+//!
+//! ## Metrics
+//!
+//! With the `metrics` feature enabled, the engine reports processed and ignored
+//! transaction counters, an active worker gauge and, with
+//! [`PenguinBuilder::with_backpressure_retries`], a counter of full worker channels
+//! through the `metrics` facade.
+//! Install any compatible exporter (e.g. Prometheus) to collect them.
+//!
+//! ## Parquet input
+//!
+//! The `parquet` feature adds `ParquetReader`, which yields transactions from a Parquet
+//! file with `type`, `client`, `tx` and `amount` columns.
+//!
+//! ## Avro input
+//!
+//! The `avro` feature adds `AvroReader`, which yields transactions from the records of
+//! an Avro Object Container File with `type`, `client`, `tx` and `amount` fields.
+//! Amounts may use the `decimal` logical type.
+//!
+//! ## Asynchronous CSV input
+//!
+//! The `csv-async` feature adds `AsyncCsvReader`, a `Stream` of the transactions in a
+//! CSV file read without blocking the runtime, for [`Penguin::run_stream`].
+//!
+//! ## Generated input
+//!
+//! The `test-utils` feature adds `generate_transactions`, a deterministic generator of
+//! deposits, withdrawals and disputes for benchmarks and tests that shouldn't depend on
+//! an input file, and `generate_csv` to get them as CSV text. The `engine` benchmark
+//! runs on generated input unless `PENGUIN_BENCH_INPUT` points at a real CSV file.
+//!
+//! ## Faster hashing
+//!
+//! Internal maps keyed by client and transaction ids use the standard SipHash hasher.
+//! The `fxhash` feature switches them to the faster Fx hasher, which is not resistant to
+//! hash flooding, so only enable it for trusted input.
+//!
+//! ## Output sinks
+//!
+//! Client states can be stored through any [`prelude::StateSink`]. The `sqlite` feature
+//! provides `SqliteSink`, which upserts them into a `clients` table, and
+//! [`prelude::TeeSink`] writes them to several sinks in one pass.
+#[cfg(feature = "avro")]
+mod avro_reader;
+#[cfg(any(feature = "avro", feature = "parquet"))]
+mod columnar;
+#[cfg(feature = "csv-async")]
+mod csv_async_reader;
+#[cfg(feature = "test-utils")]
+mod fixtures;
 mod logger;
+#[cfg(feature = "parquet")]
+mod parquet_reader;
 mod penguin;
+mod processed;
+mod report;
+mod schema;
+mod sink;
+mod snapshot;
+mod stats;
+mod stream;
+mod telemetry;
 mod types;
+mod verify;
 
 pub mod prelude {
+    #[cfg(feature = "avro")]
+    pub use super::avro_reader::AvroReader;
+    #[cfg(feature = "csv-async")]
+    pub use super::csv_async_reader::AsyncCsvReader;
+    #[cfg(feature = "test-utils")]
+    pub use super::fixtures::{generate_csv, generate_transactions};
+    #[cfg(feature = "parquet")]
+    pub use super::parquet_reader::ParquetReader;
+    #[cfg(feature = "sqlite")]
+    pub use super::sink::SqliteSink;
     pub use super::{
-        penguin::{Penguin, PenguinBuilder},
-        types::{ClientState, PenguinError, Transaction, TransactionType},
+        logger::{LogFormat, LogRotation, Logger, LoggerBuilder},
+        penguin::{
+            CsvReader, InMemoryReader, Penguin, PenguinBuilder, PenguinSink, Preflight,
+            QueueMonitor, ValidationReport,
+        },
+        report::{report, report_with_precision},
+        schema::{Column, StateSchema},
+        sink::{EvictedStates, SinkErrors, StateSink, TeeSink, VecSink},
+        stats::{RunStats, summarize_input},
+        stream::TransactionStream,
+        telemetry::{IgnoreClassifier, IgnoreReason, Outcome},
+        types::{
+            Balances, ClientDetails, ClientRow, ClientState, OutputOrder, ParseScratch,
+            PenguinError, Transaction, TransactionFormat, TransactionType,
+        },
+        verify::Mismatch,
     };
+    pub use tokio_util::sync::CancellationToken;
 }
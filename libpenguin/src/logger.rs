@@ -1,32 +1,325 @@
-use std::{io, path::Path};
+use std::{
+    io,
+    num::NonZero,
+    path::{Path, PathBuf},
+};
+use tracing::Subscriber;
+use tracing_appender::rolling::{self, RollingFileAppender};
+use tracing_subscriber::{
+    Layer, fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt,
+};
 
-/// Tracing logger that keeps a background guard alive.
-/// Tracing can keep sending logs messages as long this guard is alive.
+/// Layout of each log line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Single-line human-readable format.
+    #[default]
+    Full,
+    /// Multi-line, indented human-readable format.
+    Pretty,
+    /// Shorter single-line format.
+    Compact,
+    /// One JSON object per line, for log aggregation.
+    Json,
+}
+
+/// How often the log file is rolled over to a new one.
+///
+/// Rolled files are named after the configured file, suffixed with the date (and hour).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRotation {
+    /// Start a new file every hour.
+    Hourly,
+    /// Start a new file every day.
+    Daily,
+    /// Keep appending to the configured file.
+    Never,
+}
+
+impl From<LogRotation> for rolling::Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Hourly => rolling::Rotation::HOURLY,
+            LogRotation::Daily => rolling::Rotation::DAILY,
+            LogRotation::Never => rolling::Rotation::NEVER,
+        }
+    }
+}
+
+/// Tracing logger that keeps its background guards alive.
+/// Tracing can keep sending logs messages as long these guards are alive.
 pub struct Logger {
-    _guard: tracing_appender::non_blocking::WorkerGuard,
+    _guards: Vec<tracing_appender::non_blocking::WorkerGuard>,
 }
 
 impl Logger {
-    /// Initialize tracing to a file and return a guard
+    /// Initialize tracing to a file, writing lines in `format`, and return a guard
     /// Log level respect the `RUST_LOG` env filter.
-    pub fn try_init_from_path(path: impl AsRef<Path>) -> io::Result<Self> {
-        let file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(path)?;
-        let (non_blocking, _guard) = tracing_appender::non_blocking(file);
+    pub fn try_init_from_path(path: impl AsRef<Path>, format: LogFormat) -> io::Result<Self> {
+        Self::try_init(Some(path.as_ref()), false, format)
+    }
+
+    /// Initialize tracing to stderr only, e.g. for local debugging.
+    pub fn try_init_stderr(format: LogFormat) -> io::Result<Self> {
+        Self::try_init(None, true, format)
+    }
+
+    /// Initialize tracing to a file, stderr, or both through a single subscriber.
+    ///
+    /// Only one global subscriber can exist: once one is set, every further attempt
+    /// returns an error instead of replacing it.
+    pub fn try_init(path: Option<&Path>, stderr: bool, format: LogFormat) -> io::Result<Self> {
+        let builder = Logger::builder().with_stderr(stderr).with_format(format);
+        let builder = match path {
+            Some(path) => builder.with_file(path),
+            None => builder,
+        };
+
+        builder.try_init()
+    }
+
+    /// Start configuring a logger, e.g. to rotate its log file.
+    pub fn builder() -> LoggerBuilder {
+        LoggerBuilder::default()
+    }
+}
+
+/// Builder for a [`Logger`] writing to a file, stderr, or both.
+///
+/// Without a rotation, the log file is truncated on init like
+/// [`Logger::try_init_from_path`] does.
+#[derive(Debug, Default)]
+pub struct LoggerBuilder {
+    path: Option<PathBuf>,
+    stderr: bool,
+    format: LogFormat,
+    rotation: Option<LogRotation>,
+    max_files: Option<usize>,
+}
+
+impl LoggerBuilder {
+    /// Write logs to the file at `path`.
+    pub fn with_file(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Also write logs to stderr.
+    pub fn with_stderr(self, enabled: bool) -> Self {
+        Self {
+            stderr: enabled,
+            ..self
+        }
+    }
+
+    /// Choose how log lines are formatted.
+    pub fn with_format(self, format: LogFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    /// Roll the log file over on a schedule, appending to the current one instead of
+    /// truncating it.
+    pub fn with_rotation(self, rotation: LogRotation) -> Self {
+        Self {
+            rotation: Some(rotation),
+            ..self
+        }
+    }
+
+    /// Delete the oldest rolled files beyond `max_files`. Only applies with a rotation.
+    pub fn with_max_log_files(self, max_files: NonZero<usize>) -> Self {
+        Self {
+            max_files: Some(max_files.get()),
+            ..self
+        }
+    }
+
+    /// Install the global subscriber.
+    ///
+    /// Only one global subscriber can exist: once one is set, every further attempt
+    /// returns an error instead of replacing it.
+    pub fn try_init(self) -> io::Result<Logger> {
+        let mut guards = Vec::new();
+
+        let file_layer = match &self.path {
+            Some(path) => {
+                let (non_blocking, guard) = match self.rotation {
+                    Some(rotation) => {
+                        tracing_appender::non_blocking(self.rolling_appender(path, rotation)?)
+                    }
+                    None => tracing_appender::non_blocking(
+                        std::fs::OpenOptions::new()
+                            .create(true)
+                            .write(true)
+                            .truncate(true)
+                            .open(path)?,
+                    ),
+                };
+                guards.push(guard);
+                Some(fmt_layer(non_blocking, self.format))
+            }
+            None => None,
+        };
+        let stderr_layer = if self.stderr {
+            let (non_blocking, guard) = tracing_appender::non_blocking(io::stderr());
+            guards.push(guard);
+            Some(fmt_layer(non_blocking, self.format))
+        } else {
+            None
+        };
         let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
             .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
 
-        tracing_subscriber::fmt()
-            .with_writer(non_blocking)
-            .with_env_filter(env_filter)
-            .with_ansi(false)
-            .with_target(false)
+        tracing_subscriber::registry()
+            .with(env_filter)
+            .with(file_layer)
+            .with(stderr_layer)
             .try_init()
             .map_err(io::Error::other)?;
 
-        Ok(Logger { _guard })
+        Ok(Logger { _guards: guards })
+    }
+
+    /// Appender rolling the file at `path` over, keeping rolled files next to it.
+    fn rolling_appender(
+        &self,
+        path: &Path,
+        rotation: LogRotation,
+    ) -> io::Result<RollingFileAppender> {
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| io::Error::other(format!("{} is not a file", path.display())))?;
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        let mut builder = RollingFileAppender::builder()
+            .rotation(rotation.into())
+            .filename_prefix(file_name.to_string_lossy());
+        if let Some(max_files) = self.max_files {
+            builder = builder.max_log_files(max_files);
+        }
+
+        builder.build(directory).map_err(io::Error::other)
+    }
+}
+
+/// Formatting layer writing to `writer` in the given `format`.
+fn fmt_layer<S, W>(writer: W, format: LogFormat) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(false)
+        .with_target(false);
+
+    match format {
+        LogFormat::Full => layer.boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // The only test installing the global subscriber; the others use scoped ones.
+    #[test]
+    fn stderr_logging_initializes_once() {
+        let _logger = Logger::try_init_stderr(LogFormat::Compact).expect("first init");
+        tracing::info!("stderr logging is up");
+
+        let err = Logger::try_init_stderr(LogFormat::Compact)
+            .err()
+            .expect("second init is rejected");
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn rolling_appender_writes_log_lines() {
+        let directory =
+            std::env::temp_dir().join(format!("penguin-rolling-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+
+        let appender = Logger::builder()
+            .with_max_log_files(NonZero::new(2).unwrap())
+            .rolling_appender(&directory.join("penguin.log"), LogRotation::Daily)
+            .expect("rolling appender");
+        let (non_blocking, guard) = tracing_appender::non_blocking(appender);
+        let subscriber =
+            tracing_subscriber::registry().with(fmt_layer(non_blocking, LogFormat::Compact));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("rolling logs are up");
+        });
+        drop(guard);
+
+        let files: Vec<_> = std::fs::read_dir(&directory)
+            .expect("log directory exists")
+            .map(|entry| entry.expect("readable entry").path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert!(
+            files[0]
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("penguin.log.")
+        );
+        let logs = std::fs::read_to_string(&files[0]).expect("readable log file");
+        assert!(logs.contains("rolling logs are up"));
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn json_format_writes_one_json_object_per_line() {
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::registry().with(fmt_layer(
+            {
+                let buffer = buffer.clone();
+                move || buffer.clone()
+            },
+            LogFormat::Json,
+        ));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(client = 1, tx = 2, "insufficient funds for withdrawal");
+        });
+
+        let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value =
+            serde_json::from_str(logs.lines().next().expect("one log line")).expect("valid JSON");
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(
+            line["fields"]["message"],
+            "insufficient funds for withdrawal"
+        );
+        assert_eq!(line["fields"]["client"], 1);
+        assert_eq!(line["fields"]["tx"], 2);
     }
 }
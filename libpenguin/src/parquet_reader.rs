@@ -0,0 +1,154 @@
+use crate::{
+    columnar::{decimal_from_be_bytes, transaction_from_columns},
+    types::{PenguinError, Transaction},
+};
+use parquet::{
+    data_type::Decimal as ParquetDecimal,
+    file::reader::SerializedFileReader,
+    record::{Field, reader::RowIter},
+};
+use std::{borrow::Cow, fs::File, io, path::Path};
+
+/// Iterator over the rows of a Parquet file with `type`, `client`, `tx` and `amount`
+/// columns (plus an optional `currency` one), usable with
+/// [`PenguinBuilder::from_reader`](crate::prelude::PenguinBuilder::from_reader).
+///
+/// Decimal amounts are converted exactly, so the usual 4 decimal rounding happens on
+/// the original value rather than on an intermediate float.
+pub struct ParquetReader {
+    rows: RowIter<'static>,
+}
+
+impl ParquetReader {
+    /// Open a Parquet file for reading.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, PenguinError> {
+        let reader = SerializedFileReader::new(File::open(path)?).map_err(io::Error::other)?;
+
+        Ok(Self {
+            rows: RowIter::from_file_into(Box::new(reader)),
+        })
+    }
+}
+
+impl Iterator for ParquetReader {
+    type Item = Result<Transaction, PenguinError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let row = self.rows.next()?;
+
+        Some(
+            row.map_err(|err| PenguinError::IO(io::Error::other(err)))
+                .and_then(|row| transaction_from_columns(row.get_column_iter(), field_to_string)),
+        )
+    }
+}
+
+fn field_to_string(field: &Field) -> Result<String, PenguinError> {
+    Ok(match field {
+        Field::Null => String::new(),
+        Field::Str(value) => value.clone(),
+        Field::Byte(value) => value.to_string(),
+        Field::Short(value) => value.to_string(),
+        Field::Int(value) => value.to_string(),
+        Field::Long(value) => value.to_string(),
+        Field::UByte(value) => value.to_string(),
+        Field::UShort(value) => value.to_string(),
+        Field::UInt(value) => value.to_string(),
+        Field::ULong(value) => value.to_string(),
+        Field::Decimal(value) => decimal_to_string(value)?,
+        other => {
+            return Err(PenguinError::TransactionParse(Cow::Owned(format!(
+                "unsupported parquet value: {other}"
+            ))));
+        }
+    })
+}
+
+/// Convert a Parquet decimal without loss.
+fn decimal_to_string(value: &ParquetDecimal) -> Result<String, PenguinError> {
+    let scale = u32::try_from(value.scale())
+        .map_err(|_| PenguinError::TransactionParse(Cow::Borrowed("amount out of range")))?;
+
+    decimal_from_be_bytes(value.data(), scale).map(|amount| amount.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::columnar::assert_matches_csv_equivalent;
+    use parquet::{
+        data_type::{ByteArray, ByteArrayType, Int32Type, Int64Type},
+        file::{properties::WriterProperties, writer::SerializedFileWriter},
+        schema::parser::parse_message_type,
+    };
+    use std::sync::Arc;
+
+    fn write_fixture(path: &Path) {
+        let schema = parse_message_type(
+            "message transaction {
+                REQUIRED BYTE_ARRAY type (UTF8);
+                REQUIRED INT32 client (INTEGER(16, false));
+                REQUIRED INT32 tx (INTEGER(32, false));
+                OPTIONAL INT64 amount (DECIMAL(18, 5));
+            }",
+        )
+        .expect("valid schema");
+        let file = File::create(path).expect("create fixture");
+        let mut writer = SerializedFileWriter::new(
+            file,
+            Arc::new(schema),
+            Arc::new(WriterProperties::builder().build()),
+        )
+        .expect("parquet writer");
+        let mut row_group = writer.next_row_group().expect("row group");
+
+        let mut column = row_group.next_column().unwrap().expect("type column");
+        column
+            .typed::<ByteArrayType>()
+            .write_batch(
+                &[
+                    ByteArray::from("deposit"),
+                    ByteArray::from("deposit"),
+                    ByteArray::from("withdrawal"),
+                    ByteArray::from("dispute"),
+                ],
+                None,
+                None,
+            )
+            .expect("write type");
+        column.close().expect("close type");
+
+        let mut column = row_group.next_column().unwrap().expect("client column");
+        column
+            .typed::<Int32Type>()
+            .write_batch(&[1, 2, 1, 2], None, None)
+            .expect("write client");
+        column.close().expect("close client");
+
+        let mut column = row_group.next_column().unwrap().expect("tx column");
+        column
+            .typed::<Int32Type>()
+            .write_batch(&[1, 2, 3, 2], None, None)
+            .expect("write tx");
+        column.close().expect("close tx");
+
+        let mut column = row_group.next_column().unwrap().expect("amount column");
+        column
+            .typed::<Int64Type>()
+            .write_batch(&[123_456, 200_000, 50_000], Some(&[1, 1, 1, 0]), None)
+            .expect("write amount");
+        column.close().expect("close amount");
+
+        row_group.close().expect("close row group");
+        writer.close().expect("close writer");
+    }
+
+    #[tokio::test]
+    async fn parquet_rows_match_csv_equivalent() {
+        let path = std::env::temp_dir().join(format!("penguin-{}.parquet", std::process::id()));
+        write_fixture(&path);
+
+        assert_matches_csv_equivalent(ParquetReader::from_path(&path).expect("open fixture")).await;
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -1,451 +1,6362 @@
-use crate::{logger::Logger, types::*};
+use crate::{
+    logger::{LogFormat, LogRotation, Logger},
+    processed,
+    sink::{EvictedStates, StateSink},
+    snapshot,
+    stats::RunStats,
+    stream::TransactionStream,
+    telemetry::{self, IgnoreClassifier, IgnoreReason, Outcome},
+    types::*,
+    verify::{self, Mismatch},
+};
+use futures_util::{FutureExt, Sink, SinkExt, Stream, StreamExt};
 use rust_decimal::Decimal;
-use std::{collections::HashMap, num::NonZero, path::PathBuf};
-use tokio::{sync::mpsc, task::JoinSet};
-use tracing::{error, warn};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque, btree_map, hash_map::Entry},
+    convert::Infallible,
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::{self, Write as _},
+    iter, mem,
+    num::NonZero,
+    path::{Path, PathBuf},
+    pin::{Pin, pin},
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicIsize, AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::mpsc::{
+        self,
+        error::{SendError, TrySendError},
+    },
+    task::{self, JoinSet},
+    time,
+};
+use tokio_util::sync::{CancellationToken, PollSender};
+use tracing::{Instrument, debug, debug_span, error, info, trace, warn};
 
 /// Core engine that consumes transactions and produces client states.
 pub struct Penguin<T> {
     reader: T,
     num_workers: usize,
+    type_filter: Option<HashSet<TransactionType>>,
+    channel_capacity: usize,
+    backpressure: Option<Backpressure>,
+    batch_size: usize,
+    minor_units: Option<u32>,
+    /// Read negative deposits as withdrawals and negative withdrawals as deposits.
+    signed_amounts: bool,
+    /// Reject amounts with more decimal places than the engine keeps.
+    strict_precision: bool,
+    /// Decimal places amounts may have, see [`PenguinBuilder::with_amount_decimals`].
+    amount_decimals: u32,
+    output_order: OutputOrder,
+    /// Include clients left with nothing in the output, see
+    /// [`PenguinBuilder::with_emit_zero_balances`].
+    emit_zero_balances: bool,
+    /// Position of each client's first appearance, tracked for [`OutputOrder::FirstSeen`].
+    first_seen: Map<u16, usize>,
+    /// Transactions passed to [`Penguin::feed_once`], standing in for the input line.
+    fed_once: usize,
+    queues: QueueMonitor,
+    /// Interval between the heartbeats logged while workers are running.
+    heartbeat: Option<Duration>,
+    /// File persisting processed ids between runs, when reprocessing is idempotent.
+    processed_file: Option<PathBuf>,
+    fail_on_worker_panic: bool,
+    /// Keep feeding the other workers when one's channel closes.
+    skip_closed_workers: bool,
+    /// File the stats of every run are written to.
+    report_path: Option<PathBuf>,
+    input_name: Option<String>,
+    stats: Option<RunStats>,
+    worker_config: WorkerConfig,
+    ledger: Ledger,
     _logger: Option<Logger>,
 }
 
-impl<T, E> Penguin<T>
+/// Number of transactions buffered per worker channel unless configured otherwise.
+const DEFAULT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Withdrawals each client may have parked with pending withdrawals on.
+const MAX_PENDING_WITHDRAWALS: usize = 64;
+
+/// How the feed retries a worker whose channel is full before blocking on it.
+#[derive(Clone, Copy)]
+struct Backpressure {
+    /// `try_send` attempts made before falling back to waiting for room.
+    retries: u32,
+    /// Delay after the first full attempt, doubled after each further one.
+    backoff: Duration,
+}
+
+/// Options shared by every worker task.
+#[derive(Clone, Default)]
+struct WorkerConfig {
+    /// Balances within `[-epsilon, 0)` are snapped to zero after each transaction.
+    negative_epsilon: Option<Decimal>,
+    /// Maximum number of deposits each worker keeps for future disputes.
+    registry_capacity: Option<usize>,
+    /// Flat fee debited alongside every successful withdrawal.
+    withdrawal_fee: Option<Decimal>,
+    /// Stop the worker, and fail the run, when a balance invariant is violated.
+    strict_invariants: bool,
+    /// Keep resolved deposits in the registry so they can be disputed again.
+    allow_redispute: bool,
+    /// Fail disputes of charged back deposits instead of ignoring them.
+    strict_disputes: bool,
+    /// Client that deposited each transaction id, shared by every worker to tell
+    /// references to another client's deposit from unknown ones.
+    deposit_owners: Option<Arc<Mutex<Map<u32, u16>>>>,
+    /// Park withdrawals over the available funds until later funds cover them.
+    pending_withdrawals: bool,
+    /// Make charged back deposits disputable again when their account is unlocked.
+    restore_disputes_on_unlock: bool,
+    /// Maximum number of transactions accepted per client within a time window.
+    rate_limit: Option<RateLimit>,
+    /// Maximum number of client states each worker keeps, and where it evicts the rest.
+    client_limit: Option<ClientLimit>,
+    /// Last transactions each worker keeps, dumped when one of them fails.
+    replay: Option<ReplayConfig>,
+    /// Skip deposits and withdrawals already in the ledger's processed ids.
+    idempotent: bool,
+    /// Receives every ignored transaction while validating.
+    ignored: Option<mpsc::UnboundedSender<(usize, IgnoreReason)>>,
+    /// Receives the outcome of every transaction.
+    outcomes: Option<OutcomeSink>,
+    /// Queue depth counters, and the worker whose counter to decrease on receive.
+    queue: Option<(QueueMonitor, u16)>,
+    /// Number of ignored transactions, shared by every worker.
+    skipped: Arc<AtomicUsize>,
+    /// Labels the category of every ignored transaction.
+    classifier: Option<Classifier>,
+    /// Number of ignored transactions by category, counted apart by each worker and
+    /// merged into the engine's own counts once the worker is joined.
+    categories: Arc<Mutex<BTreeMap<String, usize>>>,
+    /// Held by every worker so the heartbeat stops once the last one is gone.
+    #[expect(dead_code, reason = "only dropped, never read")]
+    alive: Option<mpsc::Sender<Infallible>>,
+}
+
+/// Callback receiving `(tx, client, outcome)` for every transaction.
+type OutcomeSink = Arc<dyn Fn(u32, u16, Outcome) + Send + Sync>;
+
+/// An [`IgnoreClassifier`] returning the label of its category.
+type Classifier = Arc<dyn Fn(&Transaction, IgnoreReason) -> String + Send + Sync>;
+
+impl WorkerConfig {
+    /// Forget the ignored transactions counted by a previous run.
+    fn reset_counts(&self) {
+        self.skipped.store(0, Ordering::Relaxed);
+        self.categories
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Note `client` as the owner of deposit `tx`, when indexing deposits across clients.
+    fn index_deposit(&self, client: u16, tx: u32) {
+        if let Some(owners) = &self.deposit_owners {
+            owners
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entry(tx)
+                .or_insert(client);
+        }
+    }
+
+    /// Client that deposited `tx`, when indexing deposits across clients.
+    fn deposit_owner(&self, tx: u32) -> Option<u16> {
+        let owners = self.deposit_owners.as_ref()?;
+        owners
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&tx)
+            .copied()
+    }
+
+    /// Report a transaction that changed the client's balances.
+    fn apply(&self, tx: &Transaction) {
+        if let Some(outcomes) = &self.outcomes {
+            outcomes(tx.tx, tx.client, Outcome::Applied);
+        }
+    }
+
+    /// Count a transaction from input `line` that didn't change any balance, and report
+    /// it when validating.
+    fn ignore(&self, line: usize, tx: &Transaction, reason: IgnoreReason) {
+        telemetry::tx_ignored(reason);
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+        if let Some(ignored) = &self.ignored {
+            // The receiver outlives the run, so this can't fail.
+            let _ = ignored.send((line, reason));
+        }
+        if let Some(outcomes) = &self.outcomes {
+            outcomes(tx.tx, tx.client, Outcome::Ignored(reason));
+        }
+        if let Some(classifier) = &self.classifier {
+            let category = classifier(tx, reason);
+            telemetry::tx_classified(&category);
+            *self
+                .categories
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .entry(category)
+                .or_default() += 1;
+        }
+    }
+}
+
+/// Spread of an input over the workers, from [`PenguinBuilder::preflight`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Preflight {
+    /// Rows read, unreadable ones included.
+    pub rows: usize,
+    /// Distinct clients routed to each worker, indexed by worker.
+    pub clients_per_worker: Vec<usize>,
+    /// Transactions routed to each worker, indexed by worker.
+    pub transactions_per_worker: Vec<usize>,
+}
+
+/// Outcome of [`Penguin::validate`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// Input lines whose transaction could not be read.
+    pub parse_errors: Vec<usize>,
+    /// Input lines of transactions that would not change any balance, with the reason,
+    /// sorted by line.
+    pub ignored: Vec<(usize, IgnoreReason)>,
+}
+
+impl ValidationReport {
+    /// Whether every line parsed and would be applied.
+    pub fn is_clean(&self) -> bool {
+        self.parse_errors.is_empty() && self.ignored.is_empty()
+    }
+
+    /// Lines of disputes, resolves and chargebacks referencing an unknown transaction.
+    pub fn unknown_references(&self) -> impl Iterator<Item = usize> + '_ {
+        self.ignored
+            .iter()
+            .filter(|(_, reason)| *reason == IgnoreReason::UnknownTx)
+            .map(|(line, _)| *line)
+    }
+}
+
+/// Worker tasks, each returning its group id, its shard and whether it stopped early.
+type Workers = JoinSet<(
+    u16,
+    Ledger,
+    Result<(), PenguinError>,
+    BTreeMap<String, usize>,
+)>;
+
+/// Push-style entry point into a [`Penguin`], created with [`Penguin::sink`].
+///
+/// Every transaction sent through the [`Sink`] goes straight to the worker owning its
+/// client, and the sink is only ready once every worker channel has room. Call
+/// [`PenguinSink::finish`] to collect the client states; dropping the sink instead
+/// aborts the workers and loses everything they applied.
+pub struct PenguinSink<'a, T> {
+    penguin: &'a mut Penguin<T>,
+    senders: Vec<PollSender<Batch>>,
+    workers: Workers,
+    /// Workers found stopped, whose transactions are dropped with closed workers skipped.
+    closed: Set<u16>,
+    /// Number of transactions pushed so far, standing in for the input line.
+    line: usize,
+}
+
+impl<T, E> PenguinSink<'_, T>
 where
     T: Iterator<Item = TxResult<E>>,
+    E: Into<PenguinError>,
 {
-    /// Run the engine until the input iterator is over.
-    pub async fn run(&mut self) -> Result<Vec<ClientState>, PenguinError> {
-        let mut senders: HashMap<u16, mpsc::Sender<Transaction>> =
-            HashMap::with_capacity(self.num_workers);
-        let mut set = JoinSet::new();
+    /// Wait for the workers to apply every transaction pushed so far and return the
+    /// client states, like [`Penguin::run`].
+    pub async fn finish(self) -> Result<Vec<ClientState>, PenguinError> {
+        let Self {
+            penguin,
+            senders,
+            workers,
+            ..
+        } = self;
+        drop(senders);
+        penguin.join_workers(workers).await?;
 
-        for group_id in 0..self.num_workers {
-            let group_id = group_id as u16;
-            let (tx, rx) = mpsc::channel(1024);
+        Ok(penguin.output())
+    }
+}
+
+impl<T, E> Sink<Transaction> for PenguinSink<'_, T>
+where
+    T: Iterator<Item = TxResult<E>>,
+    E: Into<PenguinError>,
+{
+    type Error = PenguinError;
 
-            senders.insert(group_id, tx);
-            set.spawn(spawn_worker(rx));
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // The worker is only known once the transaction arrives, so reserve room in all.
+        // A stopped worker is reported by `start_send`, which knows the client.
+        let mut ready = true;
+        for sender in &mut self.get_mut().senders {
+            if sender.poll_reserve(cx).is_pending() {
+                ready = false;
+            }
         }
 
-        let mut line_count = 1;
-        for line in self.reader.by_ref() {
-            let tx = line.map_err(|_| PenguinError::Parse(line_count))?;
-            let group = (tx.client) % self.num_workers as u16;
-            senders[&group].send(tx).await?;
-            line_count += 1;
+        if ready {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
         }
+    }
 
-        drop(senders);
+    fn start_send(self: Pin<&mut Self>, tx: Transaction) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        this.line += 1;
+        let Some(tx) = this.penguin.prepare(this.line, tx)? else {
+            return Ok(());
+        };
 
-        let mut group_clients = Vec::with_capacity(self.num_workers);
-        while let Some(handle) = set.join_next().await {
-            match handle {
-                Ok(mut group_client) => group_clients.append(&mut group_client),
-                Err(err) => error!(%err, "worker task failed"),
+        this.penguin.see_client(tx.client);
+        let group = tx.client % this.senders.len() as u16;
+        if this.closed.contains(&group) {
+            return Ok(());
+        }
+        trace!(
+            line = this.line,
+            client = tx.client,
+            worker = group,
+            "routing transaction"
+        );
+        match this.senders[usize::from(group)].send_item(vec![(this.line, tx)]) {
+            Ok(()) => this.penguin.queues.queued(group, 1),
+            Err(err) => {
+                let batch = err.into_inner().unwrap_or_default();
+                this.penguin
+                    .undelivered(group, SendError(batch), &mut this.closed)?;
             }
         }
 
-        Ok(group_clients)
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Transactions are handed to the worker channels as soon as they are sent.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        for sender in &mut self.get_mut().senders {
+            sender.close();
+        }
+
+        Poll::Ready(Ok(()))
     }
 }
 
-/// Builder for configuring and creating a [`Penguin`] instance.
-pub struct PenguinBuilder<T> {
-    reader: T,
-    num_workers: Option<usize>,
-    log_file: Option<PathBuf>,
+/// Live view of how many transactions wait in each worker channel, see
+/// [`Penguin::queue_monitor`].
+///
+/// Cheap to clone and valid for the lifetime of the engine, so another task can poll it
+/// while a run is in progress, e.g. to decide when to scale out.
+#[derive(Debug, Clone, Default)]
+pub struct QueueMonitor {
+    /// Transactions sent to each worker minus those it received. Counted after the send
+    /// completes, so a fast worker can briefly take one below zero.
+    depths: Arc<[AtomicIsize]>,
+    /// Transactions received by all workers since the engine was built.
+    processed: Arc<AtomicUsize>,
 }
 
-impl<T, E> PenguinBuilder<T>
+impl QueueMonitor {
+    fn new(num_workers: usize) -> Self {
+        Self {
+            depths: (0..num_workers).map(|_| AtomicIsize::new(0)).collect(),
+            processed: Arc::default(),
+        }
+    }
+
+    /// Transactions sent to each worker and not yet received by it, indexed by worker.
+    pub fn queue_depths(&self) -> Vec<usize> {
+        self.depths
+            .iter()
+            .map(|depth| depth.load(Ordering::Relaxed).max(0) as usize)
+            .collect()
+    }
+
+    fn queued(&self, group: u16, len: usize) {
+        if let Some(depth) = self.depths.get(usize::from(group)) {
+            depth.fetch_add(len as isize, Ordering::Relaxed);
+        }
+    }
+
+    fn received(&self, group: u16, len: usize) {
+        if let Some(depth) = self.depths.get(usize::from(group)) {
+            depth.fetch_sub(len as isize, Ordering::Relaxed);
+        }
+        self.processed.fetch_add(len, Ordering::Relaxed);
+    }
+}
+
+/// Log the processed count and queue depths every `period` until every clone of the
+/// returned sender is dropped, i.e. until the workers holding them stop.
+fn spawn_heartbeat(period: Duration, queues: QueueMonitor) -> mpsc::Sender<Infallible> {
+    let (alive, mut stopped) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut ticks = time::interval_at(time::Instant::now() + period, period);
+        loop {
+            tokio::select! {
+                biased;
+                _ = stopped.recv() => break,
+                _ = ticks.tick() => info!(
+                    processed = queues.processed.load(Ordering::Relaxed),
+                    queue_depths = ?queues.queue_depths(),
+                    "heartbeat"
+                ),
+            }
+        }
+    });
+
+    alive
+}
+
+/// Log a batch `worker` stopped before receiving and name the client of its first
+/// transaction in the error.
+fn channel_closed(worker: u16, SendError(batch): SendError<Batch>) -> PenguinError {
+    let (line, client) = batch
+        .first()
+        .map_or((0, 0), |(line, tx)| (*line, tx.client));
+    error!(
+        line,
+        client,
+        worker,
+        lost = batch.len(),
+        "worker channel closed, transactions not delivered"
+    );
+
+    PenguinError::WorkerChannelClosed { worker, client }
+}
+
+/// Per-worker cap on client states, see [`PenguinBuilder::with_max_clients_per_worker`].
+#[derive(Clone)]
+struct ClientLimit {
+    max_clients: usize,
+    evicted: Arc<dyn EvictedStates>,
+}
+
+/// How many transactions each worker keeps for post-mortems, see
+/// [`PenguinBuilder::with_replay_buffer`].
+#[derive(Clone)]
+struct ReplayConfig {
+    capacity: usize,
+    path: Option<PathBuf>,
+}
+
+/// Per-client transaction budget over a fixed time window.
+#[derive(Clone, Copy)]
+struct RateLimit {
+    max_per_window: u32,
+    window: Duration,
+}
+
+impl<T, E> Penguin<T>
 where
     T: Iterator<Item = TxResult<E>>,
+    E: Into<PenguinError>,
 {
-    /// Start a builder from an iterator of transactions.
-    pub fn from_reader(reader: T) -> Self {
-        Self {
-            reader,
-            num_workers: None,
-            log_file: Some(PathBuf::from("penguin.log")),
+    /// Run the engine until the input iterator is over.
+    ///
+    /// Client states and dispute registries are kept between runs, so the returned list
+    /// includes every client seen so far, and [`Penguin::snapshot_blob`] captures them.
+    ///
+    /// An unreadable row fails the run with the reader's own error, placed on the row's
+    /// line in the input: a [`PenguinError::FieldCount`] takes the line, any other error
+    /// is wrapped in a [`PenguinError::InvalidRow`].
+    pub async fn run(&mut self) -> Result<Vec<ClientState>, PenguinError> {
+        self.process(None, None).await?;
+
+        Ok(self.output())
+    }
+
+    /// Run the engine until the input iterator is over or `token` is cancelled.
+    ///
+    /// On cancellation feeding stops, workers finish whatever they already received,
+    /// and the states processed so far are returned. The transaction being fed when the
+    /// token fires is dropped.
+    pub async fn run_cancellable(
+        &mut self,
+        token: CancellationToken,
+    ) -> Result<Vec<ClientState>, PenguinError> {
+        self.process(Some(&token), None).await?;
+
+        Ok(self.output())
+    }
+
+    /// Run the engine until the input iterator is over and return the client states
+    /// keyed by client id.
+    pub async fn run_to_map(mut self) -> Result<HashMap<u16, ClientState>, PenguinError> {
+        self.process(None, None).await?;
+
+        let emit_zero_balances = self.emit_zero_balances;
+        Ok(self
+            .ledger
+            .client_states
+            .into_iter()
+            .filter(|(_, state)| emit_zero_balances || !state.is_zero_balance())
+            .collect())
+    }
+
+    /// Run the engine until the input iterator is over, then write every client state to
+    /// `sink` and flush it.
+    ///
+    /// A failed write or flush is retried up to `retries` times, sleeping `backoff`
+    /// before the first retry and doubling it after each one, so an intermittent failure
+    /// doesn't lose the whole output. Once the retries run out, a write fails with
+    /// [`PenguinError::SinkWrite`] naming the client that couldn't be stored.
+    pub async fn run_to_sink(
+        &mut self,
+        sink: &mut impl StateSink,
+        retries: u32,
+        backoff: Duration,
+    ) -> Result<(), PenguinError> {
+        let states = self.run().await?;
+
+        for state in &states {
+            with_retries(retries, backoff, || sink.write_state(state))
+                .await
+                .map_err(|err| PenguinError::SinkWrite {
+                    client: state.client,
+                    source: Box::new(err),
+                })?;
         }
+        with_retries(retries, backoff, || sink.flush()).await
     }
 
-    /// Set the number of concurrent workers.
+    /// Run the engine over `stream` instead of the input iterator, until it ends.
     ///
-    /// This controls how transactions are sharded by client id.
-    pub fn with_num_workers(self, num_workers: NonZero<usize>) -> Self {
-        Self {
-            reader: self.reader,
-            num_workers: Some(num_workers.get()),
-            log_file: self.log_file,
+    /// Reading the next transaction overlaps with the workers applying the previous ones,
+    /// e.g. with an asynchronous file reader. Items go through a [`PenguinSink`], so
+    /// batching and backpressure retries don't apply.
+    ///
+    /// Dropping the returned future before it completes drops the sink too, which aborts
+    /// the workers instead of leaving them running in the background.
+    pub async fn run_stream<S, F>(&mut self, stream: S) -> Result<Vec<ClientState>, PenguinError>
+    where
+        S: Stream<Item = TxResult<F>>,
+        F: Into<PenguinError>,
+    {
+        let mut stream = pin!(stream);
+        let mut sink = self.sink();
+
+        let mut fed = Ok(());
+        for line_count in 1.. {
+            let tx = match stream.next().await {
+                Some(Ok(tx)) => tx,
+                Some(Err(err)) => {
+                    fed = Err(err.into().at_line(line_count));
+                    break;
+                }
+                None => break,
+            };
+            if let Err(err) = sink.send(tx).await {
+                fed = Err(err);
+                break;
+            }
         }
+
+        // Workers are always drained, and their errors explain any send failure.
+        let states = sink.finish().await?;
+        fed.map(|()| states)
     }
 
-    /// Enable background logging to a file.
-    pub fn with_logger(self, path: impl Into<PathBuf>) -> Self {
-        Self {
-            reader: self.reader,
+    /// Run the engine over inputs already partitioned by client, one worker per shard,
+    /// instead of the input iterator.
+    ///
+    /// Shards bypass the `client % workers` router, so every client must stay in a single
+    /// shard: the run fails with [`PenguinError::InvariantViolation`] as soon as one shows
+    /// up in a second shard. Clients retained from earlier runs belong to shard
+    /// `client % shards.len()`. Shards are read in turn, one transaction at a time, and
+    /// line numbers count transactions across all of them.
+    pub async fn run_presharded<S, F>(
+        &mut self,
+        shards: Vec<S>,
+    ) -> Result<Vec<ClientState>, PenguinError>
+    where
+        S: Iterator<Item = TxResult<F>>,
+        F: Into<PenguinError>,
+    {
+        if shards.is_empty() {
+            return Ok(self.output());
+        }
+        if shards.len() > usize::from(u16::MAX) {
+            return Err(PenguinError::TooManyWorkers(shards.len()));
+        }
+        let started = Instant::now();
+        let mut stats = RunStats {
+            input: self.input_name.clone(),
+            ..RunStats::default()
+        };
+        self.worker_config.reset_counts();
+
+        let num_shards = shards.len() as u16;
+        let owners = self
+            .ledger
+            .client_states
+            .keys()
+            .map(|&client| (client, client % num_shards))
+            .collect();
+        let num_workers = mem::replace(&mut self.num_workers, shards.len());
+        let (senders, workers) = self.spawn_workers();
+        self.num_workers = num_workers;
+
+        let fed = self
+            .feed_presharded(&senders, shards, owners, &mut stats)
+            .await;
+        drop(senders);
+        self.join_workers(workers).await.and(fed)?;
+        self.record_stats(stats, started)?;
+
+        Ok(self.output())
+    }
+
+    /// Run the engine, then compare the final balances to the expected ones listed in the
+    /// CSV file at `path`.
+    ///
+    /// The file has `client`, `available`, `held` and `total` columns, plus optional
+    /// `locked` and `currency` ones, like the CLI output, which can be used as is.
+    /// Amounts are compared rounded to 4 decimals, or to the scale set with
+    /// [`PenguinBuilder::with_minor_units`]. Clients missing on either side are reported
+    /// too, and an empty list means the run matched.
+    pub async fn verify_against(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<Vec<Mismatch>, PenguinError> {
+        let states = self.run().await?;
+
+        verify::verify(
+            &states,
+            path.as_ref(),
+            self.minor_units.unwrap_or(AMOUNT_DECIMALS),
+        )
+    }
+
+    /// Run the input through the whole pipeline without keeping any of its effects, and
+    /// report unparsable lines and transactions that would be ignored.
+    ///
+    /// Unlike [`Penguin::run`], parse errors don't stop the run. Transactions are checked
+    /// against the retained state (e.g. restored from a snapshot), which is left as it was,
+    /// and processed ids are not persisted.
+    pub async fn validate(&mut self) -> Result<ValidationReport, PenguinError> {
+        let (ignored, mut ignored_rx) = mpsc::unbounded_channel();
+        let ledger = self.ledger.clone();
+        let processed_file = self.processed_file.take();
+        let report_path = self.report_path.take();
+        let stats = self.stats.take();
+        let first_seen = self.first_seen.clone();
+        // Validated deposits go to a copy of the index, like the ledger.
+        let deposit_owners = self.worker_config.deposit_owners.clone();
+        self.worker_config.deposit_owners = deposit_owners.as_ref().map(|owners| {
+            let owners = owners.lock().unwrap_or_else(PoisonError::into_inner);
+            Arc::new(Mutex::new(owners.clone()))
+        });
+        self.worker_config.ignored = Some(ignored);
+
+        let mut report = ValidationReport::default();
+        let processed = self.process(None, Some(&mut report.parse_errors)).await;
+
+        self.worker_config.ignored = None;
+        self.worker_config.deposit_owners = deposit_owners;
+        self.processed_file = processed_file;
+        self.report_path = report_path;
+        self.stats = stats;
+        self.first_seen = first_seen;
+        self.ledger = ledger;
+        processed?;
+
+        while let Ok(entry) = ignored_rx.try_recv() {
+            report.ignored.push(entry);
+        }
+        report.ignored.sort_by_key(|(line, _)| *line);
+
+        Ok(report)
+    }
+
+    /// Serialize every client state and dispute registry entry into a versioned blob.
+    ///
+    /// The blob reflects the state after the last completed [`Penguin::run`] and can be
+    /// restored with [`PenguinBuilder::from_snapshot_blob`]. Fails with
+    /// [`PenguinError::Snapshot`] if a currency is longer than the 65535 bytes it holds.
+    pub fn snapshot_blob(&self) -> Result<Vec<u8>, PenguinError> {
+        snapshot::encode(&self.ledger)
+    }
+
+    /// Swap in a new input and forget the client states, to process another file with
+    /// the same configuration, workers, logger and monitors, without rebuilding.
+    ///
+    /// Processed ids are kept when they are persisted with
+    /// [`PenguinBuilder::with_processed_ids_file`], and the input name set on the builder
+    /// is dropped. Use [`Penguin::resume_with_reader`] to carry the states over instead.
+    pub fn with_new_reader<U>(mut self, reader: U) -> Penguin<U> {
+        self.first_seen.clear();
+        if let Some(owners) = &self.worker_config.deposit_owners {
+            owners
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+        let ledger = Ledger {
+            processed: if self.processed_file.is_some() {
+                self.ledger.processed.clone()
+            } else {
+                Set::default()
+            },
+            ..Ledger::default()
+        };
+
+        self.swap_reader(reader, ledger)
+    }
+
+    /// Swap in a new input and keep the client states and dispute registry, as if the
+    /// new input followed the previous one.
+    pub fn resume_with_reader<U>(mut self, reader: U) -> Penguin<U> {
+        let ledger = mem::take(&mut self.ledger);
+
+        self.swap_reader(reader, ledger)
+    }
+
+    /// Move the configuration over to an engine reading `reader` into `ledger`.
+    fn swap_reader<U>(self, reader: U, ledger: Ledger) -> Penguin<U> {
+        Penguin {
+            reader,
             num_workers: self.num_workers,
-            log_file: Some(path.into()),
+            type_filter: self.type_filter,
+            channel_capacity: self.channel_capacity,
+            backpressure: self.backpressure,
+            batch_size: self.batch_size,
+            minor_units: self.minor_units,
+            signed_amounts: self.signed_amounts,
+            strict_precision: self.strict_precision,
+            amount_decimals: self.amount_decimals,
+            output_order: self.output_order,
+            emit_zero_balances: self.emit_zero_balances,
+            first_seen: self.first_seen,
+            fed_once: 0,
+            queues: self.queues,
+            heartbeat: self.heartbeat,
+            processed_file: self.processed_file,
+            fail_on_worker_panic: self.fail_on_worker_panic,
+            skip_closed_workers: self.skip_closed_workers,
+            report_path: self.report_path,
+            input_name: None,
+            stats: None,
+            worker_config: self.worker_config,
+            ledger,
+            _logger: self._logger,
         }
     }
 
-    /// Build a configured [`Penguin`] instance.
-    pub fn build(self) -> Result<Penguin<T>, PenguinError> {
-        let num_workers = self.num_workers.unwrap_or(1);
+    /// Spawn the workers and return a [`PenguinSink`] to push transactions into them,
+    /// instead of reading them from the input iterator.
+    ///
+    /// The builder options apply as in [`Penguin::run`], except batching and backpressure
+    /// retries: each transaction is sent on its own once the sink reports it is ready.
+    pub fn sink(&mut self) -> PenguinSink<'_, T> {
+        let (senders, workers) = self.spawn_workers();
+        let mut senders: Vec<_> = senders.into_iter().collect();
+        senders.sort_unstable_by_key(|(group, _)| *group);
 
-        let _logger = if let Some(path) = self.log_file {
-            Some(Logger::try_init_from_path(path)?)
-        } else {
-            None
+        PenguinSink {
+            senders: senders
+                .into_iter()
+                .map(|(_, sender)| PollSender::new(sender))
+                .collect(),
+            workers,
+            closed: Set::default(),
+            line: 0,
+            penguin: self,
+        }
+    }
+
+    /// Apply a single transaction and wait for it to land in the ledger, to drive the
+    /// engine step by step (e.g. from a REPL) instead of draining the reader.
+    ///
+    /// Each call only spawns the worker owning the transaction's client, over that
+    /// worker's share of the ledger, so this is slower than [`Penguin::run`] but leaves
+    /// [`Penguin::states`] up to date between calls. Strict errors fail the call, the
+    /// ledger keeps what was applied before.
+    pub async fn feed_once(&mut self, tx: Transaction) -> Result<(), PenguinError> {
+        self.fed_once += 1;
+        let line = self.fed_once;
+        let Some(tx) = self.prepare(line, tx)? else {
+            return Ok(());
         };
+        self.see_client(tx.client);
 
-        Ok(Penguin {
-            reader: self.reader,
-            num_workers,
-            _logger,
-        })
+        let group = tx.client % self.num_workers as u16;
+        let shard = self.ledger.take_group(group, self.num_workers as u16);
+        let mut workers = JoinSet::new();
+        let sender = self.spawn_group(&mut workers, group, shard, None);
+        let sent = match sender.send(vec![(line, tx)]).await {
+            Ok(()) => {
+                self.queues.queued(group, 1);
+                Ok(())
+            }
+            Err(err) => self.undelivered(group, err, &mut Set::default()).map(drop),
+        };
+        drop(sender);
+
+        self.join_workers(workers).await.and(sent)
     }
-}
 
-/// Process transactions for a subset of clients on a worker task.
-async fn spawn_worker(mut rx: mpsc::Receiver<Transaction>) -> Vec<ClientState> {
-    let mut client_states: HashMap<u16, ClientState> = HashMap::new();
-    let mut client_tx_registry: HashMap<ClientTx, Decimal> = HashMap::new();
-
-    while let Some(tx) = rx.recv().await {
-        let client_state = client_states
-            .entry(tx.client)
-            .or_insert(ClientState::new(tx.client));
-
-        if let Some(amount) = tx.amount
-            && tx.tx_type == TransactionType::Deposit
-            && !client_state.locked
-        {
-            client_tx_registry
-                .entry((tx.client, tx.tx))
-                .or_insert(amount);
+    /// Client states after the transactions applied so far, in the configured order.
+    ///
+    /// Meant to be used between [`Penguin::feed_once`] calls, which only return once their
+    /// transaction is applied, so this never waits on the workers.
+    pub async fn states(&self) -> Vec<ClientState> {
+        self.output()
+    }
+
+    /// Drain the reader through the workers and fold their shards back into the ledger.
+    ///
+    /// With `parse_errors`, unreadable lines are recorded there and skipped instead of
+    /// failing the run.
+    async fn process(
+        &mut self,
+        cancel: Option<&CancellationToken>,
+        parse_errors: Option<&mut Vec<usize>>,
+    ) -> Result<(), PenguinError> {
+        let started = Instant::now();
+        let mut stats = RunStats {
+            input: self.input_name.clone(),
+            ..RunStats::default()
+        };
+        self.worker_config.reset_counts();
+        let (senders, workers) = self.spawn_workers();
+
+        // Workers are always drained, even on a feed error, so no state is lost.
+        let fed = self.feed(&senders, cancel, parse_errors, &mut stats).await;
+        drop(senders);
+
+        // A worker stopping early closes its channel, so its error explains any send failure.
+        self.join_workers(workers).await.and(fed)?;
+        self.record_stats(stats, started)
+    }
+
+    /// Complete the stats of a finished run, write the report if configured, and keep them.
+    fn record_stats(&mut self, mut stats: RunStats, started: Instant) -> Result<(), PenguinError> {
+        stats.rows_skipped += self.worker_config.skipped.load(Ordering::Relaxed);
+        stats.ignored_by_category = mem::take(
+            &mut *self
+                .worker_config
+                .categories
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        stats.distinct_clients = self.ledger.client_states.len();
+        stats.locked_accounts = self
+            .ledger
+            .client_states
+            .values()
+            .filter(|state| state.locked)
+            .count();
+        stats.duration_secs = started.elapsed().as_secs_f64();
+        if let Some(path) = &self.report_path {
+            stats.write_json(path)?;
         }
+        self.stats = Some(stats);
 
-        if let Err(err) = apply_tx(client_state, &tx, &mut client_tx_registry) {
-            error!(
-                %err,
-                client = client_state.client,
-                tx = tx.tx,
-                "failed to apply transaction"
-            );
+        Ok(())
+    }
+
+    /// Note the first appearance of `client` when outputting in first-seen order.
+    fn see_client(&mut self, client: u16) {
+        if self.output_order == OutputOrder::FirstSeen {
+            let next = self.first_seen.len();
+            self.first_seen.entry(client).or_insert(next);
         }
     }
 
-    client_states.into_values().collect()
-}
+    /// Clone the client states out of the ledger in the configured order.
+    fn output(&self) -> Vec<ClientState> {
+        let mut states: Vec<ClientState> = self
+            .ledger
+            .client_states
+            .values()
+            .filter(|state| self.emit_zero_balances || !state.is_zero_balance())
+            .cloned()
+            .collect();
+        match self.output_order {
+            OutputOrder::FirstSeen => states.sort_unstable_by_key(|state| {
+                (
+                    self.first_seen
+                        .get(&state.client)
+                        .copied()
+                        .unwrap_or(usize::MAX),
+                    state.client,
+                )
+            }),
+            OutputOrder::ClientAsc => states.sort_unstable_by_key(|state| state.client),
+            OutputOrder::WorkerNative => states.sort_unstable_by_key(|state| {
+                (usize::from(state.client) % self.num_workers, state.client)
+            }),
+        }
+        states
+    }
 
-/// Apply a single transaction to a client state.
-fn apply_tx(
-    client_state: &mut ClientState,
-    tx: &Transaction,
-    client_tx_registry: &mut HashMap<ClientTx, Decimal>,
-) -> Result<(), PenguinError> {
-    use TransactionType as TType;
+    /// Clone the client states out of the ledger like the run output, each with the
+    /// disputed deposits its held funds come from.
+    ///
+    /// Deposits dropped from a capped dispute registry are missing from the breakdown,
+    /// which then sums to less than `held`.
+    pub fn client_details(&self) -> Vec<ClientDetails> {
+        let mut breakdowns: Map<u16, HashMap<u32, Decimal>> = Map::default();
+        for (&(client, tx), deposit) in &self.ledger.client_tx_registry {
+            if deposit.currency.is_none() && !deposit.held.is_zero() {
+                breakdowns
+                    .entry(client)
+                    .or_default()
+                    .insert(tx, deposit.held);
+            }
+        }
 
-    if client_state.locked {
-        warn!(
-            client = client_state.client,
-            tx = tx.tx,
-            "Received transaction for locked client. Ignoring it."
+        self.output()
+            .into_iter()
+            .map(|state| ClientDetails {
+                held_breakdown: breakdowns.remove(&state.client).unwrap_or_default(),
+                state,
+            })
+            .collect()
+    }
+
+    /// Handle reporting the depth of every worker channel, for live monitoring.
+    pub fn queue_monitor(&self) -> QueueMonitor {
+        self.queues.clone()
+    }
+
+    /// Stats of the last completed run, or `None` before the first one.
+    pub fn run_stats(&self) -> Option<&RunStats> {
+        self.stats.as_ref()
+    }
+
+    /// Spawn one worker per shard of the retained ledger, returning the channels feeding
+    /// them keyed by group.
+    fn spawn_workers(&mut self) -> (Map<u16, mpsc::Sender<Batch>>, Workers) {
+        let mut senders: Map<u16, mpsc::Sender<Batch>> =
+            Map::with_capacity_and_hasher(self.num_workers, Default::default());
+        let mut set = JoinSet::new();
+        let alive = self
+            .heartbeat
+            .map(|period| spawn_heartbeat(period, self.queues.clone()));
+
+        for (group_id, shard) in self.take_shards().into_iter().enumerate() {
+            let group_id = group_id as u16;
+            let sender = self.spawn_group(&mut set, group_id, shard, alive.clone());
+            senders.insert(group_id, sender);
+        }
+
+        (senders, set)
+    }
+
+    /// Spawn the worker of `group_id` over `shard` into `workers`, returning the channel
+    /// feeding it.
+    fn spawn_group(
+        &self,
+        workers: &mut Workers,
+        group_id: u16,
+        shard: Ledger,
+        alive: Option<mpsc::Sender<Infallible>>,
+    ) -> mpsc::Sender<Batch> {
+        let (tx, rx) = mpsc::channel(self.channel_capacity);
+        let categories = Arc::default();
+        let config = WorkerConfig {
+            queue: Some((self.queues.clone(), group_id)),
+            alive,
+            categories: Arc::clone(&categories),
+            ..self.worker_config.clone()
+        };
+        workers.spawn(
+            spawn_worker(rx, config, shard)
+                .map(move |(shard, result)| {
+                    let categories =
+                        mem::take(&mut *categories.lock().unwrap_or_else(PoisonError::into_inner));
+                    (group_id, shard, result, categories)
+                })
+                .instrument(debug_span!("worker", group_id)),
         );
 
-        return Ok(());
+        tx
     }
 
-    match tx.tx_type {
-        TType::Deposit => {
-            let amount = tx
-                .amount
-                .ok_or(PenguinError::DepositOrWithdrawalWithoutAmount(
-                    client_state.client,
-                ))?;
-            client_state.available += amount;
-            client_state.total += amount;
+    /// Wait for every worker to stop, fold their shards and ignored counts back into the
+    /// engine and persist processed ids, returning the first error reported by a worker.
+    async fn join_workers(&mut self, mut workers: Workers) -> Result<(), PenguinError> {
+        let mut worker_error = None;
+        let mut shards = BTreeMap::new();
+        while let Some(handle) = workers.join_next().await {
+            match handle {
+                Ok((group_id, shard, result, categories)) => {
+                    let mut counts = self
+                        .worker_config
+                        .categories
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner);
+                    for (category, count) in categories {
+                        *counts.entry(category).or_default() += count;
+                    }
+                    shards.insert(group_id, (shard, result));
+                }
+                Err(err) => {
+                    error!(%err, "worker task failed");
+                    if self.fail_on_worker_panic {
+                        worker_error.get_or_insert(PenguinError::WorkerPanicked(err.to_string()));
+                    }
+                }
+            }
         }
-        TType::Withdrawal => {
-            let amount = tx
-                .amount
-                .ok_or(PenguinError::DepositOrWithdrawalWithoutAmount(
-                    client_state.client,
-                ))?;
-            if client_state.available < amount {
-                warn!(
-                    client = client_state.client,
-                    tx = tx.tx,
-                    amount = %amount,
-                    available = %client_state.available,
-                    "insufficient funds for withdrawal"
+
+        // Fold shards in group order, so the ledger doesn't depend on which worker
+        // finished first.
+        for (shard, result) in shards.into_values() {
+            // Overlapping shards would double count a client downstream.
+            if let Err(err) = self.ledger.absorb(shard) {
+                error!(%err, "worker shards overlap");
+                worker_error.get_or_insert(err);
+            }
+            if let Err(err) = result {
+                worker_error.get_or_insert(err);
+            }
+        }
+
+        // Persist even when the feed failed: whatever the workers applied is in the ledger.
+        if let Some(path) = &self.processed_file {
+            processed::save(path, &self.ledger.processed)?;
+        }
+
+        match worker_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Apply the feed options to a transaction from input `line`, returning `None` when
+    /// it is filtered out or of an unknown type.
+    fn prepare(
+        &self,
+        line: usize,
+        mut tx: Transaction,
+    ) -> Result<Option<Transaction>, PenguinError> {
+        if tx.tx_type == TransactionType::Unknown {
+            debug!(
+                line,
+                client = tx.client,
+                tx = tx.tx,
+                "skipping transaction of unknown type"
+            );
+            self.worker_config
+                .ignore(line, &tx, IgnoreReason::UnknownType);
+            return Ok(None);
+        }
+        if self.strict_precision
+            && self.minor_units.is_none()
+            && let Some(amount) = tx.amount
+        {
+            check_precision(amount, self.amount_decimals)
+                .map_err(|err| PenguinError::TransactionParse(Cow::Owned(err)).at_line(line))?;
+        }
+        if let Some(scale) = self.minor_units
+            && let Some(amount) = tx.amount
+        {
+            tx.amount = Some(
+                from_minor_units(amount, scale, self.amount_decimals)
+                    .map_err(|err| PenguinError::TransactionParse(Cow::Owned(err)).at_line(line))?,
+            );
+        }
+        if self.signed_amounts
+            && let Some(amount) = tx.amount
+            && amount < Decimal::ZERO
+        {
+            let flipped = match tx.tx_type {
+                TransactionType::Deposit => Some(TransactionType::Withdrawal),
+                TransactionType::Withdrawal => Some(TransactionType::Deposit),
+                _ => None,
+            };
+            if let Some(tx_type) = flipped {
+                tx.tx_type = tx_type;
+                tx.amount = Some(amount.abs());
+            }
+        }
+        if let Some(filter) = &self.type_filter
+            && !filter.contains(&tx.tx_type)
+        {
+            self.worker_config.ignore(line, &tx, IgnoreReason::Filtered);
+            return Ok(None);
+        }
+
+        Ok(Some(tx))
+    }
+
+    /// Send every transaction from the reader to the worker owning its client, in
+    /// batches of up to `batch_size` transactions per worker.
+    async fn feed(
+        &mut self,
+        senders: &Map<u16, mpsc::Sender<Batch>>,
+        cancel: Option<&CancellationToken>,
+        mut parse_errors: Option<&mut Vec<usize>>,
+        stats: &mut RunStats,
+    ) -> Result<(), PenguinError> {
+        let mut batches: Vec<Batch> = (0..self.num_workers)
+            .map(|_| Vec::with_capacity(self.batch_size))
+            .collect();
+        let mut closed = Set::default();
+
+        let mut line_count = 0;
+        // Pulled one at a time, so the loop body can use the rest of `self`.
+        while let Some(line) = self.reader.next() {
+            line_count += 1;
+            if cancel.is_some_and(CancellationToken::is_cancelled) {
+                info!(line = line_count, "run cancelled, stop feeding workers");
+                break;
+            }
+            stats.rows_read = line_count;
+
+            let tx = match (line, parse_errors.as_mut()) {
+                (Ok(tx), _) => tx,
+                (Err(_), Some(parse_errors)) => {
+                    parse_errors.push(line_count);
+                    stats.rows_skipped += 1;
+                    continue;
+                }
+                (Err(err), None) => return Err(err.into().at_line(line_count)),
+            };
+            let Some(tx) = self.prepare(line_count, tx)? else {
+                continue;
+            };
+            *stats.transactions.entry(tx.tx_type).or_default() += 1;
+            self.see_client(tx.client);
+
+            let group = (tx.client) % self.num_workers as u16;
+            if closed.contains(&group) {
+                stats.rows_undelivered += 1;
+                continue;
+            }
+            trace!(
+                line = line_count,
+                client = tx.client,
+                worker = group,
+                "routing transaction"
+            );
+            let batch = &mut batches[usize::from(group)];
+            batch.push((line_count, tx));
+            if batch.len() < self.batch_size {
+                continue;
+            }
+
+            let len = batch.len();
+            let send = deliver(&senders[&group], group, mem::take(batch), self.backpressure);
+            let sent = match cancel {
+                Some(token) => tokio::select! {
+                    sent = send => sent,
+                    _ = token.cancelled() => {
+                        info!(line = line_count, "run cancelled, stop feeding workers");
+                        break;
+                    }
+                },
+                None => send.await,
+            };
+            match sent {
+                Ok(()) => self.queues.queued(group, len),
+                Err(err) => stats.rows_undelivered += self.undelivered(group, err, &mut closed)?,
+            }
+        }
+
+        // Flush partial batches so every transaction read reaches its worker.
+        for (group, batch) in (0..).zip(batches) {
+            if !batch.is_empty() {
+                let len = batch.len();
+                match deliver(&senders[&group], group, batch, self.backpressure).await {
+                    Ok(()) => self.queues.queued(group, len),
+                    Err(err) => {
+                        stats.rows_undelivered += self.undelivered(group, err, &mut closed)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle a batch bounced back by the closed channel of worker `group`: fail the
+    /// feed, or with closed workers skipped, stop feeding that worker and return the
+    /// number of transactions lost.
+    fn undelivered(
+        &self,
+        group: u16,
+        err: SendError<Batch>,
+        closed: &mut Set<u16>,
+    ) -> Result<usize, PenguinError> {
+        let lost = err.0.len();
+        let err = channel_closed(group, err);
+        if !self.skip_closed_workers {
+            return Err(err);
+        }
+
+        warn!(
+            worker = group,
+            "dropping the next transactions of the worker"
+        );
+        closed.insert(group);
+
+        Ok(lost)
+    }
+
+    /// Feed each shard to its own worker, taking one transaction from every shard in turn.
+    ///
+    /// `owners` maps clients to the shard they were first seen in.
+    async fn feed_presharded<S, F>(
+        &mut self,
+        senders: &Map<u16, mpsc::Sender<Batch>>,
+        shards: Vec<S>,
+        mut owners: Map<u16, u16>,
+        stats: &mut RunStats,
+    ) -> Result<(), PenguinError>
+    where
+        S: Iterator<Item = TxResult<F>>,
+        F: Into<PenguinError>,
+    {
+        let mut shards: Vec<_> = shards.into_iter().map(Iterator::fuse).collect();
+        let mut closed = Set::default();
+        let mut line_count = 0;
+
+        loop {
+            let mut exhausted = true;
+            for (group, shard) in (0..).zip(shards.iter_mut()) {
+                let Some(line) = shard.next() else {
+                    continue;
+                };
+                exhausted = false;
+                line_count += 1;
+                stats.rows_read = line_count;
+
+                let tx = line.map_err(|err| err.into().at_line(line_count))?;
+                let Some(tx) = self.prepare(line_count, tx)? else {
+                    continue;
+                };
+                let owner = *owners.entry(tx.client).or_insert(group);
+                if owner != group {
+                    error!(
+                        line = line_count,
+                        client = tx.client,
+                        shard = group,
+                        owner,
+                        "client appears in more than one shard"
+                    );
+                    return Err(PenguinError::InvariantViolation(
+                        tx.client,
+                        "client appears in more than one shard",
+                    ));
+                }
+                *stats.transactions.entry(tx.tx_type).or_default() += 1;
+                self.see_client(tx.client);
+                if closed.contains(&group) {
+                    stats.rows_undelivered += 1;
+                    continue;
+                }
+
+                let sent = deliver(
+                    &senders[&group],
+                    group,
+                    vec![(line_count, tx)],
+                    self.backpressure,
+                )
+                .await;
+                match sent {
+                    Ok(()) => self.queues.queued(group, 1),
+                    Err(err) => {
+                        stats.rows_undelivered += self.undelivered(group, err, &mut closed)?;
+                    }
+                }
+            }
+            if exhausted {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Split the retained ledger into one shard per worker, using the feed's sharding.
+    fn take_shards(&mut self) -> Vec<Ledger> {
+        let mut shards: Vec<Ledger> = (0..self.num_workers).map(|_| Ledger::default()).collect();
+        let num_workers = self.num_workers as u16;
+        let shard_of = |client: u16| (client % num_workers) as usize;
+
+        for (client, state) in self.ledger.client_states.drain() {
+            shards[shard_of(client)].client_states.insert(client, state);
+        }
+        for (client_tx, amount) in self.ledger.client_tx_registry.drain() {
+            shards[shard_of(client_tx.0)]
+                .client_tx_registry
+                .insert(client_tx, amount);
+        }
+        for client_tx in self.ledger.processed.drain() {
+            shards[shard_of(client_tx.0)].processed.insert(client_tx);
+        }
+
+        shards
+    }
+}
+
+impl Penguin<()> {
+    /// Merge the partial outputs of several runs into a single set of client states,
+    /// sorted by client id.
+    ///
+    /// A client should only ever appear in one partial. If it shows up in more than one,
+    /// its balances are summed, unless it is locked in one partial and active in another,
+    /// which is reported as [`PenguinError::MergeConflict`].
+    pub fn merge_and_finalize(
+        partials: Vec<Vec<ClientState>>,
+    ) -> Result<Vec<ClientState>, PenguinError> {
+        let mut merged: BTreeMap<u16, ClientState> = BTreeMap::new();
+
+        for state in partials.into_iter().flatten() {
+            match merged.entry(state.client) {
+                btree_map::Entry::Vacant(entry) => {
+                    entry.insert(state);
+                }
+                btree_map::Entry::Occupied(mut entry) => {
+                    let existing = entry.get_mut();
+                    if existing.locked != state.locked {
+                        return Err(PenguinError::MergeConflict(state.client));
+                    }
+
+                    warn!(
+                        client = state.client,
+                        "client appears in more than one partial output, summing balances"
+                    );
+
+                    existing.available += state.available;
+                    existing.held += state.held;
+                    existing.total += state.total;
+                    existing.disputes_seen += state.disputes_seen;
+                    existing.last_tx = existing.last_tx.max(state.last_tx);
+                    existing.created_by_tx = existing.created_by_tx.or(state.created_by_tx);
+                    for (currency, balances) in state.currencies {
+                        let merged = existing.currencies.entry(currency).or_default();
+                        merged.available += balances.available;
+                        merged.held += balances.held;
+                        merged.total += balances.total;
+                    }
+                }
+            }
+        }
+
+        Ok(merged.into_values().collect())
+    }
+}
+
+/// Builder for configuring and creating a [`Penguin`] instance.
+pub struct PenguinBuilder<T> {
+    reader: T,
+    num_workers: Option<usize>,
+    log_file: Option<PathBuf>,
+    log_format: LogFormat,
+    log_rotation: Option<LogRotation>,
+    stderr_logs: bool,
+    type_filter: Option<HashSet<TransactionType>>,
+    negative_epsilon: Option<Decimal>,
+    registry_capacity: Option<usize>,
+    withdrawal_fee: Option<Decimal>,
+    strict_invariants: bool,
+    allow_redispute: bool,
+    strict_disputes: bool,
+    cross_client_index: bool,
+    pending_withdrawals: bool,
+    restore_disputes_on_unlock: bool,
+    rate_limit: Option<RateLimit>,
+    client_limit: Option<ClientLimit>,
+    replay_capacity: Option<usize>,
+    replay_path: Option<PathBuf>,
+    channel_capacity: Option<usize>,
+    backpressure: Option<Backpressure>,
+    batch_size: Option<usize>,
+    minor_units: Option<u32>,
+    signed_amounts: bool,
+    strict_precision: bool,
+    amount_decimals: u32,
+    output_order: OutputOrder,
+    emit_zero_balances: bool,
+    processed_file: Option<PathBuf>,
+    fail_on_worker_panic: bool,
+    skip_closed_workers: bool,
+    heartbeat: Option<Duration>,
+    outcome_sink: Option<OutcomeSink>,
+    ignore_classifier: Option<Classifier>,
+    report_path: Option<PathBuf>,
+    input_name: Option<String>,
+    ledger: Ledger,
+}
+
+impl<T, E> PenguinBuilder<T>
+where
+    T: Iterator<Item = TxResult<E>>,
+{
+    /// Start a builder from an iterator of transactions.
+    ///
+    /// Nothing is logged unless a log file or stderr logs are requested, see
+    /// [`PenguinBuilder::with_logger`] and [`PenguinBuilder::with_stderr_logs`].
+    pub fn from_reader(reader: T) -> Self {
+        Self {
+            reader,
+            num_workers: None,
+            log_file: None,
+            log_format: LogFormat::default(),
+            log_rotation: None,
+            stderr_logs: false,
+            type_filter: None,
+            negative_epsilon: None,
+            registry_capacity: None,
+            withdrawal_fee: None,
+            strict_invariants: false,
+            allow_redispute: false,
+            strict_disputes: false,
+            cross_client_index: false,
+            pending_withdrawals: false,
+            restore_disputes_on_unlock: false,
+            rate_limit: None,
+            client_limit: None,
+            replay_capacity: None,
+            replay_path: None,
+            channel_capacity: None,
+            backpressure: None,
+            batch_size: None,
+            minor_units: None,
+            signed_amounts: false,
+            strict_precision: false,
+            amount_decimals: AMOUNT_DECIMALS,
+            output_order: OutputOrder::default(),
+            emit_zero_balances: true,
+            processed_file: None,
+            fail_on_worker_panic: false,
+            skip_closed_workers: false,
+            heartbeat: None,
+            outcome_sink: None,
+            ignore_classifier: None,
+            report_path: None,
+            input_name: None,
+            ledger: Ledger::default(),
+        }
+    }
+
+    /// Start a builder from an iterator of transactions, resuming from a blob produced
+    /// by [`Penguin::snapshot_blob`].
+    pub fn from_snapshot_blob(reader: T, blob: &[u8]) -> Result<Self, PenguinError> {
+        Ok(Self {
+            ledger: snapshot::decode(blob)?,
+            ..Self::from_reader(reader)
+        })
+    }
+
+    /// Set the number of concurrent workers.
+    ///
+    /// This controls how transactions are sharded by client id.
+    pub fn with_num_workers(self, num_workers: NonZero<usize>) -> Self {
+        Self {
+            num_workers: Some(num_workers.get()),
+            ..self
+        }
+    }
+
+    /// Enable background logging to a file.
+    pub fn with_logger(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            log_file: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Don't log at all, which is the default, undoing any earlier
+    /// [`PenguinBuilder::with_logger`] or [`PenguinBuilder::with_stderr_logs`].
+    ///
+    /// [`PenguinBuilder::build`] then leaves the global `tracing` subscriber alone, so the
+    /// embedding application can install its own.
+    pub fn without_logger(self) -> Self {
+        Self {
+            log_file: None,
+            stderr_logs: false,
+            ..self
+        }
+    }
+
+    /// Don't write logs to a file, e.g. to only log to stderr.
+    pub fn without_log_file(self) -> Self {
+        Self {
+            log_file: None,
+            ..self
+        }
+    }
+
+    /// Also write logs to stderr, on top of the log file if there is one.
+    pub fn with_stderr_logs(self, enabled: bool) -> Self {
+        Self {
+            stderr_logs: enabled,
+            ..self
+        }
+    }
+
+    /// Choose how log lines are formatted, e.g. [`LogFormat::Json`] for log aggregation.
+    pub fn with_log_format(self, format: LogFormat) -> Self {
+        Self {
+            log_format: format,
+            ..self
+        }
+    }
+
+    /// Roll the log file over on a schedule instead of truncating it on every run.
+    pub fn with_log_rotation(self, rotation: LogRotation) -> Self {
+        Self {
+            log_rotation: Some(rotation),
+            ..self
+        }
+    }
+
+    /// Only feed transactions whose type is in `types`; everything else is skipped.
+    ///
+    /// Useful for targeted reprocessing, e.g. replaying just the dispute lifecycle.
+    pub fn with_type_filter(self, types: HashSet<TransactionType>) -> Self {
+        Self {
+            type_filter: Some(types),
+            ..self
+        }
+    }
+
+    /// Clamp available and held balances that end up within `[-epsilon, 0)` to
+    /// exactly zero after each transaction, adjusting total by the same amount.
+    ///
+    /// This only removes sub-epsilon negative dust; anything further below zero is
+    /// left untouched so real inconsistencies stay visible.
+    pub fn with_negative_epsilon_clamp(self, epsilon: Decimal) -> Self {
+        Self {
+            negative_epsilon: Some(epsilon.abs()),
+            ..self
+        }
+    }
+
+    /// Cap how many deposits each worker remembers for future disputes.
+    ///
+    /// Once a worker's registry grows past `capacity`, its oldest undisputed deposits
+    /// are evicted. This bounds memory on long feeds at the cost of correctness for
+    /// late disputes: a dispute, resolve or chargeback for an evicted deposit is treated
+    /// as referencing an unknown transaction and ignored. Deposits under dispute are
+    /// never evicted.
+    ///
+    /// Without a capacity the registry is unbounded: resolved and charged back deposits
+    /// stay in it as well, so later references to them can be reported as such.
+    pub fn with_registry_capacity(self, capacity: NonZero<usize>) -> Self {
+        Self {
+            registry_capacity: Some(capacity.get()),
+            ..self
+        }
+    }
+
+    /// Charge a flat `fee` on every successful withdrawal.
+    ///
+    /// The fee is debited from available and total funds together with the withdrawal.
+    /// If both can't be covered, the whole withdrawal is rejected as insufficient funds.
+    /// [`build`](Self::build) fails with [`PenguinError::NegativeFee`] for a negative fee.
+    pub fn with_withdrawal_fee(self, fee: Decimal) -> Self {
+        Self {
+            withdrawal_fee: Some(fee),
+            ..self
+        }
+    }
+
+    /// Abort the run when a client's total diverges from available + held.
+    ///
+    /// The invariant is checked after every transaction. Violations are always logged;
+    /// in strict mode the offending worker also stops and the run returns
+    /// [`PenguinError::InvariantViolation`].
+    pub fn with_strict_invariants(self) -> Self {
+        Self {
+            strict_invariants: true,
+            ..self
+        }
+    }
+
+    /// Let a deposit be disputed again after its dispute was resolved, e.g. when new
+    /// evidence comes up.
+    ///
+    /// By default resolved funds are settled: once nothing is left to dispute, later
+    /// disputes are ignored as [`IgnoreReason::ResolvedTx`].
+    /// With this policy resolved funds become disputable again, so deposits stay in the
+    /// registry (still subject to [`PenguinBuilder::with_registry_capacity`]).
+    pub fn with_allow_redispute(self, enabled: bool) -> Self {
+        Self {
+            allow_redispute: enabled,
+            ..self
+        }
+    }
+
+    /// Stop the run with [`PenguinError::DisputeAfterChargeback`] when a charged back
+    /// deposit is disputed again.
+    ///
+    /// Disputes, resolves and chargebacks of settled deposits are otherwise ignored,
+    /// reported as [`IgnoreReason::ResolvedTx`] or [`IgnoreReason::ChargedBackTx`] rather
+    /// than as unknown transactions. A second dispute of a charged back deposit most
+    /// likely means the input is replayed or corrupt, which auditors may not want to let
+    /// through even though the locked account can't change anymore.
+    pub fn with_strict_disputes(self, enabled: bool) -> Self {
+        Self {
+            strict_disputes: enabled,
+            ..self
+        }
+    }
+
+    /// Index every deposit by transaction id across clients, so a dispute, resolve or
+    /// chargeback referencing another client's deposit is reported as
+    /// [`IgnoreReason::CrossClientDispute`] instead of an unknown transaction.
+    ///
+    /// Such references usually mean the feed is malformed. The index is shared by every
+    /// worker, so it costs a lock per deposit, and with several workers a reference can
+    /// still be reported as unknown when it overtakes the other client's deposit.
+    pub fn with_cross_client_index(self, enabled: bool) -> Self {
+        Self {
+            cross_client_index: enabled,
+            ..self
+        }
+    }
+
+    /// Park withdrawals over the available funds instead of ignoring them, and retry them
+    /// after each later deposit, interest or resolve of the same client, for feeds that
+    /// deliver a withdrawal before the funds covering it.
+    ///
+    /// Parked withdrawals are retried in arrival order, stopping at the first one still
+    /// not covered, so a client's withdrawals never overtake each other. Each client parks
+    /// at most 64 of them, further ones are ignored as
+    /// [`IgnoreReason::InsufficientFunds`] like those still parked when the run ends.
+    pub fn with_pending_withdrawals(self, enabled: bool) -> Self {
+        Self {
+            pending_withdrawals: enabled,
+            ..self
+        }
+    }
+
+    /// On an [`TransactionType::Unlock`], also reopen the client's charged back deposits,
+    /// so disputes of them can be raised again, e.g. while remediating a wrong chargeback.
+    ///
+    /// Without this policy an unlock only lifts the lock and charged back deposits stay
+    /// settled. With it, what each deposit had charged back becomes disputable again, but
+    /// the funds are not credited back: a new dispute holds them out of the available
+    /// funds (which can go negative), a resolve releases them into available, and another
+    /// chargeback takes them out a second time. Credit the client with a deposit first if
+    /// the chargeback itself is being reversed.
+    pub fn with_restore_disputes_on_unlock(self, enabled: bool) -> Self {
+        Self {
+            restore_disputes_on_unlock: enabled,
+            ..self
+        }
+    }
+
+    /// Accept at most `max_per_window` transactions per client within each `window`.
+    ///
+    /// Windows are fixed and start with a client's first transaction. Transactions over
+    /// the limit are dropped with a warning (and counted with the `metrics` feature), so
+    /// a single flooding client can't monopolize its worker.
+    pub fn with_client_rate_limit(self, max_per_window: u32, window: Duration) -> Self {
+        Self {
+            rate_limit: Some(RateLimit {
+                max_per_window,
+                window,
+            }),
+            ..self
+        }
+    }
+
+    /// Keep at most `max_clients` client states in each worker, handing the least
+    /// recently active ones over to `evicted`.
+    ///
+    /// Clients are considered finalized once `max_clients` other clients of the same
+    /// worker had a transaction after theirs. Their state is moved to `evicted` as is,
+    /// held funds and lock included, and removed from the worker, which bounds memory on
+    /// feeds with many short-lived clients.
+    ///
+    /// A client that reappears is restored from `evicted` before its transaction is
+    /// applied, so balances stay correct, at the cost of a lookup in `evicted` for every
+    /// client a worker doesn't hold. Evicted clients are missing from the states
+    /// returned by [`Penguin::run`], from [`RunStats::distinct_clients`] and from
+    /// snapshots until they reappear: read them back from `evicted` after the run. Their
+    /// deposits stay in the registry, so they can still be disputed.
+    pub fn with_max_clients_per_worker(
+        self,
+        max_clients: NonZero<usize>,
+        evicted: impl EvictedStates + 'static,
+    ) -> Self {
+        Self {
+            client_limit: Some(ClientLimit {
+                max_clients: max_clients.get(),
+                evicted: Arc::new(evicted),
+            }),
+            ..self
+        }
+    }
+
+    /// Keep the last `capacity` transactions each worker received, and log them when one
+    /// fails to apply or breaks a balance invariant.
+    ///
+    /// The failing transaction is the last one of the dump, so the dump usually holds
+    /// what's needed to reproduce an incident. Each worker handles many clients, so the
+    /// buffer holds transactions of other clients too.
+    pub fn with_replay_buffer(self, capacity: NonZero<usize>) -> Self {
+        Self {
+            replay_capacity: Some(capacity.get()),
+            ..self
+        }
+    }
+
+    /// Also append the dumps of [`PenguinBuilder::with_replay_buffer`] to the file at
+    /// `path`, one block per failure, with a line per transaction.
+    pub fn with_replay_path(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            replay_path: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Set how many transactions each worker channel buffers. Defaults to 1024.
+    pub fn with_channel_capacity(self, capacity: NonZero<usize>) -> Self {
+        Self {
+            channel_capacity: Some(capacity.get()),
+            ..self
+        }
+    }
+
+    /// Retry full worker channels with `try_send` instead of blocking right away.
+    ///
+    /// A slow worker otherwise stalls the whole feed silently. With this option each
+    /// full channel is logged as a warning (and counted with the `metrics` feature), and
+    /// the feed sleeps `backoff`, doubling after every attempt, up to `retries` times.
+    /// After that it waits for room as usual: no transaction is ever dropped.
+    pub fn with_backpressure_retries(self, retries: u32, backoff: Duration) -> Self {
+        Self {
+            backpressure: Some(Backpressure { retries, backoff }),
+            ..self
+        }
+    }
+
+    /// Send transactions to workers in batches of up to `size`, instead of one by one.
+    ///
+    /// Larger batches cut the per-message channel overhead, at the cost of latency since a
+    /// batch only leaves once full or at the end of the input. Per-client ordering is
+    /// preserved. The channel capacity counts batches, not transactions.
+    pub fn with_batch_size(self, size: NonZero<usize>) -> Self {
+        Self {
+            batch_size: Some(size.get()),
+            ..self
+        }
+    }
+
+    /// Read amounts as integer minor units scaled by `10^-scale`, e.g. cents with a
+    /// scale of 2, so `5050` becomes `50.50`.
+    ///
+    /// Amounts that aren't whole numbers, or that come out with more decimal places than
+    /// [`PenguinBuilder::with_amount_decimals`] allows (4 by default), fail the run with a
+    /// [`PenguinError::InvalidRow`] on their line. The scale can be at most 28. Lines
+    /// parsed with `FromStr` or [`TransactionFormat::parse`] are already rounded to 4
+    /// places: use [`TransactionFormat::with_strict_precision`] so fractional digits
+    /// beyond that fail as well.
+    pub fn with_minor_units(self, scale: u32) -> Self {
+        Self {
+            minor_units: Some(scale),
+            ..self
+        }
+    }
+
+    /// Return client states in `order` from [`Penguin::run`] and the other runs.
+    ///
+    /// Defaults to [`OutputOrder::WorkerNative`]. [`OutputOrder::FirstSeen`] keeps the
+    /// position of every client in memory for the lifetime of the engine.
+    pub fn with_output_order(self, order: OutputOrder) -> Self {
+        Self {
+            output_order: order,
+            ..self
+        }
+    }
+
+    /// Include clients whose balances all ended at zero in the output of [`Penguin::run`]
+    /// and the other runs, e.g. after withdrawing everything they deposited.
+    ///
+    /// Defaults to `true`. Locked clients are always included, whatever their balances.
+    pub fn with_emit_zero_balances(self, enabled: bool) -> Self {
+        Self {
+            emit_zero_balances: enabled,
+            ..self
+        }
+    }
+
+    /// Fail the run with a [`PenguinError::InvalidRow`] on amounts with more decimal
+    /// places than [`PenguinBuilder::with_amount_decimals`] allows (4 by default),
+    /// instead of silently rounding them.
+    ///
+    /// Checked on the transactions the reader yields, so it covers deserialized sources
+    /// like [`PenguinBuilder::from_csv_reader`]. Lines parsed with `FromStr` or
+    /// [`TransactionFormat::parse`] are already rounded by then: use
+    /// [`TransactionFormat::with_strict_precision`] for those. With
+    /// [`PenguinBuilder::with_minor_units`] amounts are checked as they are converted
+    /// instead.
+    pub fn with_strict_precision(self, strict: bool) -> Self {
+        Self {
+            strict_precision: strict,
+            ..self
+        }
+    }
+
+    /// Allow amounts with up to `decimals` places instead of 4, e.g. 18 for token amounts,
+    /// in [`PenguinBuilder::with_strict_precision`] and [`PenguinBuilder::with_minor_units`].
+    /// Capped at 28, the largest scale a [`Decimal`] holds.
+    ///
+    /// Pair it with [`TransactionFormat::with_amount_decimals`] for parsed lines, which are
+    /// rounded before the engine sees them. Balances are still written out with 4 decimals.
+    pub fn with_amount_decimals(self, decimals: u32) -> Self {
+        Self {
+            amount_decimals: decimals.min(Decimal::MAX_SCALE),
+            ..self
+        }
+    }
+
+    /// Read the sign of deposit and withdrawal amounts as their direction, for feeds
+    /// with a single signed amount column.
+    ///
+    /// A negative deposit is applied as a withdrawal of the absolute amount and a
+    /// negative withdrawal as a deposit, which can then be disputed. Off by default,
+    /// where negative amounts are applied as they are.
+    pub fn with_signed_amounts(self, enabled: bool) -> Self {
+        Self {
+            signed_amounts: enabled,
+            ..self
+        }
+    }
+
+    /// Make reprocessing idempotent, remembering applied transactions in the file at `path`.
+    ///
+    /// Deposits and withdrawals whose `(client, tx)` was already applied are skipped, as
+    /// are disputes of a deposit that is still (even partly) under dispute, so running
+    /// the same input again after a crash doesn't double-apply it. Ignored transactions
+    /// aren't remembered. The file is loaded by [`PenguinBuilder::build`], failing with
+    /// [`PenguinError::ProcessedId`] on a line that isn't a `client,tx` pair, and
+    /// rewritten at the end of every run. Pair it with
+    /// [`Penguin::snapshot_blob`] to also restore balances across restarts; snapshots
+    /// carry the processed ids too.
+    pub fn with_processed_ids_file(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            processed_file: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Keep feeding the other workers when one of them stops early, e.g. after a panic,
+    /// instead of failing the run as soon as its channel is found closed.
+    ///
+    /// Transactions for the clients of the stopped worker are dropped from then on,
+    /// logged once and counted in [`RunStats::rows_undelivered`]. The other workers'
+    /// clients are still returned, unless the stopped worker's own failure ends the run,
+    /// see [`PenguinBuilder::with_fail_on_worker_panic`]. This covers every run as well
+    /// as [`Penguin::sink`] and [`Penguin::feed_once`], which don't record stats, so
+    /// what they drop is only logged.
+    pub fn with_skip_closed_workers(self, enabled: bool) -> Self {
+        Self {
+            skip_closed_workers: enabled,
+            ..self
+        }
+    }
+
+    /// Log a heartbeat every `interval` while the workers are running, with the number of
+    /// transactions processed so far and the depth of each worker channel.
+    ///
+    /// Heartbeats keep coming when the input goes quiet, so monitoring can tell a slow
+    /// feed from a stalled engine. They stop as soon as the workers do.
+    pub fn with_heartbeat(self, interval: Duration) -> Self {
+        Self {
+            heartbeat: Some(interval),
+            ..self
+        }
+    }
+
+    /// Return [`PenguinError::WorkerPanicked`] from the run when a worker task panics.
+    ///
+    /// By default the failure is only logged and the run carries on, silently losing
+    /// the clients owned by that worker.
+    pub fn with_fail_on_worker_panic(self, enabled: bool) -> Self {
+        Self {
+            fail_on_worker_panic: enabled,
+            ..self
+        }
+    }
+
+    /// Call `sink` with the tx id, client and [`Outcome`] of every transaction, e.g. to
+    /// reconcile individual transactions rather than final balances.
+    ///
+    /// The sink is called from the worker tasks, so outcomes of different clients may
+    /// interleave in any order; a single client's arrive in input order.
+    pub fn with_outcome_sink(
+        self,
+        sink: impl Fn(u32, u16, Outcome) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            outcome_sink: Some(Arc::new(sink)),
+            ..self
+        }
+    }
+
+    /// Count ignored transactions by the categories of `classifier` in
+    /// [`RunStats::ignored_by_category`], instead of only by [`IgnoreReason`].
+    pub fn with_ignore_classifier<C>(self, classifier: C) -> Self
+    where
+        C: IgnoreClassifier + 'static,
+    {
+        let classifier: Classifier =
+            Arc::new(move |tx, reason| classifier.classify(tx, reason).to_string());
+        Self {
+            ignore_classifier: Some(classifier),
+            ..self
+        }
+    }
+
+    /// Write the [`RunStats`] of every completed run to `path` as JSON, overwriting it.
+    pub fn with_report_path(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            report_path: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Name the input, e.g. with its path, in the [`RunStats`].
+    pub fn with_input_name(self, name: impl Into<String>) -> Self {
+        Self {
+            input_name: Some(name.into()),
+            ..self
+        }
+    }
+
+    /// Read up to `max_rows` rows from a copy of the input and report how they would be
+    /// spread over the configured workers, to spot a skewed input before a long run.
+    ///
+    /// The input is cloned, so the builder still reads it from the start. Use
+    /// `usize::MAX` to go through all of it. Unreadable rows are counted but not routed.
+    pub fn preflight(&self, max_rows: usize) -> Preflight
+    where
+        T: Clone,
+    {
+        let num_workers = self.num_workers.unwrap_or(1);
+        let mut clients: Vec<HashSet<u16>> = vec![HashSet::new(); num_workers];
+        let mut preflight = Preflight {
+            transactions_per_worker: vec![0; num_workers],
+            ..Preflight::default()
+        };
+
+        for line in self.reader.clone().take(max_rows) {
+            preflight.rows += 1;
+            let Ok(tx) = line else {
+                continue;
+            };
+            let group = usize::from(tx.client) % num_workers;
+            clients[group].insert(tx.client);
+            preflight.transactions_per_worker[group] += 1;
+        }
+        preflight.clients_per_worker = clients.iter().map(HashSet::len).collect();
+
+        preflight
+    }
+
+    /// Build a configured [`Penguin`] instance.
+    ///
+    /// The global `tracing` subscriber is only installed when logging was requested.
+    /// Fails with [`PenguinError::TooManyWorkers`] for more than `u16::MAX` workers, and
+    /// with [`PenguinError::NegativeFee`] for a negative withdrawal fee.
+    pub fn build(self) -> Result<Penguin<T>, PenguinError> {
+        let num_workers = self.num_workers.unwrap_or(1);
+        // Transactions are routed with `client % num_workers` in `u16` arithmetic.
+        if num_workers > usize::from(u16::MAX) {
+            return Err(PenguinError::TooManyWorkers(num_workers));
+        }
+        if let Some(fee) = self.withdrawal_fee
+            && fee < Decimal::ZERO
+        {
+            return Err(PenguinError::NegativeFee(fee));
+        }
+
+        let _logger = if self.log_file.is_some() || self.stderr_logs {
+            let mut logger = Logger::builder()
+                .with_stderr(self.stderr_logs)
+                .with_format(self.log_format);
+            if let Some(path) = self.log_file {
+                logger = logger.with_file(path);
+            }
+            if let Some(rotation) = self.log_rotation {
+                logger = logger.with_rotation(rotation);
+            }
+            Some(logger.try_init()?)
+        } else {
+            None
+        };
+
+        let mut ledger = self.ledger;
+        if let Some(path) = &self.processed_file {
+            ledger.processed.extend(processed::load(path)?);
+        }
+
+        Ok(Penguin {
+            reader: self.reader,
+            num_workers,
+            type_filter: self.type_filter,
+            channel_capacity: self.channel_capacity.unwrap_or(DEFAULT_CHANNEL_CAPACITY),
+            backpressure: self.backpressure,
+            batch_size: self.batch_size.unwrap_or(1),
+            minor_units: self.minor_units,
+            signed_amounts: self.signed_amounts,
+            strict_precision: self.strict_precision,
+            amount_decimals: self.amount_decimals,
+            output_order: self.output_order,
+            emit_zero_balances: self.emit_zero_balances,
+            first_seen: Map::default(),
+            fed_once: 0,
+            queues: QueueMonitor::new(num_workers),
+            heartbeat: self.heartbeat,
+            worker_config: WorkerConfig {
+                negative_epsilon: self.negative_epsilon,
+                registry_capacity: self.registry_capacity,
+                withdrawal_fee: self.withdrawal_fee,
+                strict_invariants: self.strict_invariants,
+                allow_redispute: self.allow_redispute,
+                strict_disputes: self.strict_disputes,
+                deposit_owners: self.cross_client_index.then(|| {
+                    let owners = ledger
+                        .client_tx_registry
+                        .keys()
+                        .map(|&(client, tx)| (tx, client))
+                        .collect();
+                    Arc::new(Mutex::new(owners))
+                }),
+                pending_withdrawals: self.pending_withdrawals,
+                restore_disputes_on_unlock: self.restore_disputes_on_unlock,
+                rate_limit: self.rate_limit,
+                client_limit: self.client_limit,
+                replay: self.replay_capacity.map(|capacity| ReplayConfig {
+                    capacity,
+                    path: self.replay_path,
+                }),
+                idempotent: self.processed_file.is_some(),
+                outcomes: self.outcome_sink,
+                classifier: self.ignore_classifier,
+                ..WorkerConfig::default()
+            },
+            processed_file: self.processed_file,
+            fail_on_worker_panic: self.fail_on_worker_panic,
+            skip_closed_workers: self.skip_closed_workers,
+            report_path: self.report_path,
+            input_name: self.input_name,
+            stats: None,
+            ledger,
+            _logger,
+        })
+    }
+}
+
+/// Convert an integer amount of minor units to the decimal amount, as parsing the
+/// dotted form would produce it, failing rather than rounding when it doesn't fit in
+/// `decimals` places.
+fn from_minor_units(amount: Decimal, scale: u32, decimals: u32) -> Result<Decimal, String> {
+    if !amount.fract().is_zero() {
+        return Err(format!(
+            "amount `{amount}` is not a whole number of minor units"
+        ));
+    }
+
+    let mut scaled = amount.trunc().normalize();
+    scaled
+        .set_scale(scale)
+        .map_err(|_| format!("minor unit scale {scale} is over the supported 28"))?;
+    check_precision(scaled, decimals)?;
+
+    Ok(scaled)
+}
+
+/// Reader over a CSV file, see [`PenguinBuilder::from_csv_reader`].
+pub type CsvReader<R> = iter::Map<
+    csv::DeserializeRecordsIntoIter<R, Transaction>,
+    fn(csv::Result<Transaction>) -> Result<Transaction, PenguinError>,
+>;
+
+impl<R> PenguinBuilder<CsvReader<R>>
+where
+    R: io::Read,
+{
+    /// Start a builder from a `csv` reader with `type, client, tx, amount` headers, and
+    /// an optional `currency` one.
+    ///
+    /// Records are deserialized into [`Transaction`]s. Rows with another number of fields
+    /// than the headers are reported as [`PenguinError::FieldCount`], and other `csv`
+    /// errors are turned into [`PenguinError::TransactionParse`].
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), PenguinError> {
+    /// let input = "type,client,tx,amount\ndeposit,1,1,2.0\nwithdrawal,1,2,0.5\n";
+    /// let reader = csv::ReaderBuilder::new()
+    ///     .trim(csv::Trim::All)
+    ///     .from_reader(input.as_bytes());
+    ///
+    /// let mut penguin = PenguinBuilder::from_csv_reader(reader).build()?;
+    /// let output = penguin.run().await?;
+    ///
+    /// assert_eq!(output[0].available().to_string(), "1.5");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_csv_reader(reader: csv::Reader<R>) -> Self {
+        Self::from_reader(reader.into_deserialize().map(csv_record as fn(_) -> _))
+    }
+}
+
+impl<R> PenguinBuilder<TransactionStream<R>>
+where
+    R: io::BufRead,
+{
+    /// Start a builder from lines like `deposit, 1, 1, 2.0`, parsed as they are read.
+    ///
+    /// A leading `type, client, tx, amount` header is skipped and blank lines are
+    /// ignored, see [`TransactionStream`] for the line handling.
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), PenguinError> {
+    /// let input = "type, client, tx, amount\ndeposit, 1, 1, 2.0\nwithdrawal, 1, 2, 0.5\n";
+    ///
+    /// let mut penguin = PenguinBuilder::from_bufread(input.as_bytes()).build()?;
+    /// let output = penguin.run().await?;
+    ///
+    /// assert_eq!(output[0].available().to_string(), "1.5");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_bufread(reader: R) -> Self {
+        Self::from_reader(TransactionStream::from_buf_read(reader).with_header_skipped())
+    }
+}
+
+fn csv_record(record: csv::Result<Transaction>) -> Result<Transaction, PenguinError> {
+    record.map_err(|err| match err.kind() {
+        csv::ErrorKind::UnequalLengths {
+            pos,
+            expected_len,
+            len,
+        } => PenguinError::FieldCount {
+            line: pos.as_ref().map_or(0, |pos| pos.line() as usize),
+            expected: *expected_len as usize,
+            found: *len as usize,
+        },
+        _ => PenguinError::TransactionParse(Cow::Owned(err.to_string())),
+    })
+}
+
+/// Call `op` until it succeeds, retrying up to `retries` times with a backoff doubling
+/// after every failure.
+async fn with_retries(
+    retries: u32,
+    mut backoff: Duration,
+    mut op: impl FnMut() -> Result<(), PenguinError>,
+) -> Result<(), PenguinError> {
+    for attempt in 1..=retries {
+        match op() {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                warn!(
+                    %err,
+                    attempt,
+                    backoff_ms = backoff.as_millis() as u64,
+                    "sink failed, backing off"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = backoff.saturating_mul(2);
+            }
+        }
+    }
+
+    op()
+}
+
+/// Send `batch` to a worker, retrying with backoff while its channel is full.
+///
+/// Full channels are logged and counted so head-of-line blocking is visible. Once the
+/// retries run out the send waits for room, so transactions are never dropped.
+async fn deliver(
+    sender: &mpsc::Sender<Batch>,
+    group: u16,
+    mut batch: Batch,
+    backpressure: Option<Backpressure>,
+) -> Result<(), SendError<Batch>> {
+    let Some(Backpressure {
+        retries,
+        mut backoff,
+    }) = backpressure
+    else {
+        return sender.send(batch).await;
+    };
+
+    for attempt in 1..=retries {
+        match sender.try_send(batch) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Closed(returned)) => return Err(SendError(returned)),
+            Err(TrySendError::Full(returned)) => {
+                batch = returned;
+                telemetry::channel_full(group);
+                warn!(
+                    line = batch.first().map(|(line, _)| *line),
+                    worker = group,
+                    attempt,
+                    backoff_ms = backoff.as_millis() as u64,
+                    "worker channel full, backing off"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = backoff.saturating_mul(2);
+            }
+        }
+    }
+
+    sender.send(batch).await
+}
+
+/// Reader over in-memory transactions, see [`PenguinBuilder::from_transactions`].
+pub type InMemoryReader<I> = iter::Map<I, fn(Transaction) -> Result<Transaction, Infallible>>;
+
+impl<I> PenguinBuilder<InMemoryReader<I>>
+where
+    I: Iterator<Item = Transaction>,
+{
+    /// Start a builder from transactions already in memory, such as a `Vec<Transaction>`.
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), PenguinError> {
+    /// let transactions = vec![
+    ///     "deposit, 1, 1, 2.0".parse::<Transaction>()?,
+    ///     "withdrawal, 1, 2, 0.5".parse::<Transaction>()?,
+    /// ];
+    ///
+    /// let mut penguin = PenguinBuilder::from_transactions(transactions).build()?;
+    /// let output = penguin.run().await?;
+    ///
+    /// assert_eq!(output[0].available().to_string(), "1.5");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_transactions(transactions: impl IntoIterator<IntoIter = I>) -> Self {
+        Self::from_reader(transactions.into_iter().map(Ok as fn(_) -> _))
+    }
+}
+
+/// Process transactions for a subset of clients on a worker task.
+///
+/// The worker starts from `ledger` and hands it back once the channel is closed, or
+/// early with the violation when strict invariants are enabled.
+async fn spawn_worker(
+    mut rx: mpsc::Receiver<Batch>,
+    config: WorkerConfig,
+    ledger: Ledger,
+) -> (Ledger, Result<(), PenguinError>) {
+    let _active = telemetry::ActiveWorker::start();
+    let mut eviction = config
+        .registry_capacity
+        .map(|capacity| RegistryEviction::new(capacity, &ledger.client_tx_registry));
+    let Ledger {
+        mut client_states,
+        mut client_tx_registry,
+        mut processed,
+    } = ledger;
+    let mut result = Ok(());
+    let mut limiter = config.rate_limit.map(RateLimiter::new);
+    let mut client_eviction = config
+        .client_limit
+        .clone()
+        .map(|limit| ClientEviction::new(limit, &client_states));
+    let mut replay = config.replay.clone().map(ReplayBuffer::new);
+    let mut pending: Map<u16, VecDeque<(usize, Transaction)>> = Map::default();
+
+    'recv: while let Some(batch) = rx.recv().await {
+        if let Some((queues, group)) = &config.queue {
+            queues.received(*group, batch.len());
+        }
+        for (line, tx) in batch {
+            telemetry::tx_processed(tx.tx_type);
+
+            if let Some(limiter) = &mut limiter
+                && !limiter.allow(tx.client, Instant::now())
+            {
+                config.ignore(line, &tx, IgnoreReason::RateLimited);
+                warn!(
+                    line,
+                    client = tx.client,
+                    tx = tx.tx,
+                    "client over its rate limit, dropping transaction"
+                );
+                continue;
+            }
+
+            if config.idempotent {
+                let client_tx = (tx.client, tx.tx);
+                let replayed = match tx.tx_type {
+                    TransactionType::Deposit
+                    | TransactionType::Withdrawal
+                    | TransactionType::Interest => processed.contains(&client_tx),
+                    TransactionType::Dispute => client_tx_registry
+                        .get(&client_tx)
+                        .is_some_and(Deposit::disputed),
+                    TransactionType::Resolve
+                    | TransactionType::Chargeback
+                    | TransactionType::Unlock
+                    | TransactionType::Unknown => false,
+                };
+                if replayed {
+                    config.ignore(line, &tx, IgnoreReason::AlreadyProcessed);
+                    debug!(
+                        line,
+                        client = tx.client,
+                        tx = tx.tx,
+                        "transaction already processed, skipping"
+                    );
+                    continue;
+                }
+            }
+
+            if let Some(eviction) = &mut client_eviction {
+                eviction.restore(tx.client, &mut client_states);
+            }
+            let client_state = client_states
+                .entry(tx.client)
+                .or_insert(ClientState::new(tx.client));
+            // Set before applying: an ignored first transaction still opened the account.
+            client_state.created_by_tx.get_or_insert(tx.tx);
+
+            if let Some(amount) = tx.amount
+                && tx.tx_type == TransactionType::Deposit
+                && !client_state.locked
+                && let Entry::Vacant(entry) = client_tx_registry.entry((tx.client, tx.tx))
+            {
+                entry.insert(Deposit {
+                    currency: tx.currency.clone(),
+                    ..Deposit::new(amount)
+                });
+                config.index_deposit(tx.client, tx.tx);
+
+                if let Some(eviction) = &mut eviction {
+                    eviction.track(line, (tx.client, tx.tx), &mut client_tx_registry);
+                }
+            }
+
+            if let Some(replay) = &mut replay {
+                replay.record(line, &tx);
+            }
+
+            let behind_parked = config.pending_withdrawals
+                && tx.tx_type == TransactionType::Withdrawal
+                && pending
+                    .get(&tx.client)
+                    .is_some_and(|parked| !parked.is_empty());
+            let applied = if behind_parked {
+                // Parked withdrawals of the client go first.
+                tx.validate()
+                    .map(|()| Some(IgnoreReason::InsufficientFunds))
+            } else {
+                tx.validate().and_then(|()| {
+                    apply_tx(client_state, line, &tx, &mut client_tx_registry, &config)
+                })
+            };
+
+            match applied {
+                Ok(None) => {
+                    config.apply(&tx);
+                    mark_processed(&mut processed, &tx, &config);
+                    // Only these raise the available funds a parked withdrawal waits for.
+                    if matches!(
+                        tx.tx_type,
+                        TransactionType::Deposit
+                            | TransactionType::Interest
+                            | TransactionType::Resolve
+                    ) && let Some(parked) = pending.get_mut(&tx.client)
+                    {
+                        retry_parked(
+                            client_state,
+                            parked,
+                            &mut client_tx_registry,
+                            &mut processed,
+                            &config,
+                        );
+                    }
+                }
+                Ok(Some(IgnoreReason::InsufficientFunds)) if config.pending_withdrawals => {
+                    let parked = pending.entry(tx.client).or_default();
+                    if parked.len() < MAX_PENDING_WITHDRAWALS {
+                        debug!(
+                            line,
+                            client = tx.client,
+                            tx = tx.tx,
+                            "parking withdrawal until funds cover it"
+                        );
+                        parked.push_back((line, tx.clone()));
+                    } else {
+                        config.ignore(line, &tx, IgnoreReason::InsufficientFunds);
+                    }
+                }
+                Ok(Some(reason)) => config.ignore(line, &tx, reason),
+                Err(err) => {
+                    config.ignore(line, &tx, IgnoreReason::Rejected);
+                    error!(
+                        %err,
+                        line,
+                        client = client_state.client,
+                        tx = tx.tx,
+                        "failed to apply transaction"
+                    );
+                    if let Some(replay) = &replay {
+                        replay.dump(line, &err).await;
+                    }
+                    // Only raised with strict disputes, which end the run.
+                    if matches!(err, PenguinError::DisputeAfterChargeback { .. }) {
+                        result = Err(err);
+                        break 'recv;
+                    }
+                }
+            }
+
+            if let Some(epsilon) = config.negative_epsilon {
+                clamp_negative_dust(client_state, epsilon);
+            }
+
+            if let Err(err) = check_balances(client_state) {
+                error!(
+                    %err,
+                    line,
+                    client = client_state.client,
+                    tx = tx.tx,
+                    "ledger invariant violated"
+                );
+                if let Some(replay) = &replay {
+                    replay.dump(line, &err).await;
+                }
+                if config.strict_invariants {
+                    result = Err(err);
+                    break 'recv;
+                }
+            }
+
+            if let Some(eviction) = &mut client_eviction {
+                eviction.track(tx.client, &mut client_states);
+            }
+        }
+    }
+
+    let mut unfunded: Vec<_> = pending.into_values().flatten().collect();
+    unfunded.sort_unstable_by_key(|(line, _)| *line);
+    for (line, tx) in unfunded {
+        warn!(
+            line,
+            client = tx.client,
+            tx = tx.tx,
+            "parked withdrawal never covered, dropping it"
+        );
+        config.ignore(line, &tx, IgnoreReason::InsufficientFunds);
+    }
+
+    let ledger = Ledger {
+        client_states,
+        client_tx_registry,
+        processed,
+    };
+    (ledger, result)
+}
+
+/// Remember an applied deposit, withdrawal or interest, so reprocessing skips it.
+///
+/// Only called once the transaction changed the balances: an ignored one is still
+/// applied when the input is run again.
+fn mark_processed(processed: &mut Set<ClientTx>, tx: &Transaction, config: &WorkerConfig) {
+    if config.idempotent
+        && matches!(
+            tx.tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Interest
+        )
+    {
+        processed.insert((tx.client, tx.tx));
+    }
+}
+
+/// Apply the withdrawals a client parked, in arrival order, until one is still not covered.
+fn retry_parked(
+    client_state: &mut ClientState,
+    parked: &mut VecDeque<(usize, Transaction)>,
+    client_tx_registry: &mut Map<ClientTx, Deposit>,
+    processed: &mut Set<ClientTx>,
+    config: &WorkerConfig,
+) {
+    while let Some((line, tx)) = parked.pop_front() {
+        match apply_tx(client_state, line, &tx, client_tx_registry, config) {
+            Ok(None) => {
+                debug!(
+                    line,
+                    client = tx.client,
+                    tx = tx.tx,
+                    "applied parked withdrawal"
+                );
+                config.apply(&tx);
+                mark_processed(processed, &tx, config);
+            }
+            Ok(Some(IgnoreReason::InsufficientFunds)) => {
+                parked.push_front((line, tx));
+                break;
+            }
+            Ok(Some(reason)) => config.ignore(line, &tx, reason),
+            Err(err) => {
+                config.ignore(line, &tx, IgnoreReason::Rejected);
+                error!(
+                    %err,
+                    line,
+                    client = tx.client,
+                    tx = tx.tx,
+                    "failed to apply parked withdrawal"
+                );
+            }
+        }
+    }
+}
+
+/// Fixed-window transaction counters for the clients of one worker.
+struct RateLimiter {
+    limit: RateLimit,
+    /// Start of the current window and transactions accepted in it, per client.
+    windows: Map<u16, (Instant, u32)>,
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            windows: Map::default(),
+        }
+    }
+
+    /// Count a transaction for `client` at `now`, returning whether it's within the limit.
+    fn allow(&mut self, client: u16, now: Instant) -> bool {
+        let (start, count) = self.windows.entry(client).or_insert((now, 0));
+        if now.duration_since(*start) >= self.limit.window {
+            *start = now;
+            *count = 0;
+        }
+
+        if *count < self.limit.max_per_window {
+            *count += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The last transactions one worker received, dumped when applying one of them fails.
+struct ReplayBuffer {
+    config: ReplayConfig,
+    entries: VecDeque<(usize, Transaction)>,
+}
+
+impl ReplayBuffer {
+    fn new(config: ReplayConfig) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(config.capacity),
+            config,
+        }
+    }
+
+    /// Remember the transaction from input `line`, forgetting the oldest one when full.
+    fn record(&mut self, line: usize, tx: &Transaction) {
+        if self.entries.len() == self.config.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((line, tx.clone()));
+    }
+
+    /// Log the buffered transactions, oldest first, after the error raised on `line`, and
+    /// append them to the dump file if any, off the async worker thread.
+    async fn dump(&self, line: usize, err: &PenguinError) {
+        let mut report = format!("line {line}: {err}\n");
+        for (line, tx) in &self.entries {
+            let amount = tx.amount.map(|amount| amount.to_string());
+            let _ = writeln!(
+                report,
+                "{line}: {}, {}, {}, {}",
+                tx.tx_type,
+                tx.client,
+                tx.tx,
+                amount.as_deref().unwrap_or_default()
+            );
+        }
+        error!(
+            line,
+            transactions = self.entries.len(),
+            "last transactions before the failure:\n{report}"
+        );
+
+        if let Some(path) = &self.config.path {
+            let file = path.clone();
+            let written = task::spawn_blocking(move || {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(file)
+                    .and_then(|mut file| file.write_all(report.as_bytes()))
+            })
+            .await
+            .unwrap_or_else(|err| Err(io::Error::other(err)));
+            if let Err(err) = written {
+                warn!(%err, path = %path.display(), "failed to write the replay dump");
+            }
+        }
+    }
+}
+
+/// Bounds a worker's registry by evicting its oldest undisputed deposits.
+struct RegistryEviction {
+    capacity: usize,
+    /// Registry keys in insertion order. May contain keys already removed from the
+    /// registry by a resolve or chargeback; those are skipped lazily.
+    order: VecDeque<ClientTx>,
+}
+
+impl RegistryEviction {
+    fn new(capacity: usize, registry: &Map<ClientTx, Deposit>) -> Self {
+        Self {
+            capacity,
+            order: registry.keys().copied().collect(),
+        }
+    }
+
+    /// Record a deposit registered from input `line` and evict until the registry fits again.
+    fn track(&mut self, line: usize, client_tx: ClientTx, registry: &mut Map<ClientTx, Deposit>) {
+        self.order.push_back(client_tx);
+
+        // Disputed deposits are rotated to the back, so give up after one full pass.
+        let mut remaining = self.order.len();
+        while registry.len() > self.capacity && remaining > 0 {
+            remaining -= 1;
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+
+            match registry.get(&oldest).map(Deposit::disputed) {
+                Some(true) => self.order.push_back(oldest),
+                Some(false) => {
+                    registry.remove(&oldest);
+                }
+                None => {}
+            }
+        }
+
+        if registry.len() > self.capacity {
+            warn!(
+                line,
+                capacity = self.capacity,
+                len = registry.len(),
+                "registry over capacity, every remaining deposit is disputed"
+            );
+        }
+
+        // Drop keys resolved or charged back in the meantime so the queue stays bounded.
+        if self.order.len() > self.capacity.saturating_mul(2) {
+            self.order
+                .retain(|client_tx| registry.contains_key(client_tx));
+        }
+    }
+}
+
+/// Bounds a worker's client states by evicting the least recently active clients.
+struct ClientEviction {
+    limit: ClientLimit,
+    /// Clients with the sequence number of each of their transactions, oldest first.
+    /// Entries superseded by a later transaction of the same client are skipped lazily.
+    order: VecDeque<(u16, u64)>,
+    /// Sequence number of each held client's latest transaction.
+    last_seen: Map<u16, u64>,
+    sequence: u64,
+}
+
+impl ClientEviction {
+    fn new(limit: ClientLimit, client_states: &Map<u16, ClientState>) -> Self {
+        let mut eviction = Self {
+            limit,
+            order: VecDeque::new(),
+            last_seen: Map::default(),
+            sequence: 0,
+        };
+        for client in client_states.keys() {
+            eviction.touch(*client);
+        }
+        eviction
+    }
+
+    /// Move `client` back from the evicted states if the worker doesn't hold it.
+    fn restore(&self, client: u16, client_states: &mut Map<u16, ClientState>) {
+        if !client_states.contains_key(&client)
+            && let Some(state) = self.limit.evicted.restore(client)
+        {
+            client_states.insert(client, state);
+        }
+    }
+
+    fn touch(&mut self, client: u16) {
+        self.sequence += 1;
+        self.last_seen.insert(client, self.sequence);
+        self.order.push_back((client, self.sequence));
+    }
+
+    /// Record a transaction of `client` and evict until the worker fits again.
+    fn track(&mut self, client: u16, client_states: &mut Map<u16, ClientState>) {
+        self.touch(client);
+
+        while client_states.len() > self.limit.max_clients {
+            let Some((oldest, sequence)) = self.order.pop_front() else {
+                break;
+            };
+            if self.last_seen.get(&oldest) != Some(&sequence) {
+                continue;
+            }
+
+            self.last_seen.remove(&oldest);
+            if let Some(state) = client_states.remove(&oldest) {
+                debug!(client = oldest, "evicting idle client");
+                self.limit.evicted.evict(state);
+            }
+        }
+
+        // Drop superseded entries so the queue stays bounded.
+        if self.order.len() > self.limit.max_clients.saturating_mul(2) {
+            let last_seen = &self.last_seen;
+            self.order
+                .retain(|(client, sequence)| last_seen.get(client) == Some(sequence));
+        }
+    }
+}
+
+/// Snap available and held balances within `[-epsilon, 0)` to zero, in every
+/// currency, moving total by the same amount so it stays `available + held`.
+fn clamp_negative_dust(client_state: &mut ClientState, epsilon: Decimal) {
+    let clamp = |available: &mut Decimal, held: &mut Decimal, total: &mut Decimal| {
+        for balance in [available, held] {
+            if *balance < Decimal::ZERO && *balance >= -epsilon {
+                *total -= *balance;
+                *balance = Decimal::ZERO;
+            }
+        }
+    };
+
+    clamp(
+        &mut client_state.available,
+        &mut client_state.held,
+        &mut client_state.total,
+    );
+    for balances in client_state.currencies.values_mut() {
+        clamp(
+            &mut balances.available,
+            &mut balances.held,
+            &mut balances.total,
+        );
+    }
+}
+
+/// Check that total equals available + held, in every currency.
+fn check_balances(client_state: &ClientState) -> Result<(), PenguinError> {
+    let consistent = |balances: Balances| balances.total == balances.available + balances.held;
+
+    if consistent(client_state.balances(None))
+        && client_state.currencies.values().copied().all(consistent)
+    {
+        Ok(())
+    } else {
+        Err(PenguinError::InvariantViolation(
+            client_state.client,
+            "total differs from available + held",
+        ))
+    }
+}
+
+/// Warn about a dispute, resolve or chargeback whose deposit isn't in the registry, and
+/// tell whether it belongs to another client.
+fn unknown_deposit(line: usize, tx: &Transaction, config: &WorkerConfig) -> IgnoreReason {
+    if let Some(owner) = config.deposit_owner(tx.tx)
+        && owner != tx.client
+    {
+        warn!(
+            line,
+            client = tx.client,
+            tx = tx.tx,
+            owner,
+            "{} for another client's transaction",
+            tx.tx_type
+        );
+
+        return IgnoreReason::CrossClientDispute;
+    }
+    warn!(
+        line,
+        client = tx.client,
+        tx = tx.tx,
+        "{} for unknown transaction",
+        tx.tx_type
+    );
+
+    IgnoreReason::UnknownTx
+}
+
+/// Apply a single transaction to a client state.
+///
+/// `line` is the input line the transaction came from, used to correlate logs. With
+/// debug logs it is also recorded with the client and transaction on a `tx` span, so logs
+/// can be filtered by client (e.g. `RUST_LOG=[tx{client=3}]=debug`).
+/// Returns why the transaction was ignored, if it didn't change any balance.
+fn apply_tx(
+    client_state: &mut ClientState,
+    line: usize,
+    tx: &Transaction,
+    client_tx_registry: &mut Map<ClientTx, Deposit>,
+    config: &WorkerConfig,
+) -> Result<Option<IgnoreReason>, PenguinError> {
+    use TransactionType as TType;
+
+    // Only at `debug`: the events below carry these fields themselves, so the default
+    // filter doesn't pay for a span per transaction.
+    let _span =
+        debug_span!("tx", line, client = tx.client, tx = tx.tx, tx_type = %tx.tx_type).entered();
+
+    // Settled deposits come first: a charged back one always belongs to a locked client.
+    if matches!(
+        tx.tx_type,
+        TType::Dispute | TType::Resolve | TType::Chargeback
+    ) && let Some(settlement) = client_tx_registry
+        .get(&(tx.client, tx.tx))
+        .and_then(|deposit| deposit.settled)
+    {
+        return settled_tx(line, tx, settlement, config);
+    }
+
+    // An unlock is the only transaction a locked account takes.
+    if client_state.locked && tx.tx_type != TType::Unlock {
+        warn!(
+            line,
+            client = client_state.client,
+            tx = tx.tx,
+            "Received transaction for locked client. Ignoring it."
+        );
+
+        return Ok(Some(IgnoreReason::LockedClient));
+    }
+
+    if matches!(
+        tx.tx_type,
+        TType::Dispute | TType::Resolve | TType::Chargeback
+    ) && let Some(deposit) = client_tx_registry.get(&(tx.client, tx.tx))
+        && !deposit.accepts(tx.currency.as_deref())
+    {
+        warn!(
+            line,
+            client = tx.client,
+            tx = tx.tx,
+            currency = tx.currency.as_deref(),
+            deposit_currency = deposit.currency.as_deref(),
+            "currency does not match the referenced deposit"
+        );
+
+        return Ok(Some(IgnoreReason::CurrencyMismatch));
+    }
+
+    match tx.tx_type {
+        // Interest credits like a deposit, but never enters the registry.
+        TType::Deposit | TType::Interest => {
+            let amount = tx
+                .amount
+                .ok_or(PenguinError::DepositOrWithdrawalWithoutAmount(
+                    client_state.client,
+                ))?;
+            let mut balances = client_state.balances(tx.currency.as_deref());
+            balances.available += amount;
+            balances.total += amount;
+            client_state.set_balances(tx.currency.as_deref(), balances);
+        }
+        TType::Withdrawal => {
+            let amount = tx
+                .amount
+                .ok_or(PenguinError::DepositOrWithdrawalWithoutAmount(
+                    client_state.client,
+                ))?;
+            let fee = config.withdrawal_fee.unwrap_or(Decimal::ZERO);
+            let balances = client_state.balances(tx.currency.as_deref());
+            let mut debited = balances;
+            debited.available -= amount + fee;
+            debited.total -= amount + fee;
+
+            // Held funds back open disputes, so only available funds can be withdrawn and
+            // what's left in total must still cover what was held before the withdrawal.
+            if debited.available < Decimal::ZERO || debited.total < balances.held {
+                warn!(
+                    line,
+                    client = client_state.client,
+                    tx = tx.tx,
+                    amount = %amount,
+                    fee = %fee,
+                    available = %balances.available,
+                    held = %balances.held,
+                    "insufficient funds for withdrawal, held funds can't be withdrawn"
+                );
+
+                return Ok(Some(IgnoreReason::InsufficientFunds));
+            }
+            client_state.set_balances(tx.currency.as_deref(), debited);
+        }
+        TType::Dispute => {
+            let Some(deposit) = client_tx_registry.get_mut(&(tx.client, tx.tx)) else {
+                return Ok(Some(unknown_deposit(line, tx, config)));
+            };
+
+            // Without an amount, whatever is left of the deposit is disputed.
+            let amount = tx.amount.unwrap_or(deposit.disputable);
+            if amount <= Decimal::ZERO || amount > deposit.disputable {
+                warn!(
+                    line,
+                    client = tx.client,
+                    tx = tx.tx,
+                    amount = %amount,
+                    disputable = %deposit.disputable,
+                    "dispute amount exceeds what is left to dispute"
+                );
+
+                return Ok(Some(IgnoreReason::AmountUnavailable));
+            }
+
+            deposit.disputable -= amount;
+            deposit.held += amount;
+            let mut balances = client_state.balances(deposit.currency.as_deref());
+            balances.held += amount;
+            balances.available -= amount;
+            client_state.set_balances(deposit.currency.as_deref(), balances);
+            client_state.disputes_seen += 1;
+        }
+        TType::Resolve => {
+            let Some(deposit) = client_tx_registry.get_mut(&(tx.client, tx.tx)) else {
+                return Ok(Some(unknown_deposit(line, tx, config)));
+            };
+
+            // Without an amount, everything held for the deposit is released.
+            let amount = tx.amount.unwrap_or(deposit.held);
+            if amount <= Decimal::ZERO || amount > deposit.held {
+                warn!(
+                    line,
+                    client = tx.client,
+                    tx = tx.tx,
+                    amount = %amount,
+                    held = %deposit.held,
+                    "resolve amount exceeds what is held for the transaction"
+                );
+
+                return Ok(Some(IgnoreReason::AmountUnavailable));
+            }
+
+            let mut balances = client_state.balances(deposit.currency.as_deref());
+            if balances.held < amount {
+                return Err(PenguinError::InvariantViolation(
+                    client_state.client,
+                    "resolve would leave held funds negative",
+                ));
+            }
+
+            balances.held -= amount;
+            balances.available += amount;
+            client_state.set_balances(deposit.currency.as_deref(), balances);
+
+            // Unless re-disputes are allowed, resolved funds are settled; keep the deposit
+            // while some of it can still be disputed.
+            deposit.held -= amount;
+            if config.allow_redispute {
+                deposit.disputable += amount;
+            } else if deposit.held.is_zero() && deposit.disputable.is_zero() {
+                deposit.settled = Some(Settlement::Resolved);
+            }
+        }
+        TType::Chargeback => {
+            let Some(deposit) = client_tx_registry.get_mut(&(tx.client, tx.tx)) else {
+                return Ok(Some(unknown_deposit(line, tx, config)));
+            };
+
+            // A chargeback reverses everything held for the deposit.
+            if !deposit.disputed() {
+                warn!(
+                    line,
+                    client = tx.client,
+                    tx = tx.tx,
+                    "chargeback for undisputed transaction"
+                );
+
+                return Ok(Some(IgnoreReason::AmountUnavailable));
+            }
+
+            let mut balances = client_state.balances(deposit.currency.as_deref());
+            if balances.held < deposit.held {
+                return Err(PenguinError::InvariantViolation(
+                    client_state.client,
+                    "chargeback would leave held funds negative",
+                ));
+            }
+
+            balances.held -= deposit.held;
+            balances.total -= deposit.held;
+            client_state.set_balances(deposit.currency.as_deref(), balances);
+            client_state.locked = true;
+
+            deposit.charged_back += deposit.held;
+            deposit.held = Decimal::ZERO;
+            deposit.settled = Some(Settlement::ChargedBack);
+        }
+        TType::Unlock => {
+            if !client_state.locked {
+                warn!(
+                    line,
+                    client = tx.client,
+                    tx = tx.tx,
+                    "unlock for an account that isn't locked"
+                );
+
+                return Ok(Some(IgnoreReason::NotLocked));
+            }
+
+            client_state.locked = false;
+            if config.restore_disputes_on_unlock {
+                for (&(client, _), deposit) in client_tx_registry.iter_mut() {
+                    if client == tx.client && deposit.settled == Some(Settlement::ChargedBack) {
+                        deposit.disputable += mem::take(&mut deposit.charged_back);
+                        deposit.settled = None;
+                    }
+                }
+            }
+        }
+        TType::Unknown => {
+            warn!(
+                line,
+                client = tx.client,
+                tx = tx.tx,
+                "transaction of unknown type"
+            );
+
+            return Ok(Some(IgnoreReason::UnknownType));
+        }
+    }
+
+    client_state.last_tx = Some(tx.tx);
+
+    Ok(None)
+}
+
+/// Ignore a dispute, resolve or chargeback of a deposit that was already settled, or
+/// fail a dispute of a charged back one with strict disputes.
+fn settled_tx(
+    line: usize,
+    tx: &Transaction,
+    settlement: Settlement,
+    config: &WorkerConfig,
+) -> Result<Option<IgnoreReason>, PenguinError> {
+    match settlement {
+        Settlement::Resolved => {
+            warn!(
+                line,
+                client = tx.client,
+                tx = tx.tx,
+                tx_type = ?tx.tx_type,
+                "transaction was already resolved"
+            );
+
+            Ok(Some(IgnoreReason::ResolvedTx))
+        }
+        Settlement::ChargedBack => {
+            warn!(
+                line,
+                client = tx.client,
+                tx = tx.tx,
+                tx_type = ?tx.tx_type,
+                "transaction was already charged back"
+            );
+            if config.strict_disputes && tx.tx_type == TransactionType::Dispute {
+                return Err(PenguinError::DisputeAfterChargeback {
+                    client: tx.client,
+                    tx: tx.tx,
+                });
+            }
+
+            Ok(Some(IgnoreReason::ChargedBackTx))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Write};
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    const LINE: usize = 1;
+
+    /// In-memory log sink so tests can assert on emitted traces.
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LogBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+
+        /// Capture every event up to `level` emitted on this thread while the guard lives.
+        fn capture(&self, level: tracing::Level) -> tracing::subscriber::DefaultGuard {
+            let subscriber = tracing_subscriber::fmt()
+                .with_writer({
+                    let logs = self.clone();
+                    move || logs.clone()
+                })
+                .with_max_level(level)
+                .with_ansi(false)
+                .finish();
+
+            tracing::subscriber::set_default(subscriber)
+        }
+    }
+
+    fn dec(value: &str) -> Decimal {
+        Decimal::from_str(value).expect("valid decimal")
+    }
+
+    fn tx(tx_type: TransactionType, client: u16, tx: u32, amount: Option<Decimal>) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount,
+            currency: None,
+        }
+    }
+
+    /// Engine without a logger, so tests don't fight over the global subscriber.
+    fn penguin<T>(reader: T, num_workers: usize) -> Penguin<T> {
+        Penguin {
+            reader,
+            num_workers,
+            type_filter: None,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            backpressure: None,
+            batch_size: 1,
+            minor_units: None,
+            signed_amounts: false,
+            strict_precision: false,
+            amount_decimals: AMOUNT_DECIMALS,
+            output_order: OutputOrder::default(),
+            emit_zero_balances: true,
+            first_seen: Map::default(),
+            fed_once: 0,
+            queues: QueueMonitor::new(num_workers),
+            heartbeat: None,
+            processed_file: None,
+            fail_on_worker_panic: false,
+            skip_closed_workers: false,
+            report_path: None,
+            input_name: None,
+            stats: None,
+            worker_config: WorkerConfig::default(),
+            ledger: Ledger::default(),
+            _logger: None,
+        }
+    }
+
+    fn parsed(inputs: &[&str]) -> std::vec::IntoIter<TxResult<PenguinError>> {
+        inputs
+            .iter()
+            .map(|line| Ok(line.parse::<Transaction>().expect("valid transaction")))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn assert_state(
+        state: &ClientState,
+        client: u16,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+    ) {
+        assert_eq!(state.client, client);
+        assert_eq!(state.available, available);
+        assert_eq!(state.held, held);
+        assert_eq!(state.total, total);
+    }
+
+    #[tokio::test]
+    async fn run_multiple_clients_with_mixed_transactions() {
+        let inputs = [
+            "deposit, 1, 1, 1.0",
+            "deposit, 2, 2, 2.0",
+            "deposit, 1, 3, 2.0",
+            "withdrawal, 1, 4, 1.5",
+            "withdrawal, 2, 5, 3.0",
+            "deposit, 1, 5,",
+        ];
+        let reader = inputs.into_iter().map(|line| {
+            Ok::<Transaction, PenguinError>(line.parse::<Transaction>().expect("valid transaction"))
+        });
+        let mut penguin = penguin(reader, 2);
+
+        let mut output = penguin.run().await.expect("run should succeed");
+        output.sort_by_key(|state| state.client);
+
+        assert_eq!(output.len(), 2);
+        assert_state(&output[0], 1, dec("1.5"), dec("0"), dec("1.5"));
+        assert_state(&output[1], 2, dec("2"), dec("0"), dec("2"));
+    }
+
+    // CI runs the suite with and without `fxhash`, so this pins both hashers' output.
+    #[tokio::test]
+    async fn results_do_not_depend_on_the_hasher() {
+        let inputs: Vec<String> = (1..=50u32)
+            .flat_map(|id| {
+                let client = id % 7;
+                [
+                    format!("deposit, {client}, {id}, {id}.5"),
+                    format!("withdrawal, {client}, {}, 1.0", 1000 + id),
+                ]
+            })
+            .chain(["dispute, 3, 3,".into(), "chargeback, 3, 3,".into()])
+            .collect();
+        let inputs: Vec<&str> = inputs.iter().map(String::as_str).collect();
+
+        let output = penguin(parsed(&inputs), 3)
+            .run_to_map()
+            .await
+            .expect("run should succeed");
+
+        let mut totals: Vec<_> = output
+            .values()
+            .map(|state| (state.client, state.total, state.locked))
+            .collect();
+        totals.sort_by_key(|(client, _, _)| *client);
+        assert_eq!(
+            totals,
+            [
+                (0, dec("192.5"), false),
+                (1, dec("200.0"), false),
+                (2, dec("157.5"), false),
+                (3, dec("161.0"), true),
+                (4, dec("171.5"), false),
+                (5, dec("178.5"), false),
+                (6, dec("185.5"), false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sink_applies_pushed_transactions() {
+        let mut engine = penguin(parsed(&[]), 2);
+        let mut sink = engine.sink();
+        for transaction in [
+            tx(TransactionType::Deposit, 1, 1, Some(dec("10.0"))),
+            tx(TransactionType::Deposit, 2, 2, Some(dec("5.0"))),
+            tx(TransactionType::Withdrawal, 1, 3, Some(dec("2.5"))),
+            tx(TransactionType::Dispute, 2, 2, None),
+            tx(TransactionType::Withdrawal, 2, 4, Some(dec("1.0"))),
+        ] {
+            sink.send(transaction).await.expect("workers running");
+        }
+
+        let mut states = sink.finish().await.expect("sink finishes");
+        states.sort_by_key(|state| state.client);
+
+        assert_eq!(states.len(), 2);
+        assert_state(&states[0], 1, dec("7.5"), dec("0"), dec("7.5"));
+        // The withdrawal can't touch the disputed funds.
+        assert_state(&states[1], 2, dec("0"), dec("5.0"), dec("5.0"));
+        // Pushed states are kept for later runs.
+        assert_eq!(engine.ledger.client_states.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn sink_names_the_worker_and_client_of_a_closed_channel() {
+        let mut engine = penguin(parsed(&[]), 2);
+        let (open, _open_rx) = mpsc::channel(16);
+        let (closed, closed_rx) = mpsc::channel(16);
+        drop(closed_rx);
+        let mut sink = PenguinSink {
+            penguin: &mut engine,
+            senders: vec![PollSender::new(open), PollSender::new(closed)],
+            workers: JoinSet::new(),
+            closed: Set::default(),
+            line: 0,
+        };
+
+        sink.send(tx(TransactionType::Deposit, 2, 1, Some(dec("1.0"))))
+            .await
+            .expect("worker 0 is running");
+        let err = sink
+            .send(tx(TransactionType::Deposit, 3, 2, Some(dec("1.0"))))
+            .await
+            .expect_err("worker 1 is gone");
+
+        assert!(matches!(
+            err,
+            PenguinError::WorkerChannelClosed {
+                worker: 1,
+                client: 3
+            }
+        ));
+
+        // With closed workers skipped, only that worker's clients are dropped.
+        sink.penguin.skip_closed_workers = true;
+        for client in [3, 5, 2] {
+            sink.send(tx(TransactionType::Deposit, client, 3, Some(dec("1.0"))))
+                .await
+                .expect("closed worker skipped");
+        }
+        assert_eq!(sink.closed, Set::from_iter([1]));
+    }
+
+    #[tokio::test]
+    async fn feed_once_applies_one_transaction_at_a_time() {
+        let mut engine = penguin(parsed(&[]), 2);
+
+        engine
+            .feed_once(tx(TransactionType::Deposit, 1, 1, Some(dec("10.0"))))
+            .await
+            .expect("deposit applied");
+        let states = engine.states().await;
+        assert_eq!(states.len(), 1);
+        assert_state(&states[0], 1, dec("10"), dec("0"), dec("10"));
+
+        engine
+            .feed_once(tx(TransactionType::Dispute, 1, 1, None))
+            .await
+            .expect("dispute applied");
+        assert_state(&engine.states().await[0], 1, dec("0"), dec("10"), dec("10"));
+
+        engine
+            .feed_once(tx(TransactionType::Resolve, 1, 1, None))
+            .await
+            .expect("resolve applied");
+        assert_state(&engine.states().await[0], 1, dec("10"), dec("0"), dec("10"));
+    }
+
+    #[tokio::test]
+    async fn dropping_the_sink_aborts_the_workers() {
+        let mut engine = penguin(parsed(&[]), 2);
+        // Every worker holds a clone of its config, so the count drops back once they are gone.
+        let skipped = Arc::clone(&engine.worker_config.skipped);
+        let mut sink = engine.sink();
+        sink.send(tx(TransactionType::Deposit, 1, 1, Some(dec("10.0"))))
+            .await
+            .expect("workers running");
+        drop(sink);
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while Arc::strong_count(&skipped) > 2 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("workers should stop once the sink is dropped");
+    }
+
+    #[tokio::test]
+    async fn outcome_sink_reports_every_transaction() {
+        let inputs = [
+            "deposit, 1, 1, 5.0",
+            "withdrawal, 1, 2, 8.0",
+            "dispute, 1, 9,",
+            "dispute, 1, 1,",
+            "chargeback, 1, 1,",
+            "deposit, 1, 3, 1.0",
+            "withdrawal, 2, 4, 1.0",
+        ];
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+        let mut penguin = Penguin {
+            worker_config: WorkerConfig {
+                outcomes: Some({
+                    let outcomes = outcomes.clone();
+                    Arc::new(move |tx: u32, client: u16, outcome: Outcome| {
+                        outcomes.lock().unwrap().push((tx, client, outcome));
+                    })
+                }),
+                ..WorkerConfig::default()
+            },
+            ..penguin(parsed(&inputs), 1)
+        };
+
+        penguin.run().await.expect("run should succeed");
+
+        assert_eq!(
+            *outcomes.lock().unwrap(),
+            [
+                (1, 1, Outcome::Applied),
+                (2, 1, Outcome::Ignored(IgnoreReason::InsufficientFunds)),
+                (9, 1, Outcome::Ignored(IgnoreReason::UnknownTx)),
+                (1, 1, Outcome::Applied),
+                (1, 1, Outcome::Applied),
+                (3, 1, Outcome::Ignored(IgnoreReason::LockedClient)),
+                (4, 2, Outcome::Ignored(IgnoreReason::InsufficientFunds)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn ignore_classifier_counts_custom_categories() {
+        enum Bucket {
+            Account,
+            Input,
+        }
+
+        impl std::fmt::Display for Bucket {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(match self {
+                    Bucket::Account => "account",
+                    Bucket::Input => "input",
+                })
+            }
+        }
+
+        struct TwoBuckets;
+
+        impl IgnoreClassifier for TwoBuckets {
+            type Category = Bucket;
+
+            fn classify(&self, _tx: &Transaction, reason: IgnoreReason) -> Bucket {
+                match reason {
+                    IgnoreReason::LockedClient | IgnoreReason::InsufficientFunds => Bucket::Account,
+                    _ => Bucket::Input,
+                }
+            }
+        }
+
+        let mut penguin = PenguinBuilder::from_reader(parsed(&[
+            "deposit, 1, 1, 5.0",
+            "withdrawal, 1, 2, 8.0",
+            "dispute, 1, 9,",
+            "dispute, 1, 1,",
+            "chargeback, 1, 1,",
+            "deposit, 1, 3, 1.0",
+            "resolve, 2, 4,",
+        ]))
+        .with_ignore_classifier(TwoBuckets)
+        .build()
+        .expect("valid configuration");
+
+        penguin.run().await.expect("run should succeed");
+
+        let stats = penguin.run_stats().expect("stats after a run");
+        assert_eq!(stats.rows_skipped, 4);
+        assert_eq!(
+            stats.ignored_by_category,
+            BTreeMap::from([("account".to_owned(), 2), ("input".to_owned(), 2)])
+        );
+    }
+
+    #[tokio::test]
+    async fn clients_owned_by_several_workers_are_an_invariant_violation() {
+        let mut engine = penguin(parsed(&[]), 2);
+        let mut workers = JoinSet::new();
+        // A broken sharder routing client 1 to both workers.
+        for (group_id, id) in [(1, 2), (0, 1)] {
+            let (sender, rx) = mpsc::channel(1);
+            sender
+                .send(vec![(
+                    LINE,
+                    tx(TransactionType::Deposit, 1, id, Some(dec("1.0"))),
+                )])
+                .await
+                .expect("worker channel open");
+            drop(sender);
+            workers.spawn(
+                spawn_worker(rx, WorkerConfig::default(), Ledger::default())
+                    .map(move |(shard, result)| (group_id, shard, result, BTreeMap::new())),
+            );
+        }
+
+        let err = engine
+            .join_workers(workers)
+            .await
+            .expect_err("expected duplicate client to be caught");
+
+        assert!(matches!(err, PenguinError::InvariantViolation(1, _)));
+        assert_eq!(engine.ledger.client_states.len(), 1);
+        // Shards are folded in group order, so the first worker's state always wins.
+        assert_eq!(engine.ledger.client_states[&1].last_tx, Some(1));
+    }
+
+    #[tokio::test]
+    async fn worker_native_order_is_stable_across_runs() {
+        let inputs: Vec<String> = (1..=64)
+            .map(|client| format!("deposit, {client}, {client}, 1.0"))
+            .collect();
+        let inputs: Vec<&str> = inputs.iter().map(String::as_str).collect();
+        let clients = || async {
+            penguin(parsed(&inputs), 4)
+                .run()
+                .await
+                .expect("run should succeed")
+                .into_iter()
+                .map(|state| state.client)
+                .collect::<Vec<_>>()
+        };
+
+        let first = clients().await;
+        // Worker 0 holds the multiples of 4.
+        assert_eq!(first[..3], [4, 8, 12]);
+        for _ in 0..10 {
+            assert_eq!(clients().await, first);
+        }
+    }
+
+    #[tokio::test]
+    async fn run_stream_matches_run() {
+        let inputs = [
+            "deposit, 1, 1, 10.0",
+            "deposit, 2, 2, 5.0",
+            "withdrawal, 1, 3, 2.5",
+            "dispute, 2, 2,",
+            "chargeback, 2, 2,",
+        ];
+        let summary = |mut states: Vec<ClientState>| {
+            states.sort_by_key(|state| state.client);
+            states
+                .into_iter()
+                .map(|state| (state.client, state.available, state.held, state.locked))
+                .collect::<Vec<_>>()
+        };
+
+        let expected = summary(
+            penguin(parsed(&inputs), 2)
+                .run()
+                .await
+                .expect("run should succeed"),
+        );
+        let streamed = summary(
+            penguin(parsed(&[]), 2)
+                .run_stream(futures_util::stream::iter(parsed(&inputs)))
+                .await
+                .expect("streamed run should succeed"),
+        );
+        assert_eq!(streamed, expected);
+
+        let err = penguin(parsed(&[]), 2)
+            .run_stream(futures_util::stream::iter([
+                Ok(tx(TransactionType::Deposit, 1, 1, Some(dec("1.0")))),
+                "deposit, 1".parse::<Transaction>(),
+            ]))
+            .await
+            .expect_err("expected the unreadable item to fail the run");
+        assert!(
+            matches!(err, PenguinError::FieldCount { line: 2, .. }),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn csv_rows_with_a_wrong_number_of_fields_name_the_line() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndispute,1,1\ndeposit,1,2,1.0,EUR\n";
+        let reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input.as_bytes());
+        let rows: Vec<_> = PenguinBuilder::from_csv_reader(reader).reader.collect();
+
+        assert!(rows[0].is_ok());
+        assert!(matches!(
+            rows[1..],
+            [
+                Err(PenguinError::FieldCount {
+                    line: 3,
+                    expected: 4,
+                    found: 3,
+                }),
+                Err(PenguinError::FieldCount {
+                    line: 4,
+                    expected: 4,
+                    found: 5,
+                }),
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn verify_against_reports_balances_off_the_expectations() {
+        let inputs = [
+            "deposit, 1, 1, 10.0",
+            "withdrawal, 1, 2, 2.5",
+            "deposit, 2, 3, 5.0",
+            "dispute, 2, 3,",
+            "deposit, 3, 4, 1.0, EUR",
+        ];
+        let path = |name: &str| {
+            std::env::temp_dir().join(format!("penguin-{name}-{}.csv", std::process::id()))
+        };
+        let balances = |available: &str, held: &str, total: &str| Balances {
+            available: dec(available),
+            held: dec(held),
+            total: dec(total),
+        };
+
+        let matching = path("expected-match");
+        std::fs::write(
+            &matching,
+            "client,available,held,total,locked,currency\n\
+             1,7.5,0,7.5,false,\n\
+             2,0,5.00000,5,false,\n\
+             3,1,0,1,false,EUR\n",
+        )
+        .unwrap();
+        let mismatches = penguin(parsed(&inputs), 2)
+            .verify_against(&matching)
+            .await
+            .expect("run should succeed");
+        std::fs::remove_file(&matching).unwrap();
+        assert!(mismatches.is_empty(), "{mismatches:?}");
+
+        let mismatching = path("expected-mismatch");
+        std::fs::write(
+            &mismatching,
+            "client,available,held,total\n\
+             1,7.5,0,7.5\n\
+             2,5,0,5\n\
+             4,1,0,1\n",
+        )
+        .unwrap();
+        let mismatches = penguin(parsed(&inputs), 2)
+            .verify_against(&mismatching)
+            .await
+            .expect("run should succeed");
+        std::fs::remove_file(&mismatching).unwrap();
+        assert_eq!(
+            mismatches,
+            [
+                Mismatch {
+                    client: 2,
+                    currency: None,
+                    expected: Some(balances("5", "0", "5")),
+                    actual: Some(balances("0", "5", "5")),
+                    expected_locked: None,
+                    actual_locked: Some(false),
+                },
+                Mismatch {
+                    client: 3,
+                    currency: Some("EUR".into()),
+                    expected: None,
+                    actual: Some(balances("1", "0", "1")),
+                    expected_locked: None,
+                    actual_locked: Some(false),
+                },
+                Mismatch {
+                    client: 4,
+                    currency: None,
+                    expected: Some(balances("1", "0", "1")),
+                    actual: None,
+                    expected_locked: None,
+                    actual_locked: None,
+                },
+            ]
+        );
+
+        // Right balances, wrong lock.
+        let locked = path("expected-locked");
+        std::fs::write(
+            &locked,
+            "client,available,held,total,locked,currency\n\
+             1,7.5,0,7.5,true,\n\
+             2,0,5,5,false,\n\
+             3,1,0,1,false,EUR\n",
+        )
+        .unwrap();
+        let mismatches = penguin(parsed(&inputs), 2)
+            .verify_against(&locked)
+            .await
+            .expect("run should succeed");
+        std::fs::remove_file(&locked).unwrap();
+        assert_eq!(
+            mismatches,
+            [Mismatch {
+                client: 1,
+                currency: None,
+                expected: Some(balances("7.5", "0", "7.5")),
+                actual: Some(balances("7.5", "0", "7.5")),
+                expected_locked: Some(true),
+                actual_locked: Some(false),
+            }]
+        );
+    }
+
+    /// Sink failing its first writes, like a database briefly unreachable.
+    struct FlakySink {
+        failures: u32,
+        written: Vec<u16>,
+    }
+
+    impl StateSink for FlakySink {
+        fn write_state(&mut self, state: &ClientState) -> Result<(), PenguinError> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(PenguinError::Sink("connection reset".into()));
+            }
+            self.written.push(state.client);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn run_to_sink_retries_transient_failures() {
+        let inputs = [
+            "deposit, 2, 1, 1.0",
+            "deposit, 1, 2, 1.0",
+            "deposit, 3, 3, 1.0",
+        ];
+        let run = |retries| async move {
+            let mut sink = FlakySink {
+                failures: 2,
+                written: Vec::new(),
+            };
+            let result = Penguin {
+                output_order: OutputOrder::ClientAsc,
+                ..penguin(parsed(&inputs), 2)
+            }
+            .run_to_sink(&mut sink, retries, Duration::from_millis(1))
+            .await;
+            (result, sink.written)
+        };
+
+        let (result, written) = run(2).await;
+        result.expect("retries cover the failures");
+        assert_eq!(written, [1, 2, 3]);
+
+        let (result, written) = run(1).await;
+        let err = result.expect_err("retries run out");
+        assert!(matches!(
+            err,
+            PenguinError::SinkWrite { client: 1, ref source }
+                if matches!(**source, PenguinError::Sink(_))
+        ));
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn oversized_worker_counts_are_rejected() {
+        let workers = usize::from(u16::MAX) + 1;
+        let result = PenguinBuilder::from_reader(parsed(&[]))
+            .with_num_workers(NonZero::new(workers).unwrap())
+            .build();
+
+        assert!(matches!(result, Err(PenguinError::TooManyWorkers(n)) if n == workers));
+        assert!(
+            PenguinBuilder::from_reader(parsed(&[]))
+                .with_num_workers(NonZero::new(usize::from(u16::MAX)).unwrap())
+                .build()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn preflight_reports_the_modulo_sharding() {
+        let inputs = [
+            "deposit, 1, 1, 1.0",
+            "deposit, 2, 2, 1.0",
+            "deposit, 3, 3, 1.0",
+            "withdrawal, 1, 4, 0.5",
+            "deposit, 5, 5, 1.0",
+            "deposit, 4, 6, 1.0",
+            "dispute, 3, 3,",
+        ];
+        // Unlike `parsed`, a lazily parsing iterator can be cloned.
+        let reader = inputs.iter().map(|line| line.parse::<Transaction>());
+        let builder =
+            PenguinBuilder::from_reader(reader).with_num_workers(NonZero::new(2).unwrap());
+
+        assert_eq!(
+            builder.preflight(usize::MAX),
+            Preflight {
+                rows: 7,
+                clients_per_worker: vec![2, 3],
+                transactions_per_worker: vec![2, 5],
+            }
+        );
+        assert_eq!(
+            builder.preflight(3),
+            Preflight {
+                rows: 3,
+                clients_per_worker: vec![1, 2],
+                transactions_per_worker: vec![1, 2],
+            }
+        );
+        // The builder still reads the input from the start.
+        assert_eq!(builder.reader.count(), inputs.len());
+    }
+
+    #[tokio::test]
+    async fn presharded_inputs_go_to_one_worker_each() {
+        // Clients 1 and 3 share a shard, which the modulo router would never do with 2 workers.
+        let first = parsed(&[
+            "deposit, 1, 1, 10.0",
+            "deposit, 3, 2, 4.0",
+            "withdrawal, 1, 3, 2.5",
+        ]);
+        let second = parsed(&["deposit, 2, 4, 5.0", "dispute, 2, 4,"]);
+
+        let mut engine = penguin(parsed(&[]), 1);
+        let mut states = engine
+            .run_presharded(vec![first, second])
+            .await
+            .expect("presharded run should succeed");
+        states.sort_by_key(|state| state.client);
+        let summary: Vec<_> = states
+            .into_iter()
+            .map(|state| (state.client, state.available, state.held))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (1, dec("7.5"), dec("0")),
+                (2, dec("0"), dec("5.0")),
+                (3, dec("4.0"), dec("0")),
+            ]
+        );
+        assert_eq!(engine.run_stats().map(|stats| stats.rows_read), Some(5));
+
+        let err = penguin(parsed(&[]), 2)
+            .run_presharded(vec![
+                parsed(&["deposit, 1, 1, 10.0"]),
+                parsed(&["deposit, 2, 2, 5.0", "withdrawal, 1, 3, 1.0"]),
+            ])
+            .await
+            .expect_err("expected a client split across shards to fail the run");
+        assert!(matches!(err, PenguinError::InvariantViolation(1, _)));
+    }
+
+    #[tokio::test]
+    async fn unknown_types_abort_strict_runs_and_are_skipped_otherwise() {
+        let inputs = [
+            "deposit, 1, 1, 5.0",
+            "transfer, 1, 2, 0.01",
+            "withdrawal, 1, 3, 1.0",
+        ];
+        let read = |format: TransactionFormat| {
+            inputs
+                .iter()
+                .map(move |line| format.parse(line))
+                .collect::<Vec<_>>()
+                .into_iter()
+        };
+
+        let err = penguin(read(TransactionFormat::default()), 1)
+            .run()
+            .await
+            .expect_err("expected the unknown type to abort the run");
+        assert!(
+            matches!(
+                &err,
+                PenguinError::InvalidRow { line: 2, source }
+                    if matches!(**source, PenguinError::TransactionParse(_))
+            ),
+            "{err}"
+        );
+
+        let lenient = TransactionFormat::default().with_unknown_types_skipped(true);
+        let report = penguin(read(lenient), 1)
+            .validate()
+            .await
+            .expect("validation should succeed");
+        assert_eq!(report.ignored, [(2, IgnoreReason::UnknownType)]);
+
+        let output = penguin(read(lenient), 1)
+            .run()
+            .await
+            .expect("run should succeed");
+        assert_state(&output[0], 1, dec("4.0"), dec("0"), dec("4.0"));
+    }
+
+    #[tokio::test]
+    async fn report_path_receives_run_stats_as_json() {
+        let path = std::env::temp_dir().join(format!("penguin-report-{}.json", std::process::id()));
+        let inputs = [
+            "deposit, 1, 1, 5.0",
+            "deposit, 2, 2, 3.0",
+            "withdrawal, 1, 3, 9.0",
+            "dispute, 2, 2,",
+            "chargeback, 2, 2,",
+            "deposit, 2, 4, 1.0",
+            "dispute, 3, 9,",
+        ];
+        let mut penguin = Penguin {
+            report_path: Some(path.clone()),
+            input_name: Some("fixture.csv".into()),
+            ..penguin(parsed(&inputs), 2)
+        };
+
+        penguin.run().await.expect("run should succeed");
+
+        let report: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).expect("report was written"))
+                .expect("valid JSON");
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(report["input"], "fixture.csv");
+        assert_eq!(report["rows_read"], 7);
+        // The overdraft, the deposit on the locked account and the unknown dispute.
+        assert_eq!(report["rows_skipped"], 3);
+        assert_eq!(
+            report["transactions"],
+            serde_json::json!({
+                "deposit": 3,
+                "withdrawal": 1,
+                "dispute": 2,
+                "chargeback": 1,
+            })
+        );
+        assert_eq!(report["distinct_clients"], 3);
+        assert_eq!(report["locked_accounts"], 1);
+        assert!(report["duration_secs"].as_f64().is_some());
+
+        let stats = penguin.run_stats().expect("stats of the run");
+        assert_eq!(stats.rows_read, 7);
+        assert_eq!(stats.transactions[&TransactionType::Deposit], 3);
+    }
+
+    #[tokio::test]
+    async fn batched_feed_matches_unbatched_output() {
+        let inputs = [
+            "deposit, 1, 1, 10.0",
+            "deposit, 2, 2, 5.0",
+            "withdrawal, 1, 3, 2.5",
+            "dispute, 2, 2,",
+            "deposit, 3, 4, 1.0",
+            "withdrawal, 2, 5, 1.0",
+            "resolve, 2, 2,",
+            "withdrawal, 2, 6, 1.0",
+            "dispute, 3, 4,",
+            "chargeback, 3, 4,",
+            "deposit, 3, 7, 2.0",
+            "deposit, 4, 8, 0.5",
+            "dispute, 1, 1,",
+        ];
+        let summary = |states: HashMap<u16, ClientState>| {
+            let mut states: Vec<_> = states
+                .into_values()
+                .map(|state| {
+                    (
+                        state.client,
+                        state.available,
+                        state.held,
+                        state.total,
+                        state.locked,
+                        state.last_tx,
+                    )
+                })
+                .collect();
+            states.sort_by_key(|state| state.0);
+            states
+        };
+
+        let unbatched = summary(
+            penguin(parsed(&inputs), 2)
+                .run_to_map()
+                .await
+                .expect("run should succeed"),
+        );
+
+        for batch_size in [2, 3, 64] {
+            let batched = Penguin {
+                batch_size,
+                ..penguin(parsed(&inputs), 2)
+            };
+            let batched = summary(batched.run_to_map().await.expect("run should succeed"));
+            assert_eq!(batched, unbatched, "batch size {batch_size}");
+        }
+        assert_eq!(unbatched.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn run_to_map_keys_states_by_client() {
+        let penguin = penguin(
+            parsed(&[
+                "deposit, 1, 1, 1.0",
+                "deposit, 2, 2, 2.0",
+                "deposit, 3, 3, 3.0",
+                "withdrawal, 1, 4, 0.5",
+                "deposit, 2, 5, 1.0",
+            ]),
+            2,
+        );
+
+        let output = penguin.run_to_map().await.expect("run should succeed");
+
+        assert_eq!(output.len(), 3);
+        assert_state(&output[&1], 1, dec("0.5"), dec("0"), dec("0.5"));
+        assert_state(&output[&2], 2, dec("3.0"), dec("0"), dec("3.0"));
+        assert_state(&output[&3], 3, dec("3.0"), dec("0"), dec("3.0"));
+    }
+
+    #[tokio::test]
+    async fn run_cancellable_returns_partial_states() {
+        let token = CancellationToken::new();
+        let reader = parsed(&[
+            "deposit, 1, 1, 1.0",
+            "deposit, 2, 2, 1.0",
+            "deposit, 1, 3, 1.0",
+            "deposit, 2, 4, 1.0",
+            "deposit, 1, 5, 1.0",
+            "deposit, 2, 6, 1.0",
+            "deposit, 1, 7, 1.0",
+            "deposit, 2, 8, 1.0",
+            "deposit, 1, 9, 1.0",
+            "deposit, 3, 10, 1.0",
+        ])
+        .enumerate()
+        .map({
+            let token = token.clone();
+            move |(index, tx)| {
+                if index == 3 {
+                    token.cancel();
+                }
+                tx
+            }
+        });
+        let mut penguin = penguin(reader, 2);
+
+        let mut output = penguin
+            .run_cancellable(token)
+            .await
+            .expect("cancelled run should succeed");
+        output.sort_by_key(|state| state.client);
+
+        assert_eq!(output.len(), 2);
+        assert_state(&output[0], 1, dec("2.0"), dec("0"), dec("2.0"));
+        assert_state(&output[1], 2, dec("1.0"), dec("0"), dec("1.0"));
+    }
+
+    #[tokio::test]
+    async fn run_returns_parse_error_with_line_number() {
+        let reader = vec![
+            Ok(tx(TransactionType::Deposit, 1, 1, Some(dec("1.0")))),
+            "deposit, 1".parse::<Transaction>(),
+        ]
+        .into_iter();
+        let err = penguin(reader, 1)
+            .run()
+            .await
+            .expect_err("expected parse error");
+        assert!(
+            matches!(err, PenguinError::FieldCount { line: 2, .. }),
+            "{err}"
+        );
+
+        // Errors that don't name a line themselves get it from the run.
+        let reader = vec![
+            Ok(tx(TransactionType::Deposit, 1, 1, Some(dec("1.0")))),
+            Err(PenguinError::TransactionParse(Cow::Borrowed("bad row"))),
+        ];
+        let err = penguin(reader.into_iter(), 1)
+            .run()
+            .await
+            .expect_err("expected parse error");
+        assert!(
+            matches!(
+                &err,
+                PenguinError::InvalidRow { line: 2, source }
+                    if matches!(&**source, PenguinError::TransactionParse(msg) if msg == "bad row")
+            ),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_blob_round_trip_resumes_processing() {
+        let mut first = penguin(
+            parsed(&[
+                "deposit, 1, 1, 10.0",
+                "deposit, 2, 2, 5.0",
+                "withdrawal, 1, 3, 2.5",
+                "deposit, 3, 4, 1.0",
+                "dispute, 3, 4,",
+            ]),
+            2,
+        );
+        first.run().await.expect("first run should succeed");
+        let blob = first.snapshot_blob().expect("snapshot should encode");
+
+        let builder = PenguinBuilder::from_snapshot_blob(
+            parsed(&["dispute, 1, 1,", "deposit, 2, 5, 1.0", "chargeback, 3, 4,"]),
+            &blob,
+        )
+        .expect("valid snapshot");
+        let mut resumed = Penguin {
+            ledger: builder.ledger,
+            ..penguin(builder.reader, 3)
+        };
+
+        let mut output = resumed.run().await.expect("resumed run should succeed");
+        output.sort_by_key(|state| state.client);
+
+        assert_eq!(output.len(), 3);
+        assert_state(&output[0], 1, dec("-2.5"), dec("10.0"), dec("7.5"));
+        assert_eq!(output[0].disputes_seen, 1);
+        assert_state(&output[1], 2, dec("6.0"), dec("0"), dec("6.0"));
+        assert_state(&output[2], 3, dec("0"), dec("0"), dec("0"));
+        assert!(output[2].locked);
+    }
+
+    #[tokio::test]
+    async fn processed_ids_make_rerunning_the_same_input_a_no_op() {
+        let path =
+            std::env::temp_dir().join(format!("penguin-idempotent-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let inputs = [
+            "deposit, 1, 1, 10.0",
+            "deposit, 2, 2, 5.0",
+            "withdrawal, 1, 3, 2.5",
+            "dispute, 2, 2,",
+            "deposit, 1, 4, 1.0",
+            "dispute, 1, 4,",
+            "resolve, 2, 2,",
+        ];
+        let idempotent = |ledger: Ledger| Penguin {
+            processed_file: Some(path.clone()),
+            worker_config: WorkerConfig {
+                idempotent: true,
+                ..WorkerConfig::default()
+            },
+            ledger,
+            ..penguin(parsed(&inputs), 2)
+        };
+        let summary = |mut states: Vec<ClientState>| {
+            states.sort_by_key(|state| state.client);
+            states
+                .into_iter()
+                .map(|state| (state.client, state.available, state.held, state.total))
+                .collect::<Vec<_>>()
+        };
+
+        let mut first = idempotent(Ledger::default());
+        let expected = summary(first.run().await.expect("first run should succeed"));
+        let blob = first.snapshot_blob().expect("snapshot should encode");
+
+        // Restart: balances come back from the snapshot, processed ids from the file.
+        let mut ledger = snapshot::decode(&blob).expect("valid snapshot");
+        ledger.processed = processed::load(&path).expect("processed ids were saved");
+        let mut second = idempotent(ledger);
+        let output = summary(second.run().await.expect("second run should succeed"));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            expected,
+            [
+                (1, dec("7.5"), dec("1.0"), dec("8.5")),
+                (2, dec("5.0"), dec("0"), dec("5.0")),
+            ]
+        );
+        assert_eq!(output, expected);
+    }
+
+    #[tokio::test]
+    async fn processed_ids_leave_ignored_transactions_out() {
+        let path = std::env::temp_dir().join(format!(
+            "penguin-idempotent-ignored-{}.txt",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let inputs = ["withdrawal, 1, 1, 5.0", "deposit, 1, 2, 10.0"];
+        let idempotent = |ledger: Ledger| Penguin {
+            processed_file: Some(path.clone()),
+            worker_config: WorkerConfig {
+                idempotent: true,
+                ..WorkerConfig::default()
+            },
+            ledger,
+            ..penguin(parsed(&inputs), 1)
+        };
+
+        let mut first = idempotent(Ledger::default());
+        first.run().await.expect("first run should succeed");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1,2\n");
+
+        // The withdrawal wasn't covered the first time, so it's still applied.
+        let mut second = idempotent(mem::take(&mut first.ledger));
+        let output = second.run().await.expect("second run should succeed");
+        std::fs::remove_file(&path).unwrap();
+
+        assert_state(&output[0], 1, dec("5.0"), dec("0"), dec("5.0"));
+    }
+
+    /// Two deposits of the largest decimal overflow the balance, panicking the worker.
+    fn overflowing_deposits() -> std::vec::IntoIter<TxResult<PenguinError>> {
+        vec![
+            Ok(tx(TransactionType::Deposit, 2, 1, Some(dec("1.0")))),
+            Ok(tx(TransactionType::Deposit, 1, 2, Some(Decimal::MAX))),
+            Ok(tx(TransactionType::Deposit, 1, 3, Some(Decimal::MAX))),
+        ]
+        .into_iter()
+    }
+
+    #[tokio::test]
+    async fn worker_panics_fail_the_run_when_requested() {
+        let mut penguin = Penguin {
+            fail_on_worker_panic: true,
+            ..penguin(overflowing_deposits(), 2)
+        };
+
+        let err = penguin
+            .run()
+            .await
+            .expect_err("worker panic should surface");
+        assert!(matches!(err, PenguinError::WorkerPanicked(_)));
+    }
+
+    #[tokio::test]
+    async fn worker_panics_are_only_logged_by_default() {
+        let mut penguin = penguin(overflowing_deposits(), 2);
+
+        let output = penguin.run().await.expect("run should succeed");
+        // The panicking worker's clients are lost, the others survive.
+        assert_eq!(output.len(), 1);
+        assert_state(&output[0], 2, dec("1.0"), dec("0"), dec("1.0"));
+    }
+
+    #[tokio::test]
+    async fn runs_keep_the_other_clients_when_a_worker_dies_mid_feed() {
+        // Worker 1 panics on its second deposit, long before client 1's input ends.
+        let mut inputs: Vec<_> = overflowing_deposits().flatten().collect();
+        inputs.extend((4..100).map(|id| tx(TransactionType::Deposit, 1, id, Some(dec("1.0")))));
+        inputs.push(tx(TransactionType::Deposit, 2, 100, Some(dec("2.0"))));
+        let build = |skip: bool| {
+            PenguinBuilder::from_transactions(inputs.clone())
+                .with_num_workers(NonZero::new(2).unwrap())
+                .with_channel_capacity(NonZero::new(1).unwrap())
+                .with_skip_closed_workers(skip)
+                .build()
+                .expect("valid builder")
+        };
+
+        let err = build(false)
+            .run()
+            .await
+            .expect_err("the closed channel fails the run");
+        assert!(matches!(
+            err,
+            PenguinError::WorkerChannelClosed {
+                worker: 1,
+                client: 1
+            }
+        ));
+
+        let mut skipping = build(true);
+        let output = skipping.run().await.expect("run should succeed");
+        assert_eq!(output.len(), 1);
+        assert_state(&output[0], 2, dec("3.0"), dec("0"), dec("3.0"));
+        let stats = skipping.run_stats().expect("stats of the run");
+        assert!(stats.rows_undelivered > 0);
+    }
+
+    #[test]
+    fn snapshot_blob_rejects_unknown_versions() {
+        let mut blob = penguin(parsed(&[]), 1)
+            .snapshot_blob()
+            .expect("snapshot should encode");
+        blob[4] += 1;
+
+        let err = PenguinBuilder::from_snapshot_blob(parsed(&[]), &blob)
+            .err()
+            .expect("expected version mismatch");
+        assert!(matches!(err, PenguinError::Snapshot(_)));
+
+        let err = PenguinBuilder::from_snapshot_blob(parsed(&[]), &blob[..3])
+            .err()
+            .expect("expected truncated snapshot");
+        assert!(matches!(err, PenguinError::Snapshot(_)));
+    }
+
+    #[tokio::test]
+    async fn snapshot_blob_rejects_currencies_too_long_to_encode() {
+        let currency = "X".repeat(usize::from(u16::MAX) + 1);
+        let deposit = Transaction::deposit(1, 1, dec("1.0")).with_currency(currency);
+        let mut penguin = penguin([Ok::<_, PenguinError>(deposit)].into_iter(), 1);
+        penguin.run().await.expect("run should succeed");
+
+        let err = penguin
+            .snapshot_blob()
+            .expect_err("the currency doesn't fit a u16 length");
+        assert!(matches!(err, PenguinError::Snapshot(_)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn run_only_feeds_filtered_transaction_types() {
+        let inputs = [
+            "deposit, 1, 1, 5.0",
+            "deposit, 1, 2, 3.0",
+            "withdrawal, 1, 3, 4.0",
+            "dispute, 1, 1,",
+            "dispute, 1, 2,",
+            "resolve, 1, 2,",
+            "chargeback, 1, 1,",
+        ];
+        let reader = inputs.into_iter().map(|line| {
+            Ok::<Transaction, PenguinError>(line.parse::<Transaction>().expect("valid transaction"))
+        });
+        let mut penguin = Penguin {
+            type_filter: Some(HashSet::from([
+                TransactionType::Deposit,
+                TransactionType::Dispute,
+                TransactionType::Resolve,
+            ])),
+            ..penguin(reader, 1)
+        };
+
+        let output = penguin.run().await.expect("run should succeed");
+
+        assert_eq!(output.len(), 1);
+        assert!(!output[0].locked);
+        assert_state(&output[0], 1, dec("3.0"), dec("5.0"), dec("8.0"));
+    }
+
+    #[tokio::test]
+    async fn worker_warnings_include_input_line_number() {
+        let logs = LogBuffer::default();
+        let _guard = logs.capture(tracing::Level::INFO);
+
+        let (sender, rx) = mpsc::channel(4);
+        sender
+            .send(vec![(
+                3,
+                tx(TransactionType::Deposit, 1, 1, Some(dec("1.0"))),
+            )])
+            .await
+            .expect("worker channel open");
+        sender
+            .send(vec![(
+                7,
+                tx(TransactionType::Withdrawal, 1, 2, Some(dec("2.0"))),
+            )])
+            .await
+            .expect("worker channel open");
+        drop(sender);
+
+        let (_, result) = spawn_worker(rx, WorkerConfig::default(), Ledger::default()).await;
+        result.expect("balances stay consistent");
+
+        let logs = logs.contents();
+        assert!(logs.contains("insufficient funds for withdrawal"));
+        assert!(logs.contains("line=7"));
+        assert!(!logs.contains("line=3"));
+    }
+
+    fn corrupted_ledger() -> Ledger {
+        let mut state = ClientState::new(1);
+        state.available = dec("1.0");
+        state.total = dec("5.0");
+        let mut ledger = Ledger::default();
+        ledger.client_states.insert(1, state);
+        ledger
+    }
+
+    #[tokio::test]
+    async fn strict_invariants_abort_the_run_on_diverging_totals() {
+        let mut penguin = Penguin {
+            worker_config: WorkerConfig {
+                strict_invariants: true,
+                ..WorkerConfig::default()
+            },
+            ledger: corrupted_ledger(),
+            ..penguin(
+                parsed(&[
+                    "deposit, 2, 1, 1.0",
+                    "deposit, 1, 2, 1.0",
+                    "deposit, 1, 3, 1.0",
+                ]),
+                1,
+            )
+        };
+
+        let err = penguin.run().await.expect_err("invariant should fire");
+        assert!(matches!(err, PenguinError::InvariantViolation(1, _)));
+        // The worker stopped at the first violation but its shard was kept.
+        assert_state(
+            &penguin.ledger.client_states[&1],
+            1,
+            dec("2.0"),
+            dec("0"),
+            dec("6.0"),
+        );
+    }
+
+    #[tokio::test]
+    async fn invariant_violations_are_logged_without_strict_mode() {
+        let logs = LogBuffer::default();
+        let _guard = logs.capture(tracing::Level::ERROR);
+
+        let mut penguin = Penguin {
+            ledger: corrupted_ledger(),
+            ..penguin(parsed(&["deposit, 1, 2, 1.0", "deposit, 1, 3, 1.0"]), 1)
+        };
+
+        let output = penguin.run().await.expect("run should succeed");
+        assert_state(&output[0], 1, dec("3.0"), dec("0"), dec("7.0"));
+        assert!(logs.contents().contains("ledger invariant violated"));
+    }
+
+    #[test]
+    fn minor_units_match_the_dotted_form() {
+        assert_eq!(
+            from_minor_units(dec("5050"), 2, AMOUNT_DECIMALS),
+            Ok(dec("50.50"))
+        );
+        assert_eq!(
+            from_minor_units(dec("5050.0"), 2, AMOUNT_DECIMALS),
+            Ok(dec("50.50"))
+        );
+        assert_eq!(
+            from_minor_units(dec("-7"), 3, AMOUNT_DECIMALS),
+            Ok(dec("-0.007"))
+        );
+        assert_eq!(
+            from_minor_units(dec("12"), 0, AMOUNT_DECIMALS),
+            Ok(dec("12"))
+        );
+        assert_eq!(
+            from_minor_units(dec("123450"), 5, AMOUNT_DECIMALS),
+            Ok(dec("1.2345"))
+        );
+
+        assert!(from_minor_units(dec("123456"), 5, AMOUNT_DECIMALS).is_err());
+        assert!(from_minor_units(dec("50.5"), 2, AMOUNT_DECIMALS).is_err());
+        assert!(from_minor_units(dec("1"), 29, AMOUNT_DECIMALS).is_err());
+
+        // One satoshi needs the amount scale raised to 8.
+        assert!(from_minor_units(dec("1"), 8, AMOUNT_DECIMALS).is_err());
+        assert_eq!(from_minor_units(dec("1"), 8, 8), Ok(dec("0.00000001")));
+    }
+
+    #[tokio::test]
+    async fn run_reads_amounts_as_minor_units() {
+        let mut penguin = Penguin {
+            minor_units: Some(2),
+            ..penguin(
+                parsed(&[
+                    "deposit, 1, 1, 5050",
+                    "withdrawal, 1, 2, 50",
+                    "dispute, 1, 1,",
+                ]),
+                1,
+            )
+        };
+
+        let output = penguin.run().await.expect("run should succeed");
+        assert_state(&output[0], 1, dec("-0.50"), dec("50.50"), dec("50.00"));
+    }
+
+    #[tokio::test]
+    async fn output_order_follows_the_configured_order() {
+        let input = [
+            "deposit, 3, 1, 1.0",
+            "deposit, 1, 2, 1.0",
+            "deposit, 2, 3, 1.0",
+            "deposit, 3, 4, 1.0",
+        ];
+
+        for (order, expected) in [
+            (OutputOrder::FirstSeen, [3, 1, 2]),
+            (OutputOrder::ClientAsc, [1, 2, 3]),
+        ] {
+            let mut penguin = Penguin {
+                output_order: order,
+                ..penguin(parsed(&input), 2)
+            };
+            let output = penguin.run().await.expect("run should succeed");
+
+            let clients: Vec<_> = output.iter().map(|state| state.client).collect();
+            assert_eq!(clients, expected, "{order:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn zero_balance_clients_can_be_left_out() {
+        let input = [
+            "deposit, 1, 1, 2.0",
+            "withdrawal, 1, 2, 2.0",
+            "deposit, 2, 3, 1.0",
+            "deposit, 3, 4, 1.0",
+            "dispute, 3, 4,",
+            "chargeback, 3, 4,",
+        ];
+
+        for (emit_zero_balances, expected) in [(true, vec![1, 2, 3]), (false, vec![2, 3])] {
+            let mut penguin = Penguin {
+                emit_zero_balances,
+                output_order: OutputOrder::ClientAsc,
+                ..penguin(parsed(&input), 2)
+            };
+            let output = penguin.run().await.expect("run should succeed");
+
+            let clients: Vec<_> = output.iter().map(|state| state.client).collect();
+            assert_eq!(
+                clients, expected,
+                "emit_zero_balances: {emit_zero_balances}"
+            );
+            assert!(output.iter().any(|state| state.client == 3 && state.locked));
+        }
+    }
+
+    #[tokio::test]
+    async fn one_engine_processes_files_one_after_the_other() {
+        let mut first = Penguin {
+            output_order: OutputOrder::ClientAsc,
+            ..penguin(parsed(&["deposit, 1, 1, 1.0", "deposit, 2, 2, 2.0"]), 2)
+        };
+        first.run().await.expect("first run should succeed");
+
+        let mut second =
+            first.with_new_reader(parsed(&["deposit, 3, 1, 3.0", "deposit, 1, 2, 0.5"]));
+        let output = second.run().await.expect("second run should succeed");
+        assert_eq!(output.len(), 2);
+        assert_state(&output[0], 1, dec("0.5"), dec("0"), dec("0.5"));
+        assert_state(&output[1], 3, dec("3.0"), dec("0"), dec("3.0"));
+
+        let mut resumed = second.resume_with_reader(parsed(&["withdrawal, 3, 3, 1.0"]));
+        let output = resumed.run().await.expect("resumed run should succeed");
+        assert_eq!(output.len(), 2);
+        assert_state(&output[0], 1, dec("0.5"), dec("0"), dec("0.5"));
+        assert_state(&output[1], 3, dec("2.0"), dec("0"), dec("2.0"));
+    }
+
+    #[tokio::test]
+    async fn client_details_break_held_funds_down_by_dispute() {
+        let inputs = [
+            "deposit, 1, 1, 10.0",
+            "deposit, 1, 2, 4.5",
+            "deposit, 1, 3, 1.0",
+            "dispute, 1, 1,",
+            "dispute, 1, 2,",
+            "deposit, 2, 4, 3.0",
+        ];
+        let mut penguin = Penguin {
+            output_order: OutputOrder::ClientAsc,
+            ..penguin(parsed(&inputs), 2)
+        };
+        penguin.run().await.expect("run should succeed");
+
+        let details = penguin.client_details();
+        assert_eq!(details.len(), 2);
+        let disputed = &details[0];
+        assert_eq!(disputed.state.held, dec("14.5"));
+        assert_eq!(
+            disputed.held_breakdown,
+            HashMap::from([(1, dec("10.0")), (2, dec("4.5"))])
+        );
+        assert_eq!(
+            disputed.held_breakdown.values().sum::<Decimal>(),
+            disputed.state.held
+        );
+        assert!(details[1].held_breakdown.is_empty());
+    }
+
+    #[tokio::test]
+    async fn strict_precision_rejects_too_precise_amounts() {
+        for strict_precision in [false, true] {
+            let transactions = [
+                Transaction::deposit(1, 1, dec("1.0")),
+                Transaction::deposit(1, 2, dec("1.23456")),
+            ];
+            let mut penguin = Penguin {
+                strict_precision,
+                ..penguin(transactions.map(Ok::<_, PenguinError>).into_iter(), 1)
+            };
+
+            let result = penguin.run().await;
+            if strict_precision {
+                let err = result.expect_err("too precise amount");
+                assert!(
+                    matches!(
+                        &err,
+                        PenguinError::InvalidRow { line: 2, source }
+                            if matches!(**source, PenguinError::TransactionParse(_))
+                    ),
+                    "{err}"
                 );
+            } else {
+                let output = result.expect("lenient run should succeed");
+                assert_eq!(format_amount(output[0].available), "2.2346");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_precision_follows_the_configured_amount_decimals() {
+        let input = "type,client,tx,amount\ndeposit,1,1,0.000000000000000001\n";
+        let build = |decimals: u32| {
+            PenguinBuilder::from_csv_reader(
+                csv::ReaderBuilder::new()
+                    .trim(csv::Trim::All)
+                    .from_reader(input.as_bytes()),
+            )
+            .with_strict_precision(true)
+            .with_amount_decimals(decimals)
+            .build()
+            .expect("valid config")
+        };
+
+        let err = build(4)
+            .run()
+            .await
+            .expect_err("18 decimals with 4 allowed");
+        assert!(
+            matches!(err, PenguinError::InvalidRow { line: 1, .. }),
+            "{err}"
+        );
+
+        let output = build(18).run().await.expect("18 decimals with 18 allowed");
+        assert_eq!(output[0].available(), dec("0.000000000000000001"));
+    }
+
+    #[tokio::test]
+    async fn signed_amounts_flip_deposits_and_withdrawals() {
+        let mut penguin = Penguin {
+            signed_amounts: true,
+            ..penguin(
+                parsed(&[
+                    "deposit, 1, 1, 10",
+                    "deposit, 1, 2, -5",
+                    "withdrawal, 1, 3, -2.5",
+                    "dispute, 1, 3,",
+                ]),
+                1,
+            )
+        };
+
+        let output = penguin.run().await.expect("run should succeed");
+        assert_state(&output[0], 1, dec("5.0"), dec("2.5"), dec("7.5"));
+
+        let stats = penguin.run_stats().expect("run completed");
+        assert_eq!(stats.transactions[&TransactionType::Deposit], 2);
+        assert_eq!(stats.transactions[&TransactionType::Withdrawal], 1);
+    }
+
+    #[tokio::test]
+    async fn run_rejects_fractional_minor_units() {
+        let mut penguin = Penguin {
+            minor_units: Some(2),
+            ..penguin(parsed(&["deposit, 1, 1, 50.50"]), 1)
+        };
+
+        let err = penguin.run().await.expect_err("fractional minor units");
+        assert!(
+            matches!(err, PenguinError::InvalidRow { line: 1, .. }),
+            "{err}"
+        );
+
+        let mut scaled = Penguin {
+            minor_units: Some(5),
+            ..self::penguin(parsed(&["deposit, 1, 1, 100", "deposit, 1, 2, 123456"]), 1)
+        };
+
+        let err = scaled.run().await.expect_err("more than 4 decimal places");
+        assert!(
+            matches!(
+                &err,
+                PenguinError::InvalidRow { line: 2, source }
+                    if matches!(**source, PenguinError::TransactionParse(_))
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn rate_limiter_resets_after_the_window() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            max_per_window: 2,
+            window: Duration::from_secs(1),
+        });
+        let start = Instant::now();
+
+        assert!(limiter.allow(1, start));
+        assert!(limiter.allow(1, start));
+        assert!(!limiter.allow(1, start + Duration::from_millis(999)));
+        assert!(limiter.allow(2, start));
+        assert!(limiter.allow(1, start + Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn failures_dump_the_last_transactions_of_the_worker() {
+        let logs = LogBuffer::default();
+        let _guard = logs.capture(tracing::Level::ERROR);
+        let path = std::env::temp_dir().join(format!("penguin-replay-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let config = WorkerConfig {
+            replay: Some(ReplayConfig {
+                capacity: 2,
+                path: Some(path.clone()),
+            }),
+            ..WorkerConfig::default()
+        };
+        let (sender, rx) = mpsc::channel(16);
+        for (line, transaction) in [
+            tx(TransactionType::Deposit, 1, 1, Some(dec("10.0"))),
+            tx(TransactionType::Deposit, 2, 2, Some(dec("5.0"))),
+            tx(TransactionType::Withdrawal, 1, 3, Some(dec("2.5"))),
+            tx(TransactionType::Deposit, 1, 4, None),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            sender
+                .send(vec![(line + 1, transaction)])
+                .await
+                .expect("worker channel open");
+        }
+        drop(sender);
+
+        let (_, result) = spawn_worker(rx, config, Ledger::default()).await;
+        result.expect("failed transactions don't stop the worker");
+        let dump = std::fs::read_to_string(&path).expect("dump was written");
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            dump,
+            "line 4: Client 1 received a deposit/withdrawal transaction with no amount \
+             associated.\n\
+             3: withdrawal, 1, 3, 2.5\n\
+             4: deposit, 1, 4, \n"
+        );
+        assert!(
+            logs.contents()
+                .contains("last transactions before the failure")
+        );
+    }
+
+    #[tokio::test]
+    async fn workers_ignore_unknown_types_instead_of_rejecting_them() {
+        let (ignored, mut reasons) = mpsc::unbounded_channel();
+        let config = WorkerConfig {
+            ignored: Some(ignored),
+            ..WorkerConfig::default()
+        };
+        let (sender, rx) = mpsc::channel(16);
+        sender
+            .send(vec![
+                (1, tx(TransactionType::Deposit, 1, 1, Some(dec("1.0")))),
+                (2, tx(TransactionType::Unknown, 1, 2, Some(dec("1.0")))),
+            ])
+            .await
+            .expect("worker channel open");
+        drop(sender);
+
+        let (ledger, result) = spawn_worker(rx, config, Ledger::default()).await;
+        result.expect("worker should succeed");
+
+        assert_eq!(reasons.recv().await, Some((2, IgnoreReason::UnknownType)));
+        assert_state(
+            &ledger.client_states[&1],
+            1,
+            dec("1.0"),
+            dec("0"),
+            dec("1.0"),
+        );
+    }
+
+    #[tokio::test]
+    async fn clients_over_the_rate_limit_have_transactions_dropped() {
+        let logs = LogBuffer::default();
+        let _guard = logs.capture(tracing::Level::WARN);
+
+        let config = WorkerConfig {
+            rate_limit: Some(RateLimit {
+                max_per_window: 2,
+                window: Duration::from_secs(3600),
+            }),
+            ..WorkerConfig::default()
+        };
+        let (sender, rx) = mpsc::channel(16);
+        for (line, transaction) in [
+            tx(TransactionType::Deposit, 1, 1, Some(dec("1.0"))),
+            tx(TransactionType::Deposit, 1, 2, Some(dec("2.0"))),
+            tx(TransactionType::Deposit, 2, 3, Some(dec("5.0"))),
+            tx(TransactionType::Deposit, 1, 4, Some(dec("4.0"))),
+            tx(TransactionType::Dispute, 1, 1, None),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            sender
+                .send(vec![(line + 1, transaction)])
+                .await
+                .expect("worker channel open");
+        }
+        drop(sender);
+
+        let (ledger, _) = spawn_worker(rx, config, Ledger::default()).await;
+
+        assert_state(
+            &ledger.client_states[&1],
+            1,
+            dec("3.0"),
+            dec("0"),
+            dec("3.0"),
+        );
+        assert_state(
+            &ledger.client_states[&2],
+            2,
+            dec("5.0"),
+            dec("0"),
+            dec("5.0"),
+        );
+        // The dropped deposit was never registered for disputes.
+        assert!(!ledger.client_tx_registry.contains_key(&(1, 4)));
+
+        let logs = logs.contents();
+        assert!(logs.contains("client over its rate limit, dropping transaction"));
+        assert!(logs.contains("line=4"));
+        assert!(logs.contains("line=5"));
+    }
+
+    #[tokio::test]
+    async fn queue_depths_rise_while_a_worker_is_paused_and_fall_as_it_drains() {
+        let mut penguin = penguin(
+            parsed(&[
+                "deposit, 1, 1, 1.0",
+                "deposit, 1, 2, 1.0",
+                "deposit, 1, 3, 1.0",
+            ]),
+            1,
+        );
+        let monitor = penguin.queue_monitor();
+        assert_eq!(monitor.queue_depths(), [0]);
+
+        // Nothing receives yet, as if the worker were paused.
+        let (sender, rx) = mpsc::channel(16);
+        let senders = Map::from_iter([(0, sender)]);
+        penguin
+            .feed(&senders, None, None, &mut RunStats::default())
+            .await
+            .expect("feed should succeed");
+        drop(senders);
+        assert_eq!(monitor.queue_depths(), [3]);
+
+        let config = WorkerConfig {
+            queue: Some((monitor.clone(), 0)),
+            ..WorkerConfig::default()
+        };
+        let (ledger, result) = spawn_worker(rx, config, Ledger::default()).await;
+        result.expect("worker should succeed");
+
+        assert_eq!(ledger.client_states[&1].available, dec("3.0"));
+        assert_eq!(monitor.queue_depths(), [0]);
+    }
+
+    #[tokio::test]
+    async fn heartbeats_fire_during_a_slow_feed_and_stop_with_the_run() {
+        let logs = LogBuffer::default();
+        let _guard = logs.capture(tracing::Level::INFO);
+        let heartbeats = || logs.contents().matches("heartbeat").count();
+        let mut penguin = Penguin {
+            heartbeat: Some(Duration::from_millis(5)),
+            ..penguin(parsed(&[]), 2)
+        };
+
+        let slow =
+            futures_util::stream::iter(parsed(&["deposit, 1, 1, 1.0", "deposit, 2, 2, 1.0"])).then(
+                |tx| async move {
+                    time::sleep(Duration::from_millis(30)).await;
+                    tx
+                },
+            );
+        let output = penguin.run_stream(slow).await.expect("run should succeed");
+        assert_eq!(output.len(), 2);
+
+        let fired = heartbeats();
+        assert!(fired >= 1, "no heartbeat in {}", logs.contents());
+        assert!(logs.contents().contains("queue_depths=[0, 0]"));
+
+        time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(heartbeats(), fired);
+    }
+
+    #[tokio::test]
+    async fn closed_worker_channels_name_the_worker_and_client() {
+        let mut penguin = penguin(parsed(&["deposit, 2, 1, 1.0", "deposit, 3, 2, 1.0"]), 2);
+        let (open, _open_rx) = mpsc::channel(16);
+        let (closed, closed_rx) = mpsc::channel(16);
+        drop(closed_rx);
+        let senders = Map::from_iter([(0, open), (1, closed)]);
+
+        let err = penguin
+            .feed(&senders, None, None, &mut RunStats::default())
+            .await
+            .expect_err("worker 1 is gone");
+
+        assert!(matches!(
+            err,
+            PenguinError::WorkerChannelClosed {
+                worker: 1,
+                client: 3
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn closed_worker_channels_only_drop_that_workers_clients() {
+        let mut penguin = Penguin {
+            skip_closed_workers: true,
+            ..penguin(
+                parsed(&[
+                    "deposit, 2, 1, 1.0",
+                    "deposit, 3, 2, 1.0",
+                    "deposit, 4, 3, 2.0",
+                    "deposit, 5, 4, 1.0",
+                ]),
+                2,
+            )
+        };
+        let (open, open_rx) = mpsc::channel(16);
+        let (closed, closed_rx) = mpsc::channel(16);
+        // Worker 1 is gone before its first transaction, worker 0 already got client 2.
+        drop(closed_rx);
+        let senders = Map::from_iter([(0, open), (1, closed)]);
+
+        let mut stats = RunStats::default();
+        let fed = penguin.feed(&senders, None, None, &mut stats).await;
+        drop(senders);
+        fed.expect("feed should go on without worker 1");
+
+        let (ledger, result) =
+            spawn_worker(open_rx, WorkerConfig::default(), Ledger::default()).await;
+        result.expect("worker 0 should succeed");
+        let mut clients: Vec<_> = ledger.client_states.into_keys().collect();
+        clients.sort_unstable();
+        assert_eq!(clients, [2, 4]);
+        assert_eq!(stats.rows_undelivered, 2);
+    }
+
+    #[test]
+    fn builder_only_installs_a_logger_when_asked_to() {
+        let default = PenguinBuilder::from_transactions(Vec::<Transaction>::new())
+            .build()
+            .expect("valid builder");
+        assert!(default._logger.is_none());
+
+        let disabled = PenguinBuilder::from_transactions(Vec::<Transaction>::new())
+            .with_logger("penguin.log")
+            .with_stderr_logs(true)
+            .without_logger()
+            .build()
+            .expect("valid builder");
+        assert!(disabled._logger.is_none());
+    }
+
+    #[tokio::test]
+    async fn from_bufread_skips_the_header_and_parses_lines() {
+        let input = io::Cursor::new(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10.0\n\
+             deposit, 2, 2, 4.0\n\
+             \n\
+             withdrawal, 1, 3, 2.5\n\
+             dispute, 2, 2,\n",
+        );
+
+        let mut penguin = PenguinBuilder::from_bufread(input)
+            .without_log_file()
+            .build()
+            .expect("valid builder");
+        let mut output = penguin.run().await.expect("run should succeed");
+        output.sort_by_key(|state| state.client);
+
+        assert_eq!(output.len(), 2);
+        assert_state(&output[0], 1, dec("7.5"), dec("0"), dec("7.5"));
+        assert_state(&output[1], 2, dec("0"), dec("4.0"), dec("4.0"));
+    }
+
+    #[tokio::test]
+    async fn evicted_clients_keep_their_balances_when_they_reappear() {
+        let evicted = Arc::new(Mutex::new(HashMap::<u16, ClientState>::new()));
+        let config = WorkerConfig {
+            client_limit: Some(ClientLimit {
+                max_clients: 2,
+                evicted: Arc::clone(&evicted) as Arc<dyn EvictedStates>,
+            }),
+            ..WorkerConfig::default()
+        };
+        let (sender, rx) = mpsc::channel(16);
+        for (line, transaction) in [
+            tx(TransactionType::Deposit, 1, 1, Some(dec("10.0"))),
+            tx(TransactionType::Deposit, 2, 2, Some(dec("5.0"))),
+            // Evicts client 1.
+            tx(TransactionType::Deposit, 3, 3, Some(dec("7.0"))),
+            // Restores client 1 and evicts client 2.
+            tx(TransactionType::Withdrawal, 1, 4, Some(dec("3.0"))),
+            // Evicts client 3.
+            tx(TransactionType::Deposit, 4, 5, Some(dec("1.0"))),
+            // Restores client 2, whose deposit is still disputable, and evicts client 1.
+            tx(TransactionType::Dispute, 2, 2, None),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            sender
+                .send(vec![(line + 1, transaction)])
+                .await
+                .expect("worker channel open");
+        }
+        drop(sender);
+
+        let (ledger, result) = spawn_worker(rx, config, Ledger::default()).await;
+        result.expect("worker should succeed");
+
+        let mut held: Vec<_> = ledger.client_states.keys().copied().collect();
+        held.sort_unstable();
+        assert_eq!(held, [2, 4]);
+        assert_state(
+            &ledger.client_states[&2],
+            2,
+            dec("0"),
+            dec("5.0"),
+            dec("5.0"),
+        );
+
+        let evicted = evicted.lock().expect("evicted states lock");
+        let mut evicted_clients: Vec<_> = evicted.keys().copied().collect();
+        evicted_clients.sort_unstable();
+        assert_eq!(evicted_clients, [1, 3]);
+        assert_state(&evicted[&1], 1, dec("7.0"), dec("0"), dec("7.0"));
+        assert_state(&evicted[&3], 3, dec("7.0"), dec("0"), dec("7.0"));
+    }
+
+    #[tokio::test]
+    async fn full_worker_channels_are_retried_and_logged() {
+        let logs = LogBuffer::default();
+        let _guard = logs.capture(tracing::Level::WARN);
+
+        // The worker only runs once the feed yields, so a one-slot channel fills at once.
+        let mut penguin = Penguin {
+            channel_capacity: 1,
+            backpressure: Some(Backpressure {
+                retries: 3,
+                backoff: Duration::from_millis(1),
+            }),
+            ..penguin(
+                parsed(&[
+                    "deposit, 1, 1, 1.0",
+                    "deposit, 1, 2, 2.0",
+                    "deposit, 1, 3, 3.0",
+                    "withdrawal, 1, 4, 0.5",
+                ]),
+                1,
+            )
+        };
+
+        let output = penguin.run().await.expect("run should succeed");
+        assert_state(&output[0], 1, dec("5.5"), dec("0"), dec("5.5"));
+
+        let logs = logs.contents();
+        assert!(logs.contains("worker channel full, backing off"));
+        assert!(logs.contains("worker=0"));
+    }
+
+    #[tokio::test]
+    async fn validate_reports_problems_without_changing_state() {
+        let mut reader: Vec<_> = parsed(&[
+            "deposit, 1, 1, 1.0",
+            "dispute, 1, 7,",
+            "withdrawal, 1, 2, 5.0",
+            "dispute, 1, 1,",
+        ])
+        .collect();
+        reader.insert(
+            1,
+            Err(PenguinError::TransactionParse(Cow::Borrowed("bad line"))),
+        );
+        let mut penguin = penguin(reader.into_iter(), 2);
+
+        let report = penguin.validate().await.expect("validation should succeed");
+
+        assert_eq!(report.parse_errors, [2]);
+        assert_eq!(
+            report.ignored,
+            [
+                (3, IgnoreReason::UnknownTx),
+                (4, IgnoreReason::InsufficientFunds)
+            ]
+        );
+        assert_eq!(report.unknown_references().collect::<Vec<_>>(), [3]);
+        assert!(!report.is_clean());
+        assert!(penguin.ledger.client_states.is_empty());
+        assert!(penguin.ledger.client_tx_registry.is_empty());
+    }
+
+    #[tokio::test]
+    async fn run_logs_the_input_line_of_a_bad_dispute() {
+        let logs = LogBuffer::default();
+        let _guard = logs.capture(tracing::Level::WARN);
+
+        let mut penguin = penguin(
+            parsed(&[
+                "deposit, 1, 1, 1.0",
+                "deposit, 2, 2, 1.0",
+                "dispute, 2, 9,",
+                "dispute, 1, 1,",
+            ]),
+            2,
+        );
+        penguin.run().await.expect("run should succeed");
+
+        let logs = logs.contents();
+        let warning = logs
+            .lines()
+            .find(|line| line.contains("dispute for unknown transaction"))
+            .expect("bad dispute is logged");
+        assert!(warning.contains("line=3"), "unexpected warning: {warning}");
+        assert!(
+            warning.contains("client=2"),
+            "unexpected warning: {warning}"
+        );
+        assert!(warning.contains("tx=9"), "unexpected warning: {warning}");
+    }
+
+    #[tokio::test]
+    async fn disputes_of_another_clients_deposit_are_reported_as_such() {
+        let logs = LogBuffer::default();
+        let _guard = logs.capture(tracing::Level::WARN);
+        let mut penguin = PenguinBuilder::from_reader(parsed(&[
+            "deposit, 1, 1, 1.0",
+            "deposit, 2, 2, 1.0",
+            "dispute, 2, 1,",
+            "dispute, 2, 9,",
+        ]))
+        .with_cross_client_index(true)
+        .build()
+        .expect("valid builder");
+
+        let report = penguin.validate().await.expect("validation should succeed");
+
+        assert_eq!(
+            report.ignored,
+            [
+                (3, IgnoreReason::CrossClientDispute),
+                (4, IgnoreReason::UnknownTx)
+            ]
+        );
+        let logs = logs.contents();
+        let warning = logs
+            .lines()
+            .find(|line| line.contains("dispute for another client's transaction"))
+            .expect("warning is logged");
+        assert!(warning.contains("owner=1"), "unexpected warning: {warning}");
+        assert!(logs.contains("dispute for unknown transaction"));
+        let owners = penguin.worker_config.deposit_owners.as_ref().unwrap();
+        assert!(owners.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn apply_tx_warnings_carry_the_transaction() {
+        let withdraw = |level| {
+            let logs = LogBuffer::default();
+            let _guard = logs.capture(level);
+            apply_tx(
+                &mut ClientState::new(1),
+                7,
+                &tx(TransactionType::Withdrawal, 1, 2, Some(dec("5.0"))),
+                &mut Map::default(),
+                &WorkerConfig::default(),
+            )
+            .expect("withdrawal is ignored");
+
+            logs.contents()
+                .lines()
+                .find(|line| line.contains("insufficient funds for withdrawal"))
+                .expect("warning is logged")
+                .to_owned()
+        };
+
+        let warning = withdraw(tracing::Level::WARN);
+        assert!(
+            warning.contains("line=7 client=1 tx=2"),
+            "unexpected warning: {warning}"
+        );
+        assert!(!warning.contains("tx{"), "unexpected span: {warning}");
+
+        // The span only comes with debug logs.
+        let warning = withdraw(tracing::Level::DEBUG);
+        assert!(
+            warning.contains("tx{line=7 client=1 tx=2 tx_type=withdrawal}"),
+            "unexpected warning: {warning}"
+        );
+    }
+
+    #[tokio::test]
+    async fn traces_record_the_worker_handling_each_client() {
+        let logs = LogBuffer::default();
+        let _guard = logs.capture(tracing::Level::TRACE);
+
+        let mut penguin = penguin(parsed(&["deposit, 2, 1, 1.0", "withdrawal, 3, 2, 5.0"]), 2);
+        penguin.run().await.expect("run should succeed");
+
+        let logs = logs.contents();
+        assert!(logs.contains("client=2 worker=0"));
+        assert!(logs.contains("client=3 worker=1"));
+        // Worker events carry the span of the worker that emitted them.
+        assert!(logs.contains("worker{group_id=1}"));
+        assert!(logs.contains("insufficient funds for withdrawal"));
+    }
+
+    #[test]
+    fn merge_and_finalize_sorts_disjoint_partials() {
+        let mut client_1 = ClientState::new(1);
+        client_1.available = dec("1.5");
+        client_1.total = dec("1.5");
+        let mut client_2 = ClientState::new(2);
+        client_2.available = dec("2");
+        client_2.total = dec("2");
+        let mut client_3 = ClientState::new(3);
+        client_3.held = dec("3");
+        client_3.total = dec("3");
+
+        let output = Penguin::merge_and_finalize(vec![vec![client_3, client_1], vec![client_2]])
+            .expect("merge should succeed");
+
+        assert_eq!(output.len(), 3);
+        assert_state(&output[0], 1, dec("1.5"), dec("0"), dec("1.5"));
+        assert_state(&output[1], 2, dec("2"), dec("0"), dec("2"));
+        assert_state(&output[2], 3, dec("0"), dec("3"), dec("3"));
+    }
+
+    #[test]
+    fn merge_and_finalize_sums_duplicated_clients() {
+        let mut first = ClientState::new(1);
+        first.available = dec("1");
+        first.total = dec("1");
+        let mut second = ClientState::new(1);
+        second.available = dec("2");
+        second.held = dec("1");
+        second.total = dec("3");
+
+        let output = Penguin::merge_and_finalize(vec![vec![first], vec![second]])
+            .expect("merge should succeed");
+
+        assert_eq!(output.len(), 1);
+        assert_state(&output[0], 1, dec("3"), dec("1"), dec("4"));
+    }
+
+    #[test]
+    fn merge_and_finalize_detects_conflicting_locks() {
+        let mut locked = ClientState::new(1);
+        locked.locked = true;
+        let active = ClientState::new(1);
+
+        let err = Penguin::merge_and_finalize(vec![vec![locked], vec![active]])
+            .expect_err("expected conflicting locks to error");
+
+        assert!(matches!(err, PenguinError::MergeConflict(1)));
+    }
+
+    #[test]
+    fn deposit_and_withdrawal_update_balances() {
+        let mut client_state = ClientState::new(1);
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Deposit, 1, 1, Some(dec("1.0"))),
+            &mut registry,
+            &WorkerConfig::default(),
+        )
+        .expect("deposit should succeed");
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Withdrawal, 1, 2, Some(dec("0.4"))),
+            &mut registry,
+            &WorkerConfig::default(),
+        )
+        .expect("withdrawal should succeed");
+
+        assert_state(&client_state, 1, dec("0.6"), dec("0"), dec("0.6"));
+    }
+
+    #[test]
+    fn withdrawal_with_insufficient_funds_is_ignored() {
+        let mut client_state = ClientState::new(1);
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Deposit, 1, 1, Some(dec("1.0"))),
+            &mut registry,
+            &WorkerConfig::default(),
+        )
+        .expect("deposit should succeed");
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Withdrawal, 1, 2, Some(dec("2.0"))),
+            &mut registry,
+            &WorkerConfig::default(),
+        )
+        .expect("withdrawal is ignored when insufficient");
+
+        assert_state(&client_state, 1, dec("1.0"), dec("0"), dec("1.0"));
+    }
+
+    #[test]
+    fn last_tx_tracks_the_last_applied_transaction() {
+        let mut client_state = ClientState::new(1);
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
+        assert_eq!(client_state.last_tx, None);
+
+        for (tx_type, id, amount) in [
+            (TransactionType::Deposit, 1, Some(dec("1.0"))),
+            (TransactionType::Deposit, 2, Some(dec("2.0"))),
+            // Ignored: insufficient funds and unknown transaction.
+            (TransactionType::Withdrawal, 3, Some(dec("5.0"))),
+            (TransactionType::Dispute, 9, None),
+        ] {
+            apply_tx(
+                &mut client_state,
+                LINE,
+                &tx(tx_type, 1, id, amount),
+                &mut registry,
+                &WorkerConfig::default(),
+            )
+            .expect("transaction is applied or ignored");
+        }
+
+        assert_eq!(client_state.last_tx, Some(2));
+
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.serialize(&client_state).expect("serialize state");
+        let output = String::from_utf8(writer.into_inner().expect("flush")).expect("utf8");
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,disputes_seen,last_tx\n\
+             1,3,0,3,false,0,2\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn created_by_tx_is_the_first_transaction_seen() {
+        let mut output = penguin(
+            parsed(&[
+                // Ignored for insufficient funds, but the account exists from here on.
+                "withdrawal, 1, 5, 1.0",
+                "deposit, 1, 6, 2.0",
+                "deposit, 2, 7, 1.0",
+                "deposit, 2, 8, 1.0",
+                // Ignored as unknown.
+                "dispute, 3, 1,",
+            ]),
+            2,
+        )
+        .run()
+        .await
+        .expect("run should succeed");
+        output.sort_by_key(|state| state.client);
+
+        let ids: Vec<_> = output
+            .iter()
+            .map(|state| (state.client, state.created_by_tx, state.last_tx))
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                (1, Some(5), Some(6)),
+                (2, Some(7), Some(8)),
+                (3, Some(1), None)
+            ]
+        );
+    }
+
+    #[test]
+    fn withdrawal_fee_is_debited_with_the_withdrawal() {
+        let mut client_state = ClientState::new(1);
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
+        let config = WorkerConfig {
+            withdrawal_fee: Some(dec("0.1")),
+            ..WorkerConfig::default()
+        };
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Deposit, 1, 1, Some(dec("1.0"))),
+            &mut registry,
+            &config,
+        )
+        .expect("deposit should succeed");
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Withdrawal, 1, 2, Some(dec("0.5"))),
+            &mut registry,
+            &config,
+        )
+        .expect("withdrawal should succeed");
+
+        assert_state(&client_state, 1, dec("0.4"), dec("0"), dec("0.4"));
+    }
+
+    #[test]
+    fn withdrawal_fee_can_make_a_withdrawal_unaffordable() {
+        let mut client_state = ClientState::new(1);
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
+        let config = WorkerConfig {
+            withdrawal_fee: Some(dec("0.1")),
+            ..WorkerConfig::default()
+        };
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Deposit, 1, 1, Some(dec("1.0"))),
+            &mut registry,
+            &config,
+        )
+        .expect("deposit should succeed");
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Withdrawal, 1, 2, Some(dec("0.95"))),
+            &mut registry,
+            &config,
+        )
+        .expect("withdrawal is ignored when the fee can't be covered");
+
+        assert_state(&client_state, 1, dec("1.0"), dec("0"), dec("1.0"));
+    }
+
+    #[test]
+    fn negative_withdrawal_fees_are_rejected() {
+        let result = PenguinBuilder::from_reader(parsed(&[]))
+            .with_withdrawal_fee(dec("-0.1"))
+            .build();
+
+        assert!(matches!(result, Err(PenguinError::NegativeFee(fee)) if fee == dec("-0.1")));
+        assert!(
+            PenguinBuilder::from_reader(parsed(&[]))
+                .with_withdrawal_fee(Decimal::ZERO)
+                .build()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn dispute_and_resolve_move_funds_between_available_and_held() {
+        let mut client_state = ClientState::new(1);
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Deposit, 1, 1, Some(dec("1.0"))),
+            &mut registry,
+            &WorkerConfig::default(),
+        )
+        .expect("deposit should succeed");
+
+        registry.insert((1, 1), Deposit::new(dec("1.0")));
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Dispute, 1, 1, None),
+            &mut registry,
+            &WorkerConfig::default(),
+        )
+        .expect("dispute should succeed");
+        assert_state(&client_state, 1, dec("0"), dec("1.0"), dec("1.0"));
+        assert_eq!(registry.len(), 1);
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Resolve, 1, 1, None),
+            &mut registry,
+            &WorkerConfig::default(),
+        )
+        .expect("resolve should succeed");
+
+        assert_state(&client_state, 1, dec("1.0"), dec("0"), dec("1.0"));
+        // The settled deposit is kept to tell later references to it apart.
+        assert_eq!(registry.len(), 1);
+        assert_eq!(registry[&(1, 1)].settled, Some(Settlement::Resolved));
+    }
+
+    #[test]
+    fn partial_disputes_and_resolves_sum_back_to_the_deposit() {
+        let mut client_state = ClientState::new(1);
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Deposit, 1, 1, Some(dec("10.0"))),
+            &mut registry,
+            &WorkerConfig::default(),
+        )
+        .expect("deposit should succeed");
+        registry.insert((1, 1), Deposit::new(dec("10.0")));
+
+        for (tx_type, amount) in [
+            (TransactionType::Dispute, Some("4.0")),
+            (TransactionType::Dispute, None),
+            (TransactionType::Resolve, Some("3.0")),
+        ] {
+            apply_tx(
+                &mut client_state,
+                LINE,
+                &tx(tx_type, 1, 1, amount.map(dec)),
+                &mut registry,
+                &WorkerConfig::default(),
+            )
+            .expect("dispute flow should succeed");
+        }
+
+        // 4 then the remaining 6 were disputed, and 3 of it released again.
+        assert_state(&client_state, 1, dec("3.0"), dec("7.0"), dec("10.0"));
+        assert_eq!(registry[&(1, 1)].disputable, dec("0"));
+        assert_eq!(registry[&(1, 1)].held, dec("7.0"));
+
+        // Nothing is left to dispute, and only 7 can be resolved.
+        for (tx_type, amount) in [
+            (TransactionType::Dispute, Some("1.0")),
+            (TransactionType::Resolve, Some("8.0")),
+        ] {
+            let ignored = apply_tx(
+                &mut client_state,
+                LINE,
+                &tx(tx_type, 1, 1, amount.map(dec)),
+                &mut registry,
+                &WorkerConfig::default(),
+            )
+            .expect("excessive amounts are ignored, not an error");
+            assert_eq!(ignored, Some(IgnoreReason::AmountUnavailable));
+        }
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Resolve, 1, 1, Some(dec("7.0"))),
+            &mut registry,
+            &WorkerConfig::default(),
+        )
+        .expect("resolve should succeed");
+
+        assert_state(&client_state, 1, dec("10.0"), dec("0"), dec("10.0"));
+        assert_eq!(registry[&(1, 1)].settled, Some(Settlement::Resolved));
+        assert_eq!(client_state.disputes_seen, 2);
+    }
+
+    #[test]
+    fn partially_resolved_deposit_can_be_disputed_again() {
+        let mut client_state = ClientState::new(1);
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Deposit, 1, 1, Some(dec("10.0"))),
+            &mut registry,
+            &WorkerConfig::default(),
+        )
+        .expect("deposit should succeed");
+        registry.insert((1, 1), Deposit::new(dec("10.0")));
 
-                return Ok(());
-            }
-            client_state.available -= amount;
-            client_state.total -= amount;
+        for (tx_type, amount) in [
+            (TransactionType::Dispute, Some("4.0")),
+            (TransactionType::Resolve, None),
+            (TransactionType::Dispute, None),
+            (TransactionType::Chargeback, None),
+        ] {
+            apply_tx(
+                &mut client_state,
+                LINE,
+                &tx(tx_type, 1, 1, amount.map(dec)),
+                &mut registry,
+                &WorkerConfig::default(),
+            )
+            .expect("dispute flow should succeed");
         }
-        TType::Dispute => {
-            let Some(tx_amount) = client_tx_registry.get(&(tx.client, tx.tx)) else {
-                warn!(
-                    client = tx.client,
-                    tx = tx.tx,
-                    "dispute for unknown transaction"
-                );
 
-                return Ok(());
+        // The resolved 4 stay; the rest was disputed and charged back.
+        assert!(client_state.locked);
+        assert_state(&client_state, 1, dec("4.0"), dec("0"), dec("4.0"));
+        assert_eq!(registry[&(1, 1)].settled, Some(Settlement::ChargedBack));
+    }
+
+    #[test]
+    fn resolved_deposits_can_be_disputed_again_only_when_allowed() {
+        for allow_redispute in [false, true] {
+            let config = WorkerConfig {
+                allow_redispute,
+                ..WorkerConfig::default()
             };
+            let mut client_state = ClientState::new(1);
+            let mut registry: Map<ClientTx, Deposit> = Map::default();
+            registry.insert((1, 1), Deposit::new(dec("1.0")));
+
+            for tx_type in [
+                TransactionType::Deposit,
+                TransactionType::Dispute,
+                TransactionType::Resolve,
+            ] {
+                let amount = (tx_type == TransactionType::Deposit).then(|| dec("1.0"));
+                apply_tx(
+                    &mut client_state,
+                    LINE,
+                    &tx(tx_type, 1, 1, amount),
+                    &mut registry,
+                    &config,
+                )
+                .expect("dispute flow should succeed");
+            }
+
+            let redispute = apply_tx(
+                &mut client_state,
+                LINE,
+                &tx(TransactionType::Dispute, 1, 1, None),
+                &mut registry,
+                &config,
+            )
+            .expect("disputes are never an error");
 
-            client_state.held += *tx_amount;
-            client_state.available -= *tx_amount;
+            if allow_redispute {
+                assert_eq!(redispute, None);
+                assert_state(&client_state, 1, dec("0"), dec("1.0"), dec("1.0"));
+                assert_eq!(client_state.disputes_seen, 2);
+            } else {
+                assert_eq!(redispute, Some(IgnoreReason::ResolvedTx));
+                assert_state(&client_state, 1, dec("1.0"), dec("0"), dec("1.0"));
+            }
         }
-        TType::Resolve => {
-            let Some(tx_amount) = client_tx_registry.get(&(tx.client, tx.tx)) else {
-                warn!(
-                    client = tx.client,
-                    tx = tx.tx,
-                    "resolve for unknown transaction"
-                );
+    }
 
-                return Ok(());
-            };
+    #[tokio::test]
+    async fn interest_credits_funds_but_cannot_be_disputed() {
+        let mut penguin = penguin(
+            parsed(&[
+                "deposit, 1, 1, 10.0",
+                "interest, 1, 2, 0.25",
+                "dispute, 1, 2,",
+                "withdrawal, 1, 3, 10.25",
+            ]),
+            1,
+        );
 
-            client_state.held -= *tx_amount;
-            client_state.available += *tx_amount;
+        let output = penguin.run().await.expect("run should succeed");
 
-            client_tx_registry.remove(&(tx.client, tx.tx));
-        }
-        TType::Chargeback => {
-            let Some(tx_amount) = client_tx_registry.get(&(tx.client, tx.tx)) else {
-                warn!(
-                    client = tx.client,
-                    tx = tx.tx,
-                    "chargeback for unknown transaction"
-                );
+        assert_state(&output[0], 1, dec("0"), dec("0"), dec("0"));
+        assert!(!penguin.ledger.client_tx_registry.contains_key(&(1, 2)));
+        let stats = penguin.run_stats().expect("run completed");
+        assert_eq!(stats.transactions[&TransactionType::Interest], 1);
+        assert_eq!(stats.rows_skipped, 1);
+    }
 
-                return Ok(());
+    #[test]
+    fn settled_deposits_are_told_apart_from_unknown_ones() {
+        for strict_disputes in [false, true] {
+            let config = WorkerConfig {
+                strict_disputes,
+                ..WorkerConfig::default()
             };
+            let mut client_state = ClientState::new(1);
+            let mut registry: Map<ClientTx, Deposit> = Map::default();
+            registry.insert((1, 1), Deposit::new(dec("1.0")));
+            registry.insert((1, 2), Deposit::new(dec("2.0")));
 
-            client_state.held -= *tx_amount;
-            client_state.total -= *tx_amount;
-            client_state.locked = true;
+            for (tx_type, id) in [
+                (TransactionType::Deposit, 1),
+                (TransactionType::Deposit, 2),
+                (TransactionType::Dispute, 1),
+                (TransactionType::Resolve, 1),
+                (TransactionType::Dispute, 2),
+                (TransactionType::Chargeback, 2),
+            ] {
+                let amount = (tx_type == TransactionType::Deposit).then(|| Decimal::from(id));
+                let ignored = apply_tx(
+                    &mut client_state,
+                    LINE,
+                    &tx(tx_type, 1, id, amount),
+                    &mut registry,
+                    &config,
+                )
+                .expect("dispute flow should succeed");
+                assert_eq!(ignored, None);
+            }
+
+            let apply =
+                |tx_type, id, registry: &mut Map<ClientTx, Deposit>, state: &mut ClientState| {
+                    apply_tx(state, LINE, &tx(tx_type, 1, id, None), registry, &config)
+                };
+            let resolved = apply(
+                TransactionType::Dispute,
+                1,
+                &mut registry,
+                &mut client_state,
+            );
+            assert_eq!(
+                resolved.expect("resolved tx is ignored"),
+                Some(IgnoreReason::ResolvedTx)
+            );
+            let unknown = apply(
+                TransactionType::Dispute,
+                9,
+                &mut registry,
+                &mut client_state,
+            );
+            assert_eq!(
+                unknown.expect("locked clients ignore unknown txs"),
+                Some(IgnoreReason::LockedClient)
+            );
+            let resolve = apply(
+                TransactionType::Resolve,
+                2,
+                &mut registry,
+                &mut client_state,
+            );
+            assert_eq!(
+                resolve.expect("resolve is never strict"),
+                Some(IgnoreReason::ChargedBackTx)
+            );
 
-            client_tx_registry.remove(&(tx.client, tx.tx));
+            let charged_back = apply(
+                TransactionType::Dispute,
+                2,
+                &mut registry,
+                &mut client_state,
+            );
+            if strict_disputes {
+                assert!(matches!(
+                    charged_back,
+                    Err(PenguinError::DisputeAfterChargeback { client: 1, tx: 2 })
+                ));
+            } else {
+                assert_eq!(
+                    charged_back.expect("charged back tx is ignored"),
+                    Some(IgnoreReason::ChargedBackTx)
+                );
+            }
+            assert!(client_state.locked);
+            assert_state(&client_state, 1, dec("1.0"), dec("0"), dec("1.0"));
         }
     }
 
-    Ok(())
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::collections::HashMap;
-    use std::str::FromStr;
+    #[tokio::test]
+    async fn strict_disputes_stop_the_run_on_a_charged_back_dispute() {
+        let mut penguin = Penguin {
+            worker_config: WorkerConfig {
+                strict_disputes: true,
+                ..WorkerConfig::default()
+            },
+            ..penguin(
+                parsed(&[
+                    "deposit, 1, 1, 1.0",
+                    "dispute, 1, 1,",
+                    "chargeback, 1, 1,",
+                    "dispute, 1, 1,",
+                ]),
+                1,
+            )
+        };
 
-    fn dec(value: &str) -> Decimal {
-        Decimal::from_str(value).expect("valid decimal")
+        let err = penguin.run().await.expect_err("charged back tx disputed");
+        assert!(matches!(
+            err,
+            PenguinError::DisputeAfterChargeback { client: 1, tx: 1 }
+        ));
     }
 
-    fn tx(tx_type: TransactionType, client: u16, tx: u32, amount: Option<Decimal>) -> Transaction {
-        Transaction {
-            tx_type,
-            client,
-            tx,
-            amount,
-        }
+    #[tokio::test]
+    async fn unlock_can_reopen_charged_back_deposits() {
+        let inputs = [
+            "deposit, 1, 1, 10.0",
+            "deposit, 1, 2, 5.0",
+            "dispute, 1, 1,",
+            "chargeback, 1, 1,",
+            "unlock, 1, 3,",
+            // The chargeback is being reversed: credit the funds back first.
+            "deposit, 1, 4, 10.0",
+            "dispute, 1, 1,",
+        ];
+        let run = |restore| {
+            let mut engine = Penguin {
+                worker_config: WorkerConfig {
+                    restore_disputes_on_unlock: restore,
+                    ..WorkerConfig::default()
+                },
+                ..penguin(parsed(&inputs), 1)
+            };
+            async move { engine.run().await.expect("run should succeed") }
+        };
+
+        let restored = run(true).await;
+        assert!(!restored[0].locked);
+        assert_state(&restored[0], 1, dec("5"), dec("10"), dec("15"));
+
+        // Without the policy the deposit stays charged back and the dispute is ignored.
+        let settled = run(false).await;
+        assert!(!settled[0].locked);
+        assert_state(&settled[0], 1, dec("15"), dec("0"), dec("15"));
     }
 
-    fn assert_state(
-        state: &ClientState,
-        client: u16,
-        available: Decimal,
-        held: Decimal,
-        total: Decimal,
-    ) {
-        assert_eq!(state.client, client);
-        assert_eq!(state.available, available);
-        assert_eq!(state.held, held);
-        assert_eq!(state.total, total);
+    #[test]
+    fn unlock_of_an_open_account_is_ignored() {
+        let mut client_state = ClientState::new(1);
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
+
+        let ignored = apply_tx(
+            &mut client_state,
+            LINE,
+            &Transaction::unlock(1, 1),
+            &mut registry,
+            &WorkerConfig::default(),
+        )
+        .expect("unlock is ignored");
+
+        assert_eq!(ignored, Some(IgnoreReason::NotLocked));
+        assert_eq!(client_state.last_tx, None);
     }
 
     #[tokio::test]
-    async fn run_multiple_clients_with_mixed_transactions() {
+    async fn pending_withdrawals_wait_for_a_covering_deposit() {
         let inputs = [
-            "deposit, 1, 1, 1.0",
-            "deposit, 2, 2, 2.0",
+            "withdrawal, 1, 1, 3.0",
+            "withdrawal, 1, 2, 1.0",
             "deposit, 1, 3, 2.0",
-            "withdrawal, 1, 4, 1.5",
-            "withdrawal, 2, 5, 3.0",
-            "deposit, 1, 5,",
+            "deposit, 1, 4, 2.0",
+            "withdrawal, 2, 5, 1.0",
         ];
-        let reader = inputs.into_iter().map(|line| {
-            Ok::<Transaction, PenguinError>(line.parse::<Transaction>().expect("valid transaction"))
-        });
-        let mut penguin = Penguin {
-            reader,
-            num_workers: 2,
-            _logger: None,
+        let mut parking = Penguin {
+            worker_config: WorkerConfig {
+                pending_withdrawals: true,
+                ..WorkerConfig::default()
+            },
+            ..penguin(parsed(&inputs), 2)
         };
 
-        let mut output = penguin.run().await.expect("run should succeed");
+        let mut output = parking.run().await.expect("run should succeed");
         output.sort_by_key(|state| state.client);
 
-        assert_eq!(output.len(), 2);
-        assert_state(&output[0], 1, dec("1.5"), dec("0"), dec("1.5"));
-        assert_state(&output[1], 2, dec("2"), dec("0"), dec("2"));
+        // 3.0 is only covered after the second deposit, then 1.0 goes through in order.
+        assert_state(&output[0], 1, dec("0"), dec("0"), dec("0"));
+        // Never covered, so dropped at the end of the run.
+        assert_state(&output[1], 2, dec("0"), dec("0"), dec("0"));
+        assert_eq!(parking.worker_config.skipped.load(Ordering::Relaxed), 1);
+
+        let mut output = penguin(parsed(&inputs), 2)
+            .run()
+            .await
+            .expect("run should succeed");
+        output.sort_by_key(|state| state.client);
+        // Without parking both withdrawals are simply ignored.
+        assert_state(&output[0], 1, dec("4"), dec("0"), dec("4"));
     }
 
     #[tokio::test]
-    async fn run_returns_parse_error_with_line_number() {
-        let reader = vec![
-            Ok(tx(TransactionType::Deposit, 1, 1, Some(dec("1.0")))),
-            Err(()),
-        ]
-        .into_iter();
-        let mut penguin = Penguin {
-            reader,
-            num_workers: 1,
-            _logger: None,
+    async fn pending_withdrawals_are_retried_after_interest_and_resolves() {
+        let mut parking = Penguin {
+            worker_config: WorkerConfig {
+                pending_withdrawals: true,
+                ..WorkerConfig::default()
+            },
+            ..penguin(
+                parsed(&[
+                    "deposit, 1, 1, 2.0",
+                    "dispute, 1, 1,",
+                    "withdrawal, 1, 2, 1.0",
+                    "resolve, 1, 1,",
+                    "withdrawal, 1, 3, 1.5",
+                    "interest, 1, 4, 0.5",
+                ]),
+                1,
+            )
+        };
+
+        let output = parking.run().await.expect("run should succeed");
+
+        // The first withdrawal waits for the resolve, the second for the interest.
+        assert_state(&output[0], 1, dec("0"), dec("0"), dec("0"));
+        assert_eq!(parking.worker_config.skipped.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn malformed_withdrawals_are_rejected_instead_of_parked() {
+        let (ignored, mut reasons) = mpsc::unbounded_channel();
+        let config = WorkerConfig {
+            pending_withdrawals: true,
+            ignored: Some(ignored),
+            ..WorkerConfig::default()
         };
+        let (sender, rx) = mpsc::channel(16);
+        sender
+            .send(vec![
+                (1, tx(TransactionType::Withdrawal, 1, 1, Some(dec("2.0")))),
+                (2, tx(TransactionType::Withdrawal, 1, 2, None)),
+            ])
+            .await
+            .expect("worker channel open");
+        drop(sender);
 
-        let err = penguin.run().await.expect_err("expected parse error");
-        assert!(matches!(err, PenguinError::Parse(2)));
+        let (ledger, result) = spawn_worker(rx, config, Ledger::default()).await;
+        result.expect("worker should succeed");
+
+        // Only the well-formed withdrawal waits, and is dropped at the end.
+        assert_eq!(reasons.recv().await, Some((2, IgnoreReason::Rejected)));
+        assert_eq!(
+            reasons.recv().await,
+            Some((1, IgnoreReason::InsufficientFunds))
+        );
+        assert_state(&ledger.client_states[&1], 1, dec("0"), dec("0"), dec("0"));
     }
 
     #[test]
-    fn deposit_and_withdrawal_update_balances() {
+    fn disputes_seen_counts_applied_disputes() {
         let mut client_state = ClientState::new(1);
-        let mut registry: HashMap<ClientTx, Decimal> = HashMap::new();
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
 
-        apply_tx(
-            &mut client_state,
-            &tx(TransactionType::Deposit, 1, 1, Some(dec("1.0"))),
-            &mut registry,
-        )
-        .expect("deposit should succeed");
+        for (id, amount) in [(1, "1.0"), (2, "2.0")] {
+            apply_tx(
+                &mut client_state,
+                LINE,
+                &tx(TransactionType::Deposit, 1, id, Some(dec(amount))),
+                &mut registry,
+                &WorkerConfig::default(),
+            )
+            .expect("deposit should succeed");
+            registry.insert((1, id), Deposit::new(dec(amount)));
+        }
 
+        for (tx_type, id) in [
+            (TransactionType::Dispute, 1),
+            (TransactionType::Dispute, 2),
+            (TransactionType::Resolve, 1),
+        ] {
+            apply_tx(
+                &mut client_state,
+                LINE,
+                &tx(tx_type, 1, id, None),
+                &mut registry,
+                &WorkerConfig::default(),
+            )
+            .expect("dispute flow should succeed");
+        }
+
+        // Unknown transactions are ignored and not counted.
         apply_tx(
             &mut client_state,
-            &tx(TransactionType::Withdrawal, 1, 2, Some(dec("0.4"))),
+            LINE,
+            &tx(TransactionType::Dispute, 1, 99, None),
             &mut registry,
+            &WorkerConfig::default(),
         )
-        .expect("withdrawal should succeed");
+        .expect("unknown disputes are ignored");
 
-        assert_state(&client_state, 1, dec("0.6"), dec("0"), dec("0.6"));
+        assert_eq!(client_state.disputes_seen, 2);
     }
 
     #[test]
-    fn withdrawal_with_insufficient_funds_is_ignored() {
+    fn currencies_do_not_interfere_with_each_other() {
         let mut client_state = ClientState::new(1);
-        let mut registry: HashMap<ClientTx, Decimal> = HashMap::new();
-
-        apply_tx(
-            &mut client_state,
-            &tx(TransactionType::Deposit, 1, 1, Some(dec("1.0"))),
-            &mut registry,
-        )
-        .expect("deposit should succeed");
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
+        let in_currency = |tx_type, id, amount: Option<&str>, currency: &str| Transaction {
+            currency: Some(currency.into()),
+            ..tx(tx_type, 1, id, amount.map(dec))
+        };
 
-        apply_tx(
-            &mut client_state,
-            &tx(TransactionType::Withdrawal, 1, 2, Some(dec("2.0"))),
-            &mut registry,
-        )
-        .expect("withdrawal is ignored when insufficient");
+        for (id, transaction) in [
+            in_currency(TransactionType::Deposit, 1, Some("10.0"), "EUR"),
+            in_currency(TransactionType::Deposit, 2, Some("5.0"), "USD"),
+            in_currency(TransactionType::Withdrawal, 3, Some("3.0"), "EUR"),
+            // More than the USD balance, even though EUR could cover it.
+            in_currency(TransactionType::Withdrawal, 4, Some("6.0"), "USD"),
+            // Dispute naming the wrong currency is ignored.
+            in_currency(TransactionType::Dispute, 2, None, "EUR"),
+            // Dispute without a currency inherits the deposit's.
+            tx(TransactionType::Dispute, 1, 2, None),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            if let (TransactionType::Deposit, Some(amount)) =
+                (transaction.tx_type, transaction.amount)
+            {
+                registry.insert(
+                    (1, transaction.tx),
+                    Deposit {
+                        currency: transaction.currency.clone(),
+                        ..Deposit::new(amount)
+                    },
+                );
+            }
+            apply_tx(
+                &mut client_state,
+                id + 1,
+                &transaction,
+                &mut registry,
+                &WorkerConfig::default(),
+            )
+            .expect("transaction should apply");
+        }
 
-        assert_state(&client_state, 1, dec("1.0"), dec("0"), dec("1.0"));
+        assert_eq!(client_state.balances(None), Balances::default());
+        assert_eq!(
+            client_state.balances(Some("EUR")),
+            Balances {
+                available: dec("7.0"),
+                held: dec("0"),
+                total: dec("7.0"),
+            }
+        );
+        assert_eq!(
+            client_state.balances(Some("USD")),
+            Balances {
+                available: dec("0"),
+                held: dec("5.0"),
+                total: dec("5.0"),
+            }
+        );
+        assert_eq!(client_state.disputes_seen, 1);
     }
 
     #[test]
-    fn dispute_and_resolve_move_funds_between_available_and_held() {
+    fn chargeback_locks_account_and_updates_totals() {
         let mut client_state = ClientState::new(1);
-        let mut registry: HashMap<ClientTx, Decimal> = HashMap::new();
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
 
         apply_tx(
             &mut client_state,
+            LINE,
             &tx(TransactionType::Deposit, 1, 1, Some(dec("1.0"))),
             &mut registry,
+            &WorkerConfig::default(),
         )
         .expect("deposit should succeed");
 
-        registry.insert((1, 1), dec("1.0"));
+        registry.insert((1, 1), Deposit::new(dec("1.0")));
 
         apply_tx(
             &mut client_state,
+            LINE,
             &tx(TransactionType::Dispute, 1, 1, None),
             &mut registry,
+            &WorkerConfig::default(),
         )
         .expect("dispute should succeed");
-        assert_state(&client_state, 1, dec("0"), dec("1.0"), dec("1.0"));
+
+        apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Chargeback, 1, 1, None),
+            &mut registry,
+            &WorkerConfig::default(),
+        )
+        .expect("chargeback should succeed");
+
+        assert!(client_state.locked);
+        assert_state(&client_state, 1, dec("0"), dec("0"), dec("0"));
         assert_eq!(registry.len(), 1);
+        assert_eq!(registry[&(1, 1)].settled, Some(Settlement::ChargedBack));
 
         apply_tx(
             &mut client_state,
-            &tx(TransactionType::Resolve, 1, 1, None),
+            LINE,
+            &tx(TransactionType::Deposit, 1, 2, Some(dec("5.0"))),
             &mut registry,
+            &WorkerConfig::default(),
         )
-        .expect("resolve should succeed");
+        .expect("locked accounts ignore deposits");
 
-        assert_state(&client_state, 1, dec("1.0"), dec("0"), dec("1.0"));
-        assert_eq!(registry.len(), 0);
+        assert_state(&client_state, 1, dec("0"), dec("0"), dec("0"));
     }
 
     #[test]
-    fn chargeback_locks_account_and_updates_totals() {
+    fn resolve_and_chargeback_never_push_held_below_zero() {
         let mut client_state = ClientState::new(1);
-        let mut registry: HashMap<ClientTx, Decimal> = HashMap::new();
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
 
         apply_tx(
             &mut client_state,
+            LINE,
             &tx(TransactionType::Deposit, 1, 1, Some(dec("1.0"))),
             &mut registry,
+            &WorkerConfig::default(),
         )
         .expect("deposit should succeed");
 
-        registry.insert((1, 1), dec("1.0"));
+        // Registry claims a disputed amount that was never moved to held.
+        registry.insert(
+            (1, 1),
+            Deposit {
+                disputable: Decimal::ZERO,
+                held: dec("1.0"),
+                ..Deposit::new(dec("1.0"))
+            },
+        );
+
+        let err = apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Resolve, 1, 1, None),
+            &mut registry,
+            &WorkerConfig::default(),
+        )
+        .expect_err("expected resolve to be rejected");
+        assert!(matches!(err, PenguinError::InvariantViolation(1, _)));
+
+        let err = apply_tx(
+            &mut client_state,
+            LINE,
+            &tx(TransactionType::Chargeback, 1, 1, None),
+            &mut registry,
+            &WorkerConfig::default(),
+        )
+        .expect_err("expected chargeback to be rejected");
+        assert!(matches!(err, PenguinError::InvariantViolation(1, _)));
+
+        assert!(!client_state.locked);
+        assert_state(&client_state, 1, dec("1.0"), dec("0"), dec("1.0"));
+    }
+
+    #[test]
+    fn negative_dust_within_epsilon_is_clamped_to_zero() {
+        let mut client_state = ClientState::new(1);
+        client_state.available = dec("0.99999");
+        client_state.total = dec("0.99999");
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
+        registry.insert((1, 1), Deposit::new(dec("1.0")));
 
         apply_tx(
             &mut client_state,
+            LINE,
             &tx(TransactionType::Dispute, 1, 1, None),
             &mut registry,
+            &WorkerConfig::default(),
         )
         .expect("dispute should succeed");
+        assert_eq!(client_state.available, dec("-0.00001"));
+
+        clamp_negative_dust(&mut client_state, dec("0.0001"));
+        assert_state(&client_state, 1, dec("0"), dec("1.0"), dec("1.0"));
+        check_balances(&client_state).expect("clamping should keep the totals consistent");
+
+        client_state.available = dec("-0.001");
+        clamp_negative_dust(&mut client_state, dec("0.0001"));
+        assert_eq!(client_state.available, dec("-0.001"));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_count_each_transaction_once() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+
+        metrics::with_local_recorder(&recorder, || {
+            let config = WorkerConfig {
+                pending_withdrawals: true,
+                ..WorkerConfig::default()
+            };
+            let (sender, rx) = mpsc::channel(16);
+            sender
+                .try_send(vec![
+                    (1, tx(TransactionType::Withdrawal, 1, 1, Some(dec("5.0")))),
+                    (2, tx(TransactionType::Deposit, 1, 2, Some(dec("1.0")))),
+                    (3, tx(TransactionType::Withdrawal, 1, 3, Some(dec("1.0")))),
+                    (4, tx(TransactionType::Deposit, 1, 4, Some(dec("1.0")))),
+                    (5, tx(TransactionType::Deposit, 1, 5, Some(dec("10.0")))),
+                ])
+                .expect("worker channel open");
+            drop(sender);
+
+            // On this thread, so the local recorder sees the worker.
+            let (ledger, result) = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .expect("runtime")
+                .block_on(spawn_worker(rx, config, Ledger::default()));
+            result.expect("worker should succeed");
+            assert_eq!(ledger.client_states[&1].available, dec("6.0"));
+        });
+
+        let processed = |tx_type: &str| {
+            snapshotter
+                .snapshot()
+                .into_vec()
+                .into_iter()
+                .find_map(|(key, _, _, value)| {
+                    let key = key.key();
+                    (key.name() == "penguin_transactions_processed_total"
+                        && key.labels().any(|label| label.value() == tx_type))
+                    .then_some(value)
+                })
+                .expect("processed counter should be recorded")
+        };
+
+        // Parked withdrawals are retried after each deposit, but counted once.
+        assert_eq!(processed("deposit"), DebugValue::Counter(3));
+        assert_eq!(processed("withdrawal"), DebugValue::Counter(2));
+    }
 
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_stop_counting_a_panicked_worker() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+
+        metrics::with_local_recorder(&recorder, || {
+            let panicked = std::panic::catch_unwind(|| {
+                let _active = telemetry::ActiveWorker::start();
+                panic!("worker failed");
+            });
+            assert!(panicked.is_err());
+        });
+
+        let active = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .find_map(|(key, _, _, value)| {
+                (key.key().name() == "penguin_active_workers").then_some(value)
+            })
+            .expect("active workers gauge should be recorded");
+
+        assert!(matches!(active, DebugValue::Gauge(value) if value == 0.0));
+    }
+
+    #[tokio::test]
+    async fn registry_capacity_evicts_oldest_undisputed_deposits() {
+        let config = WorkerConfig {
+            registry_capacity: Some(2),
+            ..WorkerConfig::default()
+        };
+        let (sender, rx) = mpsc::channel(16);
+        for (line, transaction) in [
+            tx(TransactionType::Deposit, 1, 1, Some(dec("1.0"))),
+            tx(TransactionType::Dispute, 1, 1, None),
+            tx(TransactionType::Deposit, 1, 2, Some(dec("2.0"))),
+            tx(TransactionType::Deposit, 1, 3, Some(dec("3.0"))),
+            tx(TransactionType::Deposit, 1, 4, Some(dec("4.0"))),
+            tx(TransactionType::Dispute, 1, 2, None),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            sender
+                .send(vec![(line + 1, transaction)])
+                .await
+                .expect("worker channel open");
+        }
+        drop(sender);
+
+        let (ledger, _) = spawn_worker(rx, config, Ledger::default()).await;
+
+        // tx 1 is disputed so it survives; tx 2 and 3 were evicted in order.
+        assert_eq!(ledger.client_tx_registry.len(), 2);
+        assert!(ledger.client_tx_registry.contains_key(&(1, 1)));
+        assert!(ledger.client_tx_registry.contains_key(&(1, 4)));
+        // The dispute on the evicted tx 2 is ignored.
+        assert_state(
+            &ledger.client_states[&1],
+            1,
+            dec("9.0"),
+            dec("1.0"),
+            dec("10.0"),
+        );
+    }
+
+    #[test]
+    fn withdrawal_never_draws_on_held_funds() {
+        let mut client_state = ClientState::new(1);
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
+        let config = WorkerConfig::default();
+
+        for transaction in [
+            tx(TransactionType::Deposit, 1, 1, Some(dec("6.0"))),
+            tx(TransactionType::Deposit, 1, 2, Some(dec("4.0"))),
+        ] {
+            registry.insert(
+                (transaction.client, transaction.tx),
+                Deposit::new(transaction.amount.unwrap()),
+            );
+            apply_tx(
+                &mut client_state,
+                LINE,
+                &transaction,
+                &mut registry,
+                &config,
+            )
+            .expect("deposit applies");
+        }
         apply_tx(
             &mut client_state,
-            &tx(TransactionType::Chargeback, 1, 1, None),
+            LINE,
+            &tx(TransactionType::Dispute, 1, 2, None),
             &mut registry,
+            &config,
         )
-        .expect("chargeback should succeed");
-
-        assert!(client_state.locked);
-        assert_state(&client_state, 1, dec("0"), dec("0"), dec("0"));
-        assert_eq!(registry.len(), 0);
+        .expect("dispute applies");
 
-        apply_tx(
+        // Total is 10, but 4 of it is held, so only 6 can be withdrawn.
+        let ignored = apply_tx(
             &mut client_state,
-            &tx(TransactionType::Deposit, 1, 2, Some(dec("5.0"))),
+            LINE,
+            &tx(TransactionType::Withdrawal, 1, 3, Some(dec("7.0"))),
             &mut registry,
+            &config,
         )
-        .expect("locked accounts ignore deposits");
+        .expect("withdrawal is ignored, not an error");
 
-        assert_state(&client_state, 1, dec("0"), dec("0"), dec("0"));
+        assert_eq!(ignored, Some(IgnoreReason::InsufficientFunds));
+        assert_state(&client_state, 1, dec("6.0"), dec("4.0"), dec("10.0"));
     }
 
     #[test]
     fn deposit_without_amount_is_an_error() {
         let mut client_state = ClientState::new(1);
-        let mut registry: HashMap<ClientTx, Decimal> = HashMap::new();
+        let mut registry: Map<ClientTx, Deposit> = Map::default();
 
         let err = apply_tx(
             &mut client_state,
+            LINE,
             &tx(TransactionType::Deposit, 1, 1, None),
             &mut registry,
+            &WorkerConfig::default(),
         )
         .expect_err("expected deposit without amount to error");
 
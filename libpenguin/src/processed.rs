@@ -0,0 +1,113 @@
+//! Persistence of processed transaction ids, used to make reprocessing idempotent.
+//!
+//! The file holds one `client,tx` pair per line.
+
+use crate::types::{ClientTx, PenguinError, Set};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Load the processed ids stored at `path`. A missing file means nothing was processed yet.
+pub(crate) fn load(path: &Path) -> Result<Set<ClientTx>, PenguinError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Set::default()),
+        Err(err) => return Err(err.into()),
+    };
+
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            line.split_once(',')
+                .and_then(|(client, tx)| {
+                    Some((client.trim().parse().ok()?, tx.trim().parse().ok()?))
+                })
+                .ok_or_else(|| PenguinError::ProcessedId {
+                    line: index + 1,
+                    content: line.to_owned(),
+                })
+        })
+        .collect()
+}
+
+/// Replace the file at `path` with `processed`, sorted for stable diffs.
+///
+/// The ids are written to a sibling file first and renamed over `path`, so a crash
+/// mid-write leaves the previous list intact.
+pub(crate) fn save(path: &Path, processed: &Set<ClientTx>) -> Result<(), PenguinError> {
+    let mut ids: Vec<_> = processed.iter().collect();
+    ids.sort_unstable();
+
+    let contents: String = ids
+        .into_iter()
+        .map(|(client, tx)| format!("{client},{tx}\n"))
+        .collect();
+    let staging = staging_path(path);
+    fs::write(&staging, contents)?;
+    fs::rename(&staging, path)?;
+
+    Ok(())
+}
+
+/// Sibling of `path` no other save or file uses: the process id and a per-process
+/// counter are appended to the full file name.
+fn staging_path(path: &Path) -> PathBuf {
+    static SAVES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(format!(
+        ".{}.{}.tmp",
+        process::id(),
+        SAVES.fetch_add(1, Ordering::Relaxed)
+    ));
+    staging.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn processed_ids_round_trip_through_a_file() {
+        let path =
+            std::env::temp_dir().join(format!("penguin-processed-{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        assert!(load(&path).expect("missing file is empty").is_empty());
+
+        let processed = Set::from_iter([(2, 7), (1, 3), (1, 1)]);
+        save(&path, &processed).expect("save processed ids");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1,1\n1,3\n2,7\n");
+        assert_eq!(load(&path).expect("load processed ids"), processed);
+
+        fs::write(&path, "1,1\nnot an id\n").unwrap();
+        let err = load(&path).expect_err("invalid line");
+        assert!(matches!(
+            err,
+            PenguinError::ProcessedId { line: 2, content } if content == "not an id"
+        ));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn saving_leaves_files_sharing_the_stem_alone() {
+        let path =
+            std::env::temp_dir().join(format!("penguin-processed-{}.ids", std::process::id()));
+        let sibling = path.with_extension("tmp");
+        fs::write(&sibling, "unrelated").unwrap();
+
+        save(&path, &Set::from_iter([(1, 1)])).expect("save processed ids");
+        assert_eq!(fs::read_to_string(&sibling).unwrap(), "unrelated");
+        assert_ne!(staging_path(&path), staging_path(&path));
+        assert_eq!(staging_path(&path).parent(), path.parent());
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&sibling).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use crate::types::{AMOUNT_DECIMALS, ClientRow, ClientState, format_amount, format_amount_dp};
+use std::fmt::{self, Write};
+
+const HEADERS: [&str; 5] = ["client", "currency", "available", "held", "total"];
+
+/// Format client states as an aligned, human-readable table.
+///
+/// Amounts use the same 4 decimal formatting as the CSV output and are right-aligned;
+/// locked accounts are flagged with `LOCKED`.
+pub fn report(states: &[ClientState]) -> String {
+    report_with_precision(states, AMOUNT_DECIMALS)
+}
+
+/// Format client states like [`report`], with amounts rounded to `decimals` places.
+pub fn report_with_precision(states: &[ClientState], decimals: u32) -> String {
+    let rows: Vec<(ClientRow<'_>, [String; 5])> = states
+        .iter()
+        .flat_map(ClientState::rows)
+        .map(|row| {
+            let cells = [
+                row.client.to_string(),
+                row.currency.unwrap_or_default().to_owned(),
+                format_amount_dp(row.balances.available, decimals),
+                format_amount_dp(row.balances.held, decimals),
+                format_amount_dp(row.balances.total, decimals),
+            ];
+            (row, cells)
+        })
+        .collect();
+
+    let mut widths = HEADERS.map(str::len);
+    for (_, cells) in &rows {
+        for (width, cell) in widths.iter_mut().zip(cells) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut output = String::new();
+    push_line(&mut output, &HEADERS, &widths, false);
+    for (row, cells) in &rows {
+        push_line(&mut output, cells, &widths, row.locked);
+    }
+
+    output
+}
+
+/// Append a table line: the currency column is left-aligned, every other one right-aligned.
+fn push_line(output: &mut String, cells: &[impl AsRef<str>; 5], widths: &[usize; 5], locked: bool) {
+    let [client, currency, available, held, total] = cells.each_ref().map(AsRef::as_ref);
+    let [w_client, w_currency, w_available, w_held, w_total] = *widths;
+
+    // Writing to a String can't fail.
+    let _ = write!(
+        output,
+        "{client:>w_client$}  {currency:<w_currency$}  {available:>w_available$}  {held:>w_held$}  {total:>w_total$}"
+    );
+    if locked {
+        output.push_str("  LOCKED");
+    }
+    output.push('\n');
+}
+
+impl fmt::Display for ClientRow<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client {}", self.client)?;
+        if let Some(currency) = self.currency {
+            write!(f, " ({currency})")?;
+        }
+        write!(
+            f,
+            ": available {}, held {}, total {}",
+            format_amount(self.balances.available),
+            format_amount(self.balances.held),
+            format_amount(self.balances.total),
+        )?;
+        if self.locked {
+            write!(f, ", LOCKED")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// One line per currency, as in [`ClientState::rows`].
+impl fmt::Display for ClientState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, row) in self.rows().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{row}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Balances;
+    use rust_decimal::Decimal;
+
+    fn state() -> ClientState {
+        let mut state = ClientState::with_balances(12, Decimal::new(15, 1), Decimal::ZERO, true);
+        state.currencies.insert(
+            "EUR".into(),
+            Balances {
+                available: Decimal::new(100, 0),
+                held: Decimal::new(25, 1),
+                total: Decimal::new(1025, 1),
+            },
+        );
+        state
+    }
+
+    #[test]
+    fn display_lists_each_currency() {
+        assert_eq!(
+            state().to_string(),
+            "client 12: available 1.5, held 0, total 1.5, LOCKED\n\
+             client 12 (EUR): available 100, held 2.5, total 102.5, LOCKED"
+        );
+    }
+
+    #[test]
+    fn report_aligns_columns() {
+        assert_eq!(
+            report(&[ClientState::new(3), state()]),
+            "client  currency  available  held  total\n\
+             \x20    3                    0     0      0\n\
+             \x20   12                  1.5     0    1.5  LOCKED\n\
+             \x20   12  EUR             100   2.5  102.5  LOCKED\n"
+        );
+    }
+}
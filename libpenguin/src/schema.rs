@@ -0,0 +1,241 @@
+use crate::types::{AMOUNT_DECIMALS, ClientRow, ClientState, format_amount_dp};
+use rust_decimal::Decimal;
+use std::io;
+
+/// A column of the client state output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    /// Client identifier.
+    Client,
+    /// Available funds.
+    Available,
+    /// Funds held by disputes.
+    Held,
+    /// Available plus held funds.
+    Total,
+    /// Whether the account is locked.
+    Locked,
+    /// Number of disputes applied to the account.
+    DisputesSeen,
+    /// Currency of the row, empty for the default balances. Not part of the default
+    /// schema, add it to tell the rows of a client apart when it uses several currencies.
+    Currency,
+    /// Id of the last transaction that changed the account, empty if none did.
+    LastTx,
+    /// Id of the first transaction seen for the account, empty if unknown. Not part of the
+    /// default schema, add it with [`StateSchema::column`].
+    CreatedByTx,
+}
+
+impl Column {
+    /// Header used when the column isn't renamed, the same as the serde field name.
+    pub fn default_name(self) -> &'static str {
+        match self {
+            Column::Client => "client",
+            Column::Available => "available",
+            Column::Held => "held",
+            Column::Total => "total",
+            Column::Locked => "locked",
+            Column::DisputesSeen => "disputes_seen",
+            Column::Currency => "currency",
+            Column::LastTx => "last_tx",
+            Column::CreatedByTx => "created_by_tx",
+        }
+    }
+
+    /// Render this column of `row` with amounts rounded to `decimals` places, formatted
+    /// like the `Serialize` impl at 4.
+    fn value(self, row: &ClientRow<'_>, decimals: u32) -> String {
+        match self {
+            Column::Client => row.client.to_string(),
+            Column::Available => format_amount_dp(row.balances.available, decimals),
+            Column::Held => format_amount_dp(row.balances.held, decimals),
+            Column::Total => format_amount_dp(row.balances.total, decimals),
+            Column::Locked => row.locked.to_string(),
+            Column::DisputesSeen => row.disputes_seen.to_string(),
+            Column::Currency => row.currency.unwrap_or_default().to_owned(),
+            Column::LastTx => row.last_tx.map(|tx| tx.to_string()).unwrap_or_default(),
+            Column::CreatedByTx => row
+                .created_by_tx
+                .map(|tx| tx.to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Output layout for client states: which columns, in which order, under which header.
+///
+/// The default schema matches the `Serialize` impl of [`ClientRow`].
+///
+/// ```
+/// # use libpenguin::prelude::*;
+/// let schema = StateSchema::new()
+///     .renamed(Column::Client, "client_id")
+///     .column(Column::Total)
+///     .column(Column::Available)
+///     .column(Column::Held)
+///     .column(Column::Locked);
+///
+/// assert_eq!(schema.header(), ["client_id", "total", "available", "held", "locked"]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateSchema {
+    columns: Vec<(Column, String)>,
+    /// Decimal places amounts are written with.
+    precision: u32,
+}
+
+impl StateSchema {
+    /// Start an empty schema.
+    pub fn new() -> Self {
+        Self {
+            columns: Vec::new(),
+            precision: AMOUNT_DECIMALS,
+        }
+    }
+
+    /// Append `column` under its default name.
+    pub fn column(self, column: Column) -> Self {
+        self.renamed(column, column.default_name())
+    }
+
+    /// Append `column` under the header `name`.
+    pub fn renamed(mut self, column: Column, name: impl Into<String>) -> Self {
+        self.columns.push((column, name.into()));
+        self
+    }
+
+    /// Write amounts with at most `decimals` places instead of 4, capped at 28.
+    ///
+    /// Only the output is rounded: balances are computed and kept at full precision.
+    pub fn with_precision(self, decimals: u32) -> Self {
+        Self {
+            precision: decimals.min(Decimal::MAX_SCALE),
+            ..self
+        }
+    }
+
+    /// Header row.
+    pub fn header(&self) -> Vec<&str> {
+        self.columns.iter().map(|(_, name)| name.as_str()).collect()
+    }
+
+    /// Values of `row`, in column order.
+    pub fn record(&self, row: &ClientRow<'_>) -> Vec<String> {
+        self.columns
+            .iter()
+            .map(|(column, _)| column.value(row, self.precision))
+            .collect()
+    }
+
+    /// Write the header and one record per row of every state (see [`ClientState::rows`]).
+    pub fn write_csv<W: io::Write>(
+        &self,
+        writer: &mut csv::Writer<W>,
+        states: &[ClientState],
+    ) -> csv::Result<()> {
+        writer.write_record(self.header())?;
+        for row in states.iter().flat_map(ClientState::rows) {
+            writer.write_record(self.record(&row))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for StateSchema {
+    fn default() -> Self {
+        [
+            Column::Client,
+            Column::Available,
+            Column::Held,
+            Column::Total,
+            Column::Locked,
+            Column::DisputesSeen,
+            Column::LastTx,
+        ]
+        .into_iter()
+        .fold(Self::new(), Self::column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    fn write(schema: &StateSchema, states: &[ClientState]) -> String {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        schema.write_csv(&mut writer, states).expect("write csv");
+
+        String::from_utf8(writer.into_inner().expect("flush csv")).unwrap()
+    }
+
+    fn state() -> ClientState {
+        ClientState::with_balances(7, Decimal::new(1_234_567, 5), Decimal::new(5, 1), true)
+    }
+
+    #[test]
+    fn reordered_and_renamed_columns() {
+        let schema = StateSchema::new()
+            .renamed(Column::Client, "client_id")
+            .column(Column::Total)
+            .column(Column::Available)
+            .column(Column::Held)
+            .renamed(Column::Locked, "frozen");
+
+        assert_eq!(
+            write(&schema, &[state()]),
+            "client_id,total,available,held,frozen\n7,12.8457,12.3457,0.5,true\n"
+        );
+    }
+
+    #[test]
+    fn precision_rounds_only_the_output() {
+        let schema = StateSchema::new()
+            .column(Column::Available)
+            .column(Column::Total);
+        let state = state();
+
+        assert_eq!(
+            write(
+                &schema.clone().with_precision(2),
+                std::slice::from_ref(&state)
+            ),
+            "available,total\n12.35,12.85\n"
+        );
+        assert_eq!(
+            write(&schema.with_precision(0), std::slice::from_ref(&state)),
+            "available,total\n12,13\n"
+        );
+        assert_eq!(state.available, Decimal::new(1_234_567, 5));
+    }
+
+    #[test]
+    fn created_by_tx_is_opt_in() {
+        let mut state = state();
+        state.created_by_tx = Some(3);
+
+        assert!(!StateSchema::default().header().contains(&"created_by_tx"));
+        assert_eq!(
+            write(
+                &StateSchema::new()
+                    .column(Column::Client)
+                    .column(Column::CreatedByTx),
+                &[state]
+            ),
+            "client,created_by_tx\n7,3\n"
+        );
+    }
+
+    #[test]
+    fn default_schema_matches_serialize() {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for row in state().rows() {
+            writer.serialize(row).expect("serialize row");
+        }
+        let serialized = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+
+        assert_eq!(write(&StateSchema::default(), &[state()]), serialized);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::types::{ClientState, PenguinError};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// Destination for the client states produced by a run.
+///
+/// Implement it to store balances somewhere other than the CLI's CSV output.
+pub trait StateSink {
+    /// Store a single client state, replacing any previous state for the same client.
+    fn write_state(&mut self, state: &ClientState) -> Result<(), PenguinError>;
+
+    /// Make sure every written state has been persisted.
+    fn flush(&mut self) -> Result<(), PenguinError> {
+        Ok(())
+    }
+}
+
+/// [`StateSink`] collecting every state in memory, e.g. to inspect them in tests.
+#[derive(Debug, Default)]
+pub struct VecSink {
+    /// States in the order they were written.
+    pub states: Vec<ClientState>,
+}
+
+impl StateSink for VecSink {
+    fn write_state(&mut self, state: &ClientState) -> Result<(), PenguinError> {
+        self.states.push(state.clone());
+        Ok(())
+    }
+}
+
+/// [`StateSink`] writing every state to several sinks, e.g. a CSV and a JSON output in
+/// the same run.
+///
+/// Every inner sink gets every state even when another one fails. Failures are
+/// returned as they are when only one sink failed, or as a [`PenguinError::Sink`]
+/// holding [`SinkErrors`] otherwise.
+#[derive(Default)]
+pub struct TeeSink {
+    sinks: Vec<Box<dyn StateSink>>,
+}
+
+impl TeeSink {
+    /// Fan out to `sinks`, in order.
+    pub fn new(sinks: Vec<Box<dyn StateSink>>) -> Self {
+        Self { sinks }
+    }
+
+    /// Also write to `sink`, after the existing ones.
+    pub fn with_sink(mut self, sink: impl StateSink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Give the inner sinks back, e.g. to read what they collected.
+    pub fn into_inner(self) -> Vec<Box<dyn StateSink>> {
+        self.sinks
+    }
+
+    fn each(
+        &mut self,
+        mut op: impl FnMut(&mut dyn StateSink) -> Result<(), PenguinError>,
+    ) -> Result<(), PenguinError> {
+        let mut errors: Vec<_> = self
+            .sinks
+            .iter_mut()
+            .filter_map(|sink| op(sink.as_mut()).err())
+            .collect();
+
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(PenguinError::Sink(Box::new(SinkErrors(errors)))),
+        }
+    }
+}
+
+impl StateSink for TeeSink {
+    fn write_state(&mut self, state: &ClientState) -> Result<(), PenguinError> {
+        self.each(|sink| sink.write_state(state))
+    }
+
+    fn flush(&mut self) -> Result<(), PenguinError> {
+        self.each(|sink| sink.flush())
+    }
+}
+
+/// Errors of the several sinks of a [`TeeSink`] that failed on the same state.
+#[derive(Debug)]
+pub struct SinkErrors(pub Vec<PenguinError>);
+
+impl fmt::Display for SinkErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} sinks failed", self.0.len())?;
+        for err in &self.0 {
+            write!(f, "; {err}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SinkErrors {}
+
+/// Storage for the client states a worker evicts, see
+/// [`PenguinBuilder::with_max_clients_per_worker`](crate::prelude::PenguinBuilder::with_max_clients_per_worker).
+///
+/// Called from the worker tasks, but a given client is only ever evicted and restored
+/// by the worker owning it.
+pub trait EvictedStates: Send + Sync {
+    /// Store the state of a client evicted from its worker, replacing any previous one.
+    fn evict(&self, state: ClientState);
+
+    /// Take back the state of `client`, if it was evicted, as a transaction for it arrived.
+    fn restore(&self, client: u16) -> Option<ClientState>;
+}
+
+impl EvictedStates for Mutex<HashMap<u16, ClientState>> {
+    fn evict(&self, state: ClientState) {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(state.client, state);
+    }
+
+    fn restore(&self, client: u16) -> Option<ClientState> {
+        self.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&client)
+    }
+}
+
+impl<S: EvictedStates + ?Sized> EvictedStates for Arc<S> {
+    fn evict(&self, state: ClientState) {
+        (**self).evict(state);
+    }
+
+    fn restore(&self, client: u16) -> Option<ClientState> {
+        (**self).restore(client)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteSink;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::StateSink;
+    use crate::types::{ClientState, PenguinError, format_amount};
+    use rusqlite::{Connection, params};
+    use std::path::Path;
+
+    /// [`StateSink`] that upserts client states into a `clients` SQLite table.
+    ///
+    /// Amounts are stored as text, formatted exactly like the CSV output, so no
+    /// precision is lost to SQLite's floating point types.
+    pub struct SqliteSink {
+        conn: Connection,
+    }
+
+    impl SqliteSink {
+        /// Open (or create) the database at `path`.
+        pub fn open(path: impl AsRef<Path>) -> Result<Self, PenguinError> {
+            Self::from_connection(Connection::open(path).map_err(sink_error)?)
+        }
+
+        /// Use an existing connection, creating the `clients` table if needed.
+        pub fn from_connection(conn: Connection) -> Result<Self, PenguinError> {
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS clients (
+                    client INTEGER PRIMARY KEY,
+                    available TEXT NOT NULL,
+                    held TEXT NOT NULL,
+                    total TEXT NOT NULL,
+                    locked INTEGER NOT NULL
+                )",
+                (),
+            )
+            .map_err(sink_error)?;
+
+            Ok(Self { conn })
+        }
+
+        /// Underlying connection, e.g. to query the stored states.
+        pub fn connection(&self) -> &Connection {
+            &self.conn
+        }
+    }
+
+    impl StateSink for SqliteSink {
+        fn write_state(&mut self, state: &ClientState) -> Result<(), PenguinError> {
+            self.conn
+                .execute(
+                    "INSERT INTO clients (client, available, held, total, locked)
+                    VALUES (?1, ?2, ?3, ?4, ?5)
+                    ON CONFLICT(client) DO UPDATE SET
+                        available = excluded.available,
+                        held = excluded.held,
+                        total = excluded.total,
+                        locked = excluded.locked",
+                    params![
+                        state.client,
+                        format_amount(state.available),
+                        format_amount(state.held),
+                        format_amount(state.total),
+                        state.locked,
+                    ],
+                )
+                .map_err(sink_error)?;
+
+            Ok(())
+        }
+    }
+
+    fn sink_error(err: rusqlite::Error) -> PenguinError {
+        PenguinError::Sink(Box::new(err))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use rust_decimal::Decimal;
+
+        #[test]
+        fn sqlite_sink_upserts_client_rows() {
+            let mut sink = SqliteSink::from_connection(
+                Connection::open_in_memory().expect("in-memory database"),
+            )
+            .expect("create table");
+
+            let mut state = ClientState::new(7);
+            state.available = Decimal::new(15, 1);
+            state.total = Decimal::new(15, 1);
+            sink.write_state(&state).expect("insert state");
+
+            state.held = Decimal::new(12345, 4);
+            state.total = Decimal::new(27345, 4);
+            state.locked = true;
+            sink.write_state(&state).expect("update state");
+            sink.write_state(&ClientState::new(8))
+                .expect("insert state");
+
+            let row: (String, String, String, bool) = sink
+                .connection()
+                .query_row(
+                    "SELECT available, held, total, locked FROM clients WHERE client = ?1",
+                    [7],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                )
+                .expect("client row");
+
+            assert_eq!(row, ("1.5".into(), "1.2345".into(), "2.7345".into(), true));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    /// Shares a [`VecSink`] with the test while a [`TeeSink`] owns it.
+    #[derive(Clone, Default)]
+    struct SharedSink(Rc<RefCell<VecSink>>);
+
+    impl StateSink for SharedSink {
+        fn write_state(&mut self, state: &ClientState) -> Result<(), PenguinError> {
+            self.0.borrow_mut().write_state(state)
+        }
+    }
+
+    struct FailingSink;
+
+    impl StateSink for FailingSink {
+        fn write_state(&mut self, _state: &ClientState) -> Result<(), PenguinError> {
+            Err(PenguinError::Sink("disk full".into()))
+        }
+    }
+
+    #[test]
+    fn tee_sink_writes_every_state_to_every_sink() {
+        let (first, second) = (SharedSink::default(), SharedSink::default());
+        let mut tee = TeeSink::default()
+            .with_sink(first.clone())
+            .with_sink(second.clone());
+
+        for client in [3, 1, 2] {
+            tee.write_state(&ClientState::new(client))
+                .expect("write state");
+        }
+        tee.flush().expect("flush sinks");
+
+        for sink in [first, second] {
+            let clients: Vec<_> = sink.0.borrow().states.iter().map(|s| s.client).collect();
+            assert_eq!(clients, [3, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn tee_sink_keeps_writing_past_failures_and_aggregates_them() {
+        let sink = SharedSink::default();
+        let mut tee = TeeSink::default()
+            .with_sink(FailingSink)
+            .with_sink(sink.clone())
+            .with_sink(FailingSink);
+
+        let err = tee
+            .write_state(&ClientState::new(1))
+            .expect_err("two sinks fail");
+
+        assert_eq!(sink.0.borrow().states.len(), 1);
+        match err {
+            PenguinError::Sink(err) => {
+                let errors = err.downcast_ref::<SinkErrors>().expect("aggregated errors");
+                assert_eq!(errors.0.len(), 2);
+            }
+            other => panic!("expected a sink error, got {other:?}"),
+        }
+    }
+}
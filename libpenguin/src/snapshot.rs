@@ -0,0 +1,239 @@
+use crate::types::{Balances, ClientState, Deposit, Ledger, PenguinError, Settlement};
+use rust_decimal::Decimal;
+use std::borrow::Cow;
+
+/// Leading bytes identifying a penguin snapshot.
+const MAGIC: &[u8; 4] = b"PNGN";
+/// Snapshot layout version. Bump it whenever the encoding below changes.
+const VERSION: u8 = 9;
+
+/// Encode a ledger as a compact little-endian blob.
+///
+/// Layout (v9): magic, version, client count, clients, registry count, registry entries,
+/// processed count, processed ids.
+/// A client is `client: u16, available, held, total, locked: u8, disputes_seen: u32,
+/// has_last_tx: u8, last_tx: u32?, has_created_by_tx: u8, created_by_tx: u32?,
+/// currency count: u16, (currency, available, held, total)*` and a registry entry is
+/// `client: u16, tx: u32, amount, disputable, held, charged_back, settled: u8,
+/// has_currency: u8, currency?`, with `settled` 0 while disputable, 1 once resolved and
+/// 2 once charged back, and a processed id is `client: u16, tx: u32`. Decimals use their
+/// 16-byte form and strings are a `u16` length followed by UTF-8 bytes.
+///
+/// Fails with [`PenguinError::Snapshot`] when a currency, or a client's list of
+/// currencies, is too long for its `u16` length.
+pub(crate) fn encode(ledger: &Ledger) -> Result<Vec<u8>, PenguinError> {
+    let mut blob = Vec::with_capacity(
+        MAGIC.len()
+            + 13
+            + ledger.client_states.len() * 58
+            + ledger.client_tx_registry.len() * 72
+            + ledger.processed.len() * 6,
+    );
+    blob.extend_from_slice(MAGIC);
+    blob.push(VERSION);
+
+    blob.extend_from_slice(&(ledger.client_states.len() as u32).to_le_bytes());
+    for state in ledger.client_states.values() {
+        blob.extend_from_slice(&state.client.to_le_bytes());
+        blob.extend_from_slice(&state.available.serialize());
+        blob.extend_from_slice(&state.held.serialize());
+        blob.extend_from_slice(&state.total.serialize());
+        blob.push(state.locked as u8);
+        blob.extend_from_slice(&state.disputes_seen.to_le_bytes());
+        blob.push(state.last_tx.is_some() as u8);
+        if let Some(last_tx) = state.last_tx {
+            blob.extend_from_slice(&last_tx.to_le_bytes());
+        }
+        blob.push(state.created_by_tx.is_some() as u8);
+        if let Some(created_by_tx) = state.created_by_tx {
+            blob.extend_from_slice(&created_by_tx.to_le_bytes());
+        }
+        blob.extend_from_slice(&u16_len(state.currencies.len(), "currency list")?.to_le_bytes());
+        for (currency, balances) in &state.currencies {
+            put_str(&mut blob, currency)?;
+            blob.extend_from_slice(&balances.available.serialize());
+            blob.extend_from_slice(&balances.held.serialize());
+            blob.extend_from_slice(&balances.total.serialize());
+        }
+    }
+
+    blob.extend_from_slice(&(ledger.client_tx_registry.len() as u32).to_le_bytes());
+    for (&(client, tx), deposit) in &ledger.client_tx_registry {
+        blob.extend_from_slice(&client.to_le_bytes());
+        blob.extend_from_slice(&tx.to_le_bytes());
+        blob.extend_from_slice(&deposit.amount.serialize());
+        blob.extend_from_slice(&deposit.disputable.serialize());
+        blob.extend_from_slice(&deposit.held.serialize());
+        blob.extend_from_slice(&deposit.charged_back.serialize());
+        blob.push(match deposit.settled {
+            None => 0,
+            Some(Settlement::Resolved) => 1,
+            Some(Settlement::ChargedBack) => 2,
+        });
+        blob.push(deposit.currency.is_some() as u8);
+        if let Some(currency) = &deposit.currency {
+            put_str(&mut blob, currency)?;
+        }
+    }
+
+    blob.extend_from_slice(&(ledger.processed.len() as u32).to_le_bytes());
+    for &(client, tx) in &ledger.processed {
+        blob.extend_from_slice(&client.to_le_bytes());
+        blob.extend_from_slice(&tx.to_le_bytes());
+    }
+
+    Ok(blob)
+}
+
+fn put_str(blob: &mut Vec<u8>, value: &str) -> Result<(), PenguinError> {
+    blob.extend_from_slice(&u16_len(value.len(), "currency")?.to_le_bytes());
+    blob.extend_from_slice(value.as_bytes());
+
+    Ok(())
+}
+
+/// Length of a `what` that is written as a `u16`, failing rather than truncating it.
+fn u16_len(len: usize, what: &str) -> Result<u16, PenguinError> {
+    u16::try_from(len).map_err(|_| {
+        PenguinError::Snapshot(Cow::Owned(format!(
+            "{what} of length {len} is over the {} a snapshot holds",
+            u16::MAX
+        )))
+    })
+}
+
+/// Decode a blob produced by [`encode`].
+pub(crate) fn decode(blob: &[u8]) -> Result<Ledger, PenguinError> {
+    let mut cursor = Cursor(blob);
+
+    if cursor.take::<4>()? != *MAGIC {
+        return Err(PenguinError::Snapshot(Cow::Borrowed(
+            "missing snapshot header",
+        )));
+    }
+    let [version] = cursor.take::<1>()?;
+    if version != VERSION {
+        return Err(PenguinError::Snapshot(Cow::Owned(format!(
+            "unsupported version {version}, expected {VERSION}"
+        ))));
+    }
+
+    let mut ledger = Ledger::default();
+
+    for _ in 0..cursor.u32()? {
+        let mut state = ClientState {
+            client: cursor.u16()?,
+            available: cursor.decimal()?,
+            held: cursor.decimal()?,
+            total: cursor.decimal()?,
+            locked: cursor.bool()?,
+            disputes_seen: cursor.u32()?,
+            last_tx: if cursor.bool()? {
+                Some(cursor.u32()?)
+            } else {
+                None
+            },
+            created_by_tx: if cursor.bool()? {
+                Some(cursor.u32()?)
+            } else {
+                None
+            },
+            ..ClientState::new(0)
+        };
+        for _ in 0..cursor.u16()? {
+            let currency = cursor.string()?;
+            let balances = Balances {
+                available: cursor.decimal()?,
+                held: cursor.decimal()?,
+                total: cursor.decimal()?,
+            };
+            state.currencies.insert(currency, balances);
+        }
+        ledger.client_states.insert(state.client, state);
+    }
+
+    for _ in 0..cursor.u32()? {
+        let client_tx = (cursor.u16()?, cursor.u32()?);
+        let deposit = Deposit {
+            amount: cursor.decimal()?,
+            disputable: cursor.decimal()?,
+            held: cursor.decimal()?,
+            charged_back: cursor.decimal()?,
+            settled: match cursor.take()? {
+                [0] => None,
+                [1] => Some(Settlement::Resolved),
+                [2] => Some(Settlement::ChargedBack),
+                _ => {
+                    return Err(PenguinError::Snapshot(Cow::Borrowed(
+                        "unknown deposit settlement",
+                    )));
+                }
+            },
+            currency: if cursor.bool()? {
+                Some(cursor.string()?)
+            } else {
+                None
+            },
+        };
+        ledger.client_tx_registry.insert(client_tx, deposit);
+    }
+
+    for _ in 0..cursor.u32()? {
+        ledger.processed.insert((cursor.u16()?, cursor.u32()?));
+    }
+
+    if !cursor.0.is_empty() {
+        return Err(PenguinError::Snapshot(Cow::Borrowed(
+            "trailing bytes after snapshot",
+        )));
+    }
+
+    Ok(ledger)
+}
+
+/// Reads fixed-size values from the front of a blob.
+struct Cursor<'a>(&'a [u8]);
+
+impl Cursor<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], PenguinError> {
+        let (head, rest) = self
+            .0
+            .split_first_chunk::<N>()
+            .ok_or(PenguinError::Snapshot(Cow::Borrowed(
+                "snapshot is truncated",
+            )))?;
+        self.0 = rest;
+
+        Ok(*head)
+    }
+
+    fn u16(&mut self) -> Result<u16, PenguinError> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, PenguinError> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn bool(&mut self) -> Result<bool, PenguinError> {
+        self.take().map(|[byte]: [u8; 1]| byte != 0)
+    }
+
+    fn decimal(&mut self) -> Result<Decimal, PenguinError> {
+        self.take().map(Decimal::deserialize)
+    }
+
+    fn string(&mut self) -> Result<String, PenguinError> {
+        let len = usize::from(self.u16()?);
+        if self.0.len() < len {
+            return Err(PenguinError::Snapshot(Cow::Borrowed(
+                "snapshot is truncated",
+            )));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| PenguinError::Snapshot(Cow::Borrowed("currency is not valid UTF-8")))
+    }
+}
@@ -0,0 +1,114 @@
+use crate::types::{PenguinError, Transaction, TransactionType};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// Summary of the last run, see [`Penguin::run_stats`](crate::prelude::Penguin::run_stats).
+///
+/// [`PenguinBuilder::with_report_path`](crate::prelude::PenguinBuilder::with_report_path)
+/// writes it as a JSON object with the same field names.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RunStats {
+    /// Name of the input, e.g. its path, set with
+    /// [`PenguinBuilder::with_input_name`](crate::prelude::PenguinBuilder::with_input_name).
+    pub input: Option<String>,
+    /// Input rows read, including skipped ones.
+    pub rows_read: usize,
+    /// Rows that didn't change any balance, for any [`IgnoreReason`](crate::prelude::IgnoreReason).
+    pub rows_skipped: usize,
+    /// Skipped rows by the category of the
+    /// [`PenguinBuilder::with_ignore_classifier`](crate::prelude::PenguinBuilder::with_ignore_classifier)
+    /// classifier, empty without one.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub ignored_by_category: BTreeMap<String, usize>,
+    /// Rows dropped because their worker stopped early, with
+    /// [`PenguinBuilder::with_skip_closed_workers`](crate::prelude::PenguinBuilder::with_skip_closed_workers).
+    pub rows_undelivered: usize,
+    /// Rows handed to the workers, by transaction type.
+    pub transactions: BTreeMap<TransactionType, usize>,
+    /// Clients known at the end of the run, including those from earlier runs.
+    pub distinct_clients: usize,
+    /// Locked accounts at the end of the run.
+    pub locked_accounts: usize,
+    /// Wall-clock duration of the run, in seconds.
+    pub duration_secs: f64,
+}
+
+impl RunStats {
+    /// Write the stats to `path` as pretty-printed JSON.
+    pub(crate) fn write_json(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()
+    }
+}
+
+/// Count the transactions of each type in `reader`, without running the engine.
+///
+/// This consumes the reader, so a [`Penguin`](crate::prelude::Penguin) can only process
+/// the same input afterwards if the source can be read again, e.g. by opening the file
+/// a second time. Fails on the first unreadable row with the reader's error on its line,
+/// like a run does.
+///
+/// ```
+/// # use libpenguin::prelude::*;
+/// let txs = [Transaction::dispute(1, 1), Transaction::dispute(2, 2)];
+/// let counts = summarize_input(txs.into_iter().map(Ok::<_, PenguinError>))?;
+///
+/// assert_eq!(counts[&TransactionType::Dispute], 2);
+/// # Ok::<(), PenguinError>(())
+/// ```
+pub fn summarize_input<E: Into<PenguinError>>(
+    reader: impl IntoIterator<Item = Result<Transaction, E>>,
+) -> Result<HashMap<TransactionType, usize>, PenguinError> {
+    let mut counts = HashMap::new();
+    for (line, tx) in (1..).zip(reader) {
+        let tx = tx.map_err(|err| err.into().at_line(line))?;
+        *counts.entry(tx.tx_type).or_default() += 1;
+    }
+
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarize_input_counts_each_type() {
+        let lines = [
+            "deposit, 1, 1, 1.0",
+            "deposit, 2, 2, 2.0",
+            "withdrawal, 1, 3, 0.5",
+            "dispute, 2, 2,",
+            "resolve, 2, 2,",
+            "dispute, 2, 2,",
+            "chargeback, 2, 2,",
+            "deposit, 3, 4, 1.0",
+        ];
+
+        let counts = summarize_input(lines.iter().map(|line| line.parse::<Transaction>()))
+            .expect("valid input");
+
+        assert_eq!(
+            counts,
+            HashMap::from([
+                (TransactionType::Deposit, 3),
+                (TransactionType::Withdrawal, 1),
+                (TransactionType::Dispute, 2),
+                (TransactionType::Resolve, 1),
+                (TransactionType::Chargeback, 1),
+            ])
+        );
+
+        let bad = ["deposit, 1, 1, 1.0", "deposit, x, 2, 1.0"];
+        assert!(matches!(
+            summarize_input(bad.iter().map(|line| line.parse::<Transaction>())),
+            Err(PenguinError::InvalidRow { line: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,377 @@
+use crate::types::{ParseScratch, PenguinError, Transaction, TransactionFormat};
+use futures_util::{Stream, stream};
+use std::{
+    borrow::Cow,
+    io::{self, BufRead, BufReader},
+    mem,
+    net::{TcpStream, ToSocketAddrs},
+};
+use tokio::{sync::mpsc, task};
+
+/// Iterator over newline-delimited transactions coming from any [`BufRead`] source.
+///
+/// Each non-empty line is parsed with [`Transaction`]'s `FromStr` implementation, or the
+/// [`TransactionFormat`] set with [`TransactionStream::with_format`], so it plugs straight
+/// into [`PenguinBuilder::from_reader`](crate::prelude::PenguinBuilder::from_reader).
+/// Lines split across several reads are reassembled, and the iterator ends on EOF.
+///
+/// Lines longer than the configured maximum are skipped without being buffered and
+/// yield a [`PenguinError::TransactionParse`], so a pathological input can't exhaust
+/// memory.
+pub struct TransactionStream<R> {
+    reader: R,
+    line: Vec<u8>,
+    max_line_length: usize,
+    scratch: ParseScratch,
+    /// Whether the first non-empty line may still be a header to skip.
+    skip_header: bool,
+    /// Number of lines read so far, blank and skipped ones included.
+    line_number: usize,
+}
+
+/// Outcome of reading one line from the source.
+enum LineRead {
+    Eof,
+    Line,
+    /// The line was discarded after exceeding the limit; holds its full length.
+    TooLong(usize),
+}
+
+impl<R> TransactionStream<R> {
+    /// Default cap on a single line, far above any well-formed transaction.
+    pub const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+
+    /// Number of transactions [`Self::into_async`] reads ahead of the engine.
+    const READ_AHEAD: usize = 1024;
+}
+
+impl TransactionStream<BufReader<TcpStream>> {
+    /// Connect to `addr` and stream the transactions it sends until it closes the connection.
+    ///
+    /// Connecting and reading block the calling thread. From async code, connect within
+    /// [`tokio::task::spawn_blocking`] and read through [`Self::into_async`].
+    pub fn from_tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self::from_buf_read(BufReader::new(TcpStream::connect(
+            addr,
+        )?)))
+    }
+}
+
+impl<R: BufRead> TransactionStream<R> {
+    /// Stream transactions from an already opened source.
+    pub fn from_buf_read(reader: R) -> Self {
+        Self {
+            reader,
+            line: Vec::new(),
+            max_line_length: Self::DEFAULT_MAX_LINE_LENGTH,
+            scratch: ParseScratch::new(),
+            skip_header: false,
+            line_number: 0,
+        }
+    }
+
+    /// Reject lines longer than `max` bytes, line terminator included.
+    ///
+    /// Defaults to [`Self::DEFAULT_MAX_LINE_LENGTH`].
+    pub fn with_max_line_length(self, max: usize) -> Self {
+        Self {
+            max_line_length: max,
+            ..self
+        }
+    }
+
+    /// Parse lines with `format`, e.g. for `;`-delimited input.
+    pub fn with_format(self, format: TransactionFormat) -> Self {
+        Self {
+            scratch: ParseScratch::with_format(format),
+            ..self
+        }
+    }
+
+    /// Skip the first non-empty line when it is a `type, client, tx, amount` header,
+    /// recognized by its first column being `type`.
+    pub fn with_header_skipped(self) -> Self {
+        Self {
+            skip_header: true,
+            ..self
+        }
+    }
+
+    /// Read the next line into `self.line`, buffering at most `max_line_length` bytes.
+    fn read_line(&mut self) -> io::Result<LineRead> {
+        self.line.clear();
+        let mut len = 0;
+
+        loop {
+            let available = match self.reader.fill_buf() {
+                Ok(available) => available,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            if available.is_empty() {
+                break;
+            }
+
+            let (used, done) = match available.iter().position(|byte| *byte == b'\n') {
+                Some(newline) => (newline + 1, true),
+                None => (available.len(), false),
+            };
+            len += used;
+            if len <= self.max_line_length {
+                self.line.extend_from_slice(&available[..used]);
+            } else {
+                self.line.clear();
+            }
+            self.reader.consume(used);
+
+            if done {
+                break;
+            }
+        }
+
+        if len > 0 {
+            self.line_number += 1;
+        }
+        Ok(match len {
+            0 => LineRead::Eof,
+            len if len > self.max_line_length => LineRead::TooLong(len),
+            _ => LineRead::Line,
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for TransactionStream<R> {
+    type Item = Result<Transaction, PenguinError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.read_line() {
+                Ok(LineRead::Eof) => return None,
+                Ok(LineRead::TooLong(len)) => {
+                    return Some(Err(PenguinError::TransactionParse(Cow::Owned(format!(
+                        "line is {len} bytes long, over the {} bytes limit",
+                        self.max_line_length
+                    )))));
+                }
+                Ok(LineRead::Line) => {}
+                Err(err) => return Some(Err(err.into())),
+            }
+
+            let line = match std::str::from_utf8(&self.line) {
+                Ok(line) => line,
+                Err(_) => {
+                    return Some(Err(PenguinError::TransactionParse(Cow::Borrowed(
+                        "line is not valid UTF-8",
+                    ))));
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            if mem::take(&mut self.skip_header) && is_header(line) {
+                continue;
+            }
+            return Some(
+                Transaction::parse_into(line, &mut self.scratch)
+                    .map_err(|err| err.at_line(self.line_number)),
+            );
+        }
+    }
+}
+
+impl<R: BufRead + Send + 'static> TransactionStream<R> {
+    /// Read the transactions on a blocking thread and hand them over as a [`Stream`], for
+    /// [`Penguin::run_stream`](crate::prelude::Penguin::run_stream), so a slow source
+    /// such as a socket doesn't block the runtime.
+    ///
+    /// Must be called from within a Tokio runtime. Dropping the stream stops the reads
+    /// once the next transaction comes in.
+    pub fn into_async(self) -> impl Stream<Item = Result<Transaction, PenguinError>> + Send {
+        let (sender, receiver) = mpsc::channel(Self::READ_AHEAD);
+        task::spawn_blocking(move || {
+            for transaction in self {
+                if sender.blocking_send(transaction).is_err() {
+                    break;
+                }
+            }
+        });
+
+        stream::unfold(receiver, |mut receiver| async move {
+            let transaction = receiver.recv().await?;
+            Some((transaction, receiver))
+        })
+    }
+}
+
+/// Whether `line` starts with a `type` column header, quoted or not.
+fn is_header(line: &str) -> bool {
+    let line = line.trim_start();
+    let line = line.strip_prefix('"').unwrap_or(line);
+    let end = line
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(line.len());
+    line[..end].eq_ignore_ascii_case("type")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::{ClientState, PenguinBuilder};
+    use crate::types::TransactionType;
+    use rust_decimal::Decimal;
+    use std::{
+        io::{Cursor, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    #[test]
+    fn from_tcp_reads_transactions_until_eof() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind loopback");
+        let addr = listener.local_addr().expect("local addr");
+
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().expect("accept connection");
+            // Split lines across writes to exercise partial reads.
+            for chunk in [
+                "deposit, 1, 1, 1.0\nwithdr",
+                "awal, 1, 2, 0.5\n\n",
+                "deposit, 2, 3, 2.0\r\n",
+                "dispute, 1, 1,",
+            ] {
+                socket.write_all(chunk.as_bytes()).expect("write chunk");
+                socket.flush().expect("flush chunk");
+            }
+        });
+
+        let transactions = TransactionStream::from_tcp(addr)
+            .expect("connect to loopback")
+            .collect::<Result<Vec<_>, _>>()
+            .expect("valid transactions");
+        server.join().expect("server thread");
+
+        let summary: Vec<_> = transactions
+            .iter()
+            .map(|tx| (tx.tx_type, tx.client, tx.tx))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (TransactionType::Deposit, 1, 1),
+                (TransactionType::Withdrawal, 1, 2),
+                (TransactionType::Deposit, 2, 3),
+                (TransactionType::Dispute, 1, 1),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn into_async_feeds_tcp_input_to_the_engine() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind loopback");
+        let addr = listener.local_addr().expect("local addr");
+
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().expect("accept connection");
+            socket
+                .write_all(b"deposit, 1, 1, 2.0\nwithdrawal, 1, 2, 0.5\ndeposit, 2, 3, 1.0\n")
+                .expect("write transactions");
+        });
+
+        let stream = task::spawn_blocking(move || TransactionStream::from_tcp(addr))
+            .await
+            .expect("connect task")
+            .expect("connect to loopback");
+        let mut states = PenguinBuilder::from_transactions(Vec::new())
+            .build()
+            .expect("valid builder")
+            .run_stream(stream.into_async())
+            .await
+            .expect("run should succeed");
+        server.join().expect("server thread");
+        states.sort_by_key(ClientState::client);
+
+        let balances: Vec<_> = states
+            .iter()
+            .map(|state| (state.client(), state.total()))
+            .collect();
+        assert_eq!(
+            balances,
+            [(1, Decimal::new(15, 1)), (2, Decimal::new(10, 1))]
+        );
+    }
+
+    #[test]
+    fn only_a_leading_header_is_skipped() {
+        for (input, expected) in [
+            (
+                "\n\"type\",\"client\",\"tx\",\"amount\"\ndeposit, 1, 1, 1.0\n",
+                1,
+            ),
+            ("deposit, 1, 1, 1.0\ndeposit, 1, 2, 1.0\n", 2),
+        ] {
+            let transactions = TransactionStream::from_buf_read(Cursor::new(input))
+                .with_header_skipped()
+                .collect::<Result<Vec<_>, _>>()
+                .expect("valid transactions");
+            assert_eq!(transactions.len(), expected, "{input:?}");
+        }
+
+        let mut stream =
+            TransactionStream::from_buf_read(Cursor::new("deposit, 1, 1, 1.0\ntype, 1, 2\n"))
+                .with_header_skipped();
+        assert!(stream.next().is_some_and(|tx| tx.is_ok()));
+        assert!(stream.next().is_some_and(|tx| tx.is_err()));
+    }
+
+    #[test]
+    fn field_count_errors_name_the_input_line() {
+        let input = "type, client, tx, amount\n\ndeposit, 1, 1\ndeposit, 1, 2, 1.0, EUR, x\n";
+        let errors: Vec<_> = TransactionStream::from_buf_read(Cursor::new(input))
+            .with_header_skipped()
+            .map(|tx| tx.expect_err("wrong number of fields"))
+            .collect();
+
+        assert!(matches!(
+            errors[..],
+            [
+                PenguinError::FieldCount {
+                    line: 3,
+                    expected: 4,
+                    found: 3,
+                },
+                PenguinError::FieldCount {
+                    line: 4,
+                    expected: 5,
+                    found: 6,
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn over_long_lines_are_rejected_and_skipped() {
+        let input = format!("{}\ndeposit, 1, 1, 1.0\n", "x".repeat(1_000_000));
+        // A small buffer makes the long line arrive in many chunks.
+        let reader = BufReader::with_capacity(16, Cursor::new(input));
+        let mut stream = TransactionStream::from_buf_read(reader).with_max_line_length(64);
+
+        match stream.next() {
+            Some(Err(PenguinError::TransactionParse(msg))) => {
+                assert!(msg.contains("1000001 bytes"), "unexpected message: {msg}");
+                assert!(msg.contains("64 bytes limit"), "unexpected message: {msg}");
+            }
+            other => panic!("expected a line length error, got {other:?}"),
+        }
+        assert!(stream.line.capacity() < 1024);
+
+        let tx = stream
+            .next()
+            .expect("one more line")
+            .expect("valid transaction");
+        assert_eq!(
+            (tx.tx_type, tx.client, tx.tx),
+            (TransactionType::Deposit, 1, 1)
+        );
+        assert!(stream.next().is_none());
+    }
+}
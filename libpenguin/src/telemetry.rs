@@ -0,0 +1,167 @@
+//! Optional instrumentation through the `metrics` facade.
+//!
+//! Every hook compiles to a no-op unless the `metrics` feature is enabled, so the
+//! engine can call them unconditionally. Users pick and install the exporter.
+
+use crate::types::{Transaction, TransactionType};
+use std::fmt::Display;
+
+/// Why a transaction did not change any balance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IgnoreReason {
+    /// Skipped by the builder's type filter before reaching a worker.
+    Filtered,
+    /// Transaction type this version doesn't handle.
+    UnknownType,
+    /// The client account is locked.
+    LockedClient,
+    /// Withdrawal larger than the available funds.
+    InsufficientFunds,
+    /// Dispute, resolve or chargeback for a transaction not in the registry.
+    UnknownTx,
+    /// Dispute, resolve or chargeback for a deposit whose disputes were all resolved.
+    ResolvedTx,
+    /// Dispute, resolve or chargeback for a deposit already charged back.
+    ChargedBackTx,
+    /// Dispute, resolve or chargeback in a different currency than its deposit.
+    CurrencyMismatch,
+    /// Dispute, resolve or chargeback for more than the deposit has left to dispute, or
+    /// has held.
+    AmountUnavailable,
+    /// Applying the transaction returned an error.
+    Rejected,
+    /// The client went over its rate limit.
+    RateLimited,
+    /// Already applied by a previous run, with idempotent reprocessing on.
+    AlreadyProcessed,
+    /// Unlock of an account that isn't locked.
+    NotLocked,
+    /// Dispute, resolve or chargeback referencing another client's deposit, with the
+    /// cross-client index on.
+    CrossClientDispute,
+}
+
+/// Sorts ignored transactions into an operator's own categories, e.g. to report locked
+/// accounts apart from every other skip, see
+/// [`PenguinBuilder::with_ignore_classifier`](crate::prelude::PenguinBuilder::with_ignore_classifier).
+///
+/// Called from the worker tasks for every transaction that didn't change any balance.
+/// Each category is counted under its `Display` label in
+/// [`RunStats::ignored_by_category`](crate::prelude::RunStats::ignored_by_category) and,
+/// with the `metrics` feature, in the `penguin_transactions_ignored_by_category_total`
+/// counter.
+pub trait IgnoreClassifier: Send + Sync {
+    /// The operator's categories.
+    type Category: Display;
+
+    /// Category of `tx`, ignored for `reason`.
+    fn classify(&self, tx: &Transaction, reason: IgnoreReason) -> Self::Category;
+}
+
+/// What happened to a single transaction, as reported to
+/// [`PenguinBuilder::with_outcome_sink`](crate::prelude::PenguinBuilder::with_outcome_sink).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The transaction was applied to the client's balances.
+    Applied,
+    /// The transaction didn't change any balance.
+    Ignored(IgnoreReason),
+}
+
+/// Count a transaction handed to a worker, by type.
+#[cfg(feature = "metrics")]
+pub(crate) fn tx_processed(tx_type: TransactionType) {
+    metrics::counter!("penguin_transactions_processed_total", "type" => tx_type.as_str())
+        .increment(1);
+}
+
+/// Count a transaction that was ignored, by reason.
+#[cfg(feature = "metrics")]
+pub(crate) fn tx_ignored(reason: IgnoreReason) {
+    metrics::counter!("penguin_transactions_ignored_total", "reason" => reason_label(reason))
+        .increment(1);
+}
+
+/// Count an ignored transaction, by the category an [`IgnoreClassifier`] put it in.
+#[cfg(feature = "metrics")]
+pub(crate) fn tx_classified(category: &str) {
+    metrics::counter!(
+        "penguin_transactions_ignored_by_category_total",
+        "category" => category.to_owned()
+    )
+    .increment(1);
+}
+
+/// Track a worker task starting.
+#[cfg(feature = "metrics")]
+fn worker_started() {
+    metrics::gauge!("penguin_active_workers").increment(1.0);
+}
+
+/// Track a worker task finishing.
+#[cfg(feature = "metrics")]
+fn worker_stopped() {
+    metrics::gauge!("penguin_active_workers").decrement(1.0);
+}
+
+/// Count a send attempt that found a worker channel full.
+#[cfg(feature = "metrics")]
+pub(crate) fn channel_full(worker: u16) {
+    metrics::counter!("penguin_worker_channel_full_total", "worker" => worker.to_string())
+        .increment(1);
+}
+
+#[cfg(feature = "metrics")]
+fn reason_label(reason: IgnoreReason) -> &'static str {
+    match reason {
+        IgnoreReason::Filtered => "filtered",
+        IgnoreReason::UnknownType => "unknown_type",
+        IgnoreReason::LockedClient => "locked_client",
+        IgnoreReason::InsufficientFunds => "insufficient_funds",
+        IgnoreReason::UnknownTx => "unknown_tx",
+        IgnoreReason::ResolvedTx => "resolved_tx",
+        IgnoreReason::ChargedBackTx => "charged_back_tx",
+        IgnoreReason::CurrencyMismatch => "currency_mismatch",
+        IgnoreReason::AmountUnavailable => "amount_unavailable",
+        IgnoreReason::Rejected => "rejected",
+        IgnoreReason::RateLimited => "rate_limited",
+        IgnoreReason::AlreadyProcessed => "already_processed",
+        IgnoreReason::NotLocked => "not_locked",
+        IgnoreReason::CrossClientDispute => "cross_client_dispute",
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn tx_processed(_tx_type: TransactionType) {}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn tx_ignored(_reason: IgnoreReason) {}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn tx_classified(_category: &str) {}
+
+#[cfg(not(feature = "metrics"))]
+fn worker_started() {}
+
+#[cfg(not(feature = "metrics"))]
+fn worker_stopped() {}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn channel_full(_worker: u16) {}
+
+/// Tracks a worker task as active until dropped, so a worker that panics or is aborted
+/// still stops being counted.
+pub(crate) struct ActiveWorker(());
+
+impl ActiveWorker {
+    pub(crate) fn start() -> Self {
+        worker_started();
+        Self(())
+    }
+}
+
+impl Drop for ActiveWorker {
+    fn drop(&mut self) {
+        worker_stopped();
+    }
+}
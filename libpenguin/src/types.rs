@@ -1,15 +1,21 @@
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize, ser::SerializeStruct};
-use std::{borrow::Cow, io, str::FromStr};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, hash_map::Entry},
+    convert::Infallible,
+    fmt, io,
+    str::FromStr,
+};
 use thiserror::Error;
-use tokio::sync::mpsc::error::SendError;
 
 pub(crate) type TxResult<E> = Result<Transaction, E>;
 
 /// A transaction coming from the input stream.
 ///
 /// Any source is fine as long as it can produce values compatible with this struct.
-#[derive(Debug, Deserialize)]
+/// It serializes back to the same shape, with a missing amount or currency as `null`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Transaction {
     #[serde(rename = "type")]
     /// Transaction type.
@@ -18,100 +24,762 @@ pub struct Transaction {
     pub client: u16,
     /// Transaction identifier.
     pub tx: u32,
-    /// Optional amount for deposit/withdrawal transactions.
+    /// Amount of a deposit or withdrawal. On a dispute or resolve, the part of the
+    /// referenced deposit it applies to, defaulting to all of it.
     pub amount: Option<Decimal>,
+    /// Optional currency code. Transactions without one use the default balances.
+    #[serde(default)]
+    pub currency: Option<String>,
 }
 
-/// Parse a transaction from a CSV-like line.
+/// Parse a transaction from a CSV line.
 ///
-/// The expected format is: `type, client, tx, amount` where `amount` is optional.
+/// The expected format is: `type, client, tx, amount, currency` where `amount` and
+/// `currency` are optional. Fields are tokenized with the `csv` crate, so quoting and
+/// surrounding whitespace behave the same as in the CLI reader. Fields are trimmed, `\r`
+/// of CRLF line endings included, and a blank amount reads as no amount. Use
+/// [`TransactionFormat`] for other delimiters or decimal separators.
 impl FromStr for Transaction {
     type Err = PenguinError;
 
     fn from_str(line: &str) -> Result<Self, Self::Err> {
-        let mut parts = line.split(',').map(|part| part.trim());
-        let tx_type = match parts
+        TransactionFormat::default().parse(line)
+    }
+}
+
+/// Field delimiter and decimal separator of transaction lines.
+///
+/// The default reads `deposit, 1, 1, 1.5`. European exports often look like
+/// `deposit; 1; 1; 1,5` instead:
+///
+/// ```
+/// # use libpenguin::prelude::*;
+/// # use rust_decimal::Decimal;
+/// let format = TransactionFormat::default()
+///     .with_delimiter(b';')
+///     .with_decimal_separator(',');
+/// let tx = format.parse("deposit; 1; 1; 1,50")?;
+///
+/// assert_eq!(tx.amount, Some(Decimal::new(150, 2)));
+/// # Ok::<(), PenguinError>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionFormat {
+    delimiter: u8,
+    decimal_separator: char,
+    skip_unknown_types: bool,
+    strict_precision: bool,
+    amount_decimals: u32,
+}
+
+impl Default for TransactionFormat {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            decimal_separator: '.',
+            skip_unknown_types: false,
+            strict_precision: false,
+            amount_decimals: AMOUNT_DECIMALS,
+        }
+    }
+}
+
+impl TransactionFormat {
+    /// Split fields on `delimiter` instead of `,`.
+    pub fn with_delimiter(self, delimiter: u8) -> Self {
+        Self { delimiter, ..self }
+    }
+
+    /// Read amounts with `separator` between the integer and fractional digits instead
+    /// of `.`, which is then rejected.
+    pub fn with_decimal_separator(self, separator: char) -> Self {
+        Self {
+            decimal_separator: separator,
+            ..self
+        }
+    }
+
+    /// Read types this version doesn't handle as [`TransactionType::Unknown`], so the
+    /// engine skips them, instead of failing to parse the line.
+    ///
+    /// Useful to ingest feeds that already carry transaction types added in the future.
+    pub fn with_unknown_types_skipped(self, skip: bool) -> Self {
+        Self {
+            skip_unknown_types: skip,
+            ..self
+        }
+    }
+
+    /// Fail to parse amounts with more decimal places than the amount scale (4 by
+    /// default) instead of rounding them, so upstream data errors don't go unnoticed.
+    pub fn with_strict_precision(self, strict: bool) -> Self {
+        Self {
+            strict_precision: strict,
+            ..self
+        }
+    }
+
+    /// Keep parsed amounts with up to `decimals` places instead of 4, e.g. 18 for token
+    /// amounts. Capped at 28, the largest scale a [`Decimal`] holds.
+    ///
+    /// Amounts with more digits than a `Decimal` can represent fail with
+    /// [`PenguinError::AmountOutOfRange`] rather than being rounded. Balances are still
+    /// written out with 4 decimals.
+    pub fn with_amount_decimals(self, decimals: u32) -> Self {
+        Self {
+            amount_decimals: decimals.min(Decimal::MAX_SCALE),
+            ..self
+        }
+    }
+
+    /// Parse one transaction line.
+    pub fn parse(&self, line: &str) -> Result<Transaction, PenguinError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .delimiter(self.delimiter)
+            .trim(csv::Trim::All)
+            .from_reader(line.as_bytes());
+        let record = reader
+            .records()
+            .next()
+            .transpose()
+            .map_err(|err| PenguinError::TransactionParse(Cow::Owned(err.to_string())))?
+            .unwrap_or_default();
+
+        Transaction::from_fields(record.iter(), self)
+    }
+
+    /// Parse one `type, client, tx, whole, frac` line, whose amount is split into its
+    /// integer part and its fractional digits, as some feeds do to stay clear of
+    /// floating-point amounts.
+    ///
+    /// The fractional digits are read as written, leading zeros included, so `12` and
+    /// `3400` make `12.3400`. Both columns are blank for disputes, resolves and
+    /// chargebacks, and a trailing currency column is read like in [`Self::parse`].
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// # use rust_decimal::Decimal;
+    /// let tx = TransactionFormat::default().parse_split_amount("deposit, 1, 1, 12, 3400")?;
+    ///
+    /// assert_eq!(tx.amount, Some(Decimal::new(12_3400, 4)));
+    /// # Ok::<(), PenguinError>(())
+    /// ```
+    pub fn parse_split_amount(&self, line: &str) -> Result<Transaction, PenguinError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .delimiter(self.delimiter)
+            .trim(csv::Trim::All)
+            .from_reader(line.as_bytes());
+        let record = reader
+            .records()
             .next()
-            .ok_or(PenguinError::TransactionParse(Cow::Borrowed(
-                "type is required",
-            )))? {
+            .transpose()
+            .map_err(|err| PenguinError::TransactionParse(Cow::Owned(err.to_string())))?
+            .unwrap_or_default();
+        if record.len() < 5 {
+            return Err(PenguinError::FieldCount {
+                line: 1,
+                expected: 5,
+                found: record.len(),
+            });
+        }
+
+        let amount = join_amount(&record[3], &record[4], self.decimal_separator)?;
+        let fields = record
+            .iter()
+            .take(3)
+            .chain([amount.as_str()])
+            .chain(record.iter().skip(5));
+
+        Transaction::from_fields(fields, self)
+    }
+}
+
+/// Put the `whole` and `frac` columns of a split amount back together, blank when both
+/// are.
+fn join_amount(whole: &str, frac: &str, decimal_separator: char) -> Result<String, PenguinError> {
+    let whole = whole.trim();
+    let frac = frac.trim();
+    if whole.is_empty() && frac.is_empty() {
+        return Ok(String::new());
+    }
+
+    let unsigned = whole.strip_prefix(['+', '-']).unwrap_or(whole);
+    if unsigned.is_empty() || !unsigned.chars().all(|c| c.is_ascii_digit()) {
+        return Err(PenguinError::TransactionParse(Cow::Owned(format!(
+            "whole part of the amount must be an integer, got `{whole}`"
+        ))));
+    }
+    if !frac.chars().all(|c| c.is_ascii_digit()) {
+        return Err(PenguinError::TransactionParse(Cow::Owned(format!(
+            "fractional part of the amount must be digits, got `{frac}`"
+        ))));
+    }
+
+    Ok(if frac.is_empty() {
+        whole.to_owned()
+    } else {
+        format!("{whole}{decimal_separator}{frac}")
+    })
+}
+
+/// Reusable buffers for [`Transaction::parse_into`].
+///
+/// Keep one per reading loop: once the buffers have grown to fit the longest line,
+/// parsing a line only allocates for its currency, if it has one.
+#[derive(Debug, Clone)]
+pub struct ParseScratch {
+    format: TransactionFormat,
+    reader: csv_core::Reader,
+    output: Vec<u8>,
+    ends: Vec<usize>,
+}
+
+impl Default for ParseScratch {
+    fn default() -> Self {
+        Self::with_format(TransactionFormat::default())
+    }
+}
+
+impl ParseScratch {
+    /// Buffers for lines in the default format.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Buffers for lines in `format`.
+    pub fn with_format(format: TransactionFormat) -> Self {
+        Self {
+            format,
+            reader: csv_core::ReaderBuilder::new()
+                .delimiter(format.delimiter)
+                .build(),
+            output: vec![0; 128],
+            ends: vec![0; 8],
+        }
+    }
+
+    /// Tokenize `line` into the buffers and return how many bytes and fields it produced.
+    fn tokenize(&mut self, line: &str) -> (usize, usize) {
+        self.reader.reset();
+        let mut input = line.as_bytes();
+        let (mut out, mut fields) = (0, 0);
+        loop {
+            let (result, read, written, ended) =
+                self.reader
+                    .read_record(input, &mut self.output[out..], &mut self.ends[fields..]);
+            input = &input[read..];
+            out += written;
+            fields += ended;
+            match result {
+                // An empty input tells the reader the line is over.
+                csv_core::ReadRecordResult::InputEmpty => {}
+                csv_core::ReadRecordResult::OutputFull => {
+                    self.output.resize(self.output.len() * 2, 0);
+                }
+                csv_core::ReadRecordResult::OutputEndsFull => {
+                    self.ends.resize(self.ends.len() * 2, 0);
+                }
+                csv_core::ReadRecordResult::Record | csv_core::ReadRecordResult::End => {
+                    return (out, fields);
+                }
+            }
+        }
+    }
+}
+
+/// Parse an amount, accepting `_` or space digit grouping and scientific notation.
+///
+/// Commas are not accepted as grouping: they are field separators, so a grouped
+/// amount with commas only survives tokenizing when quoted and is still rejected.
+fn parse_amount(raw: &str, decimal_separator: char) -> Result<Decimal, PenguinError> {
+    // The CSV reader only unquotes a field when the quote opens it, so `, "1 000"` keeps
+    // its quotes after trimming.
+    let raw = raw
+        .strip_prefix('"')
+        .and_then(|quoted| quoted.strip_suffix('"'))
+        .unwrap_or(raw);
+    let mut digits: Cow<'_, str> = if raw.contains(['_', ' ']) {
+        Cow::Owned(raw.chars().filter(|c| *c != '_' && *c != ' ').collect())
+    } else {
+        Cow::Borrowed(raw)
+    };
+    let invalid = || {
+        PenguinError::TransactionParse(Cow::Owned(format!("amount must be decimal, got `{raw}`")))
+    };
+    if decimal_separator != '.' {
+        if digits.contains('.') {
+            return Err(invalid());
+        }
+        digits = Cow::Owned(digits.replace(decimal_separator, "."));
+    }
+
+    let parsed = if digits.contains(['e', 'E']) {
+        Decimal::from_scientific(&digits)
+    } else {
+        Decimal::from_str_exact(&digits)
+    };
+
+    parsed.map_err(|_| {
+        if is_numeric(&digits) {
+            PenguinError::AmountOutOfRange(raw.to_owned())
+        } else {
+            invalid()
+        }
+    })
+}
+
+/// Whether `digits` is a well-formed number, i.e. one that only fails to parse because
+/// a [`Decimal`] can't hold it.
+fn is_numeric(digits: &str) -> bool {
+    let (mantissa, exponent) = match digits.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (digits, None),
+    };
+    let unsigned = |part: &str| part.strip_prefix(['+', '-']).unwrap_or(part).to_owned();
+    let mantissa = unsigned(mantissa);
+    let (integer, fraction) = mantissa.split_once('.').unwrap_or((&mantissa, ""));
+
+    !(integer.is_empty() && fraction.is_empty())
+        && integer
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+        && exponent.is_none_or(|exponent| {
+            let exponent = unsigned(exponent);
+            !exponent.is_empty() && exponent.chars().all(|c| c.is_ascii_digit())
+        })
+}
+
+/// Parse a transaction from a `csv` record with `type, client, tx, amount` columns
+/// and an optional `currency` column.
+///
+/// Errors name the offending column, which makes bad input easier to track down
+/// than the generic serde deserialization errors.
+impl TryFrom<&csv::StringRecord> for Transaction {
+    type Error = PenguinError;
+
+    fn try_from(record: &csv::StringRecord) -> Result<Self, Self::Error> {
+        Transaction::from_fields(record.iter(), &TransactionFormat::default())
+    }
+}
+
+impl Transaction {
+    /// Parse one line like `FromStr` does, reusing `scratch`'s buffers instead of
+    /// allocating new ones for every line.
+    ///
+    /// Lines are read in the format `scratch` was created with.
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// # use rust_decimal::Decimal;
+    /// let mut scratch = ParseScratch::new();
+    /// for (line, tx) in ["deposit, 1, 1, 1.5", "withdrawal, 1, 2, 0.5"].into_iter().zip(1..) {
+    ///     let parsed = Transaction::parse_into(line, &mut scratch)?;
+    ///     assert_eq!(parsed, line.parse()?);
+    ///     assert_eq!(parsed.tx, tx);
+    /// }
+    /// # Ok::<(), PenguinError>(())
+    /// ```
+    pub fn parse_into(line: &str, scratch: &mut ParseScratch) -> Result<Self, PenguinError> {
+        let (len, fields) = scratch.tokenize(line);
+        let output = &scratch.output[..len];
+        let mut start = 0;
+        let fields = scratch.ends[..fields].iter().map(|&end| {
+            let field = &output[start..end];
+            start = end;
+            // Only ASCII quotes and delimiters are dropped from the UTF-8 line.
+            std::str::from_utf8(field).expect("fields of a str are valid UTF-8")
+        });
+
+        Transaction::from_fields(fields, &scratch.format)
+    }
+
+    /// Deposit of `amount` into `client`'s account.
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// # use rust_decimal::Decimal;
+    /// let tx = Transaction::deposit(1, 1, Decimal::new(15, 1));
+    ///
+    /// assert_eq!(tx.tx_type, TransactionType::Deposit);
+    /// assert_eq!(tx.amount, Some(Decimal::new(15, 1)));
+    /// ```
+    pub fn deposit(client: u16, tx: u32, amount: Decimal) -> Self {
+        Self::new(TransactionType::Deposit, client, tx, Some(amount))
+    }
+
+    /// Withdrawal of `amount` from `client`'s account.
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// # use rust_decimal::Decimal;
+    /// let tx = Transaction::withdrawal(1, 2, Decimal::ONE);
+    ///
+    /// assert_eq!(tx.tx_type, TransactionType::Withdrawal);
+    /// assert_eq!(tx.amount, Some(Decimal::ONE));
+    /// ```
+    pub fn withdrawal(client: u16, tx: u32, amount: Decimal) -> Self {
+        Self::new(TransactionType::Withdrawal, client, tx, Some(amount))
+    }
+
+    /// Dispute of deposit `tx`. Use [`Transaction::with_amount`] to dispute only part of it.
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// let tx = Transaction::dispute(1, 1);
+    ///
+    /// assert_eq!(tx.tx_type, TransactionType::Dispute);
+    /// assert_eq!(tx.amount, None);
+    /// ```
+    pub fn dispute(client: u16, tx: u32) -> Self {
+        Self::new(TransactionType::Dispute, client, tx, None)
+    }
+
+    /// Resolve of disputed deposit `tx`, releasing its held funds.
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// let tx = Transaction::resolve(1, 1);
+    ///
+    /// assert_eq!(tx.tx_type, TransactionType::Resolve);
+    /// assert_eq!(tx.amount, None);
+    /// ```
+    pub fn resolve(client: u16, tx: u32) -> Self {
+        Self::new(TransactionType::Resolve, client, tx, None)
+    }
+
+    /// Chargeback of disputed deposit `tx`, which also locks the account.
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// let tx = Transaction::chargeback(1, 1);
+    ///
+    /// assert_eq!(tx.tx_type, TransactionType::Chargeback);
+    /// assert_eq!(tx.amount, None);
+    /// ```
+    pub fn chargeback(client: u16, tx: u32) -> Self {
+        Self::new(TransactionType::Chargeback, client, tx, None)
+    }
+
+    /// Unlock of `client`'s account after a chargeback, identified as transaction `tx`.
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// let tx = Transaction::unlock(1, 9);
+    ///
+    /// assert_eq!(tx.tx_type, TransactionType::Unlock);
+    /// assert_eq!(tx.amount, None);
+    /// ```
+    pub fn unlock(client: u16, tx: u32) -> Self {
+        Self::new(TransactionType::Unlock, client, tx, None)
+    }
+
+    /// Interest of `amount` credited to `client`'s account.
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// # use rust_decimal::Decimal;
+    /// let tx = Transaction::interest(1, 3, Decimal::new(5, 2));
+    ///
+    /// assert_eq!(tx.tx_type, TransactionType::Interest);
+    /// assert_eq!(tx.amount, Some(Decimal::new(5, 2)));
+    /// ```
+    pub fn interest(client: u16, tx: u32, amount: Decimal) -> Self {
+        Self::new(TransactionType::Interest, client, tx, Some(amount))
+    }
+
+    /// Set the amount, e.g. to dispute or resolve only part of a deposit.
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// # use rust_decimal::Decimal;
+    /// let tx = Transaction::dispute(1, 1).with_amount(Decimal::ONE);
+    ///
+    /// assert_eq!(tx.amount, Some(Decimal::ONE));
+    /// ```
+    pub fn with_amount(self, amount: Decimal) -> Self {
+        Self {
+            amount: Some(amount),
+            ..self
+        }
+    }
+
+    /// Check the transaction makes sense on its own, before it reaches a client account.
+    ///
+    /// Deposits, withdrawals and interest need an amount, failing with
+    /// [`PenguinError::DepositOrWithdrawalWithoutAmount`] otherwise. The amount of a
+    /// dispute or resolve is optional and only covers part of the deposit, the one of a
+    /// chargeback or unlock is ignored. Transactions of an unknown type pass, workers
+    /// ignore them as [`UnknownType`](crate::prelude::IgnoreReason::UnknownType).
+    ///
+    /// Workers run this check before applying every transaction.
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// # use rust_decimal::Decimal;
+    /// assert!(Transaction::deposit(1, 1, Decimal::ONE).validate().is_ok());
+    /// assert!(Transaction::dispute(1, 1).validate().is_ok());
+    ///
+    /// let mut tx = Transaction::withdrawal(1, 2, Decimal::ONE);
+    /// tx.amount = None;
+    /// assert!(tx.validate().is_err());
+    /// ```
+    pub fn validate(&self) -> Result<(), PenguinError> {
+        match self.tx_type {
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Interest
+                if self.amount.is_none() =>
+            {
+                Err(PenguinError::DepositOrWithdrawalWithoutAmount(self.client))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Apply the transaction to the `currency` balances instead of the default ones.
+    ///
+    /// ```
+    /// # use libpenguin::prelude::*;
+    /// # use rust_decimal::Decimal;
+    /// let tx = Transaction::deposit(1, 1, Decimal::TEN).with_currency("EUR");
+    ///
+    /// assert_eq!(tx.currency.as_deref(), Some("EUR"));
+    /// ```
+    pub fn with_currency(self, currency: impl Into<String>) -> Self {
+        Self {
+            currency: Some(currency.into()),
+            ..self
+        }
+    }
+
+    fn new(tx_type: TransactionType, client: u16, tx: u32, amount: Option<Decimal>) -> Self {
+        Self {
+            tx_type,
+            client,
+            tx,
+            amount,
+            currency: None,
+        }
+    }
+
+    /// Build a transaction from its raw `type, client, tx, amount, currency` fields,
+    /// read as `format` says.
+    pub(crate) fn from_fields<'a>(
+        fields: impl Iterator<Item = &'a str>,
+        format: &TransactionFormat,
+    ) -> Result<Self, PenguinError> {
+        let missing = |found| PenguinError::FieldCount {
+            line: 1,
+            expected: 4,
+            found,
+        };
+        let mut parts = fields.map(|part| part.trim());
+        let tx_type = match parts.next().ok_or(missing(0))? {
             "deposit" => TransactionType::Deposit,
             "withdrawal" => TransactionType::Withdrawal,
             "dispute" => TransactionType::Dispute,
             "resolve" => TransactionType::Resolve,
             "chargeback" => TransactionType::Chargeback,
+            "interest" => TransactionType::Interest,
+            "unlock" => TransactionType::Unlock,
+            _ if format.skip_unknown_types => TransactionType::Unknown,
             other => {
                 return Err(PenguinError::TransactionParse(Cow::Owned(format!(
                     "unexpected type: {other}"
                 ))));
             }
         };
-        let client = parts
-            .next()
-            .ok_or(PenguinError::TransactionParse(Cow::Borrowed(
-                "client is required",
-            )))?
-            .parse()
-            .map_err(|_| PenguinError::TransactionParse(Cow::Borrowed("client must be a u16")))?;
-        let tx = parts
-            .next()
-            .ok_or(PenguinError::TransactionParse(Cow::Borrowed(
-                "tx is required",
-            )))?
-            .parse()
-            .map_err(|_| PenguinError::TransactionParse(Cow::Borrowed("tx must be a u32")))?;
+        let client = parts.next().ok_or(missing(1)).and_then(|raw| {
+            raw.parse::<u16>().map_err(|_| {
+                PenguinError::TransactionParse(Cow::Owned(format!(
+                    "client must be a u16, got `{raw}`"
+                )))
+            })
+        })?;
+        let tx = parts.next().ok_or(missing(2)).and_then(|raw| {
+            raw.parse::<u32>().map_err(|_| {
+                PenguinError::TransactionParse(Cow::Owned(format!("tx must be a u32, got `{raw}`")))
+            })
+        })?;
         let amount = match parts.next() {
-            Some(raw) if !raw.is_empty() => Some(
-                Decimal::from_str(raw)
-                    .map_err(|_| {
-                        PenguinError::TransactionParse(Cow::Borrowed("amount must be decimal"))
-                    })?
-                    .round_dp(4),
-            ),
-            _ => None,
+            Some(raw) if !raw.is_empty() => {
+                let amount = parse_amount(raw, format.decimal_separator)?;
+                if format.strict_precision {
+                    check_precision(amount, format.amount_decimals)
+                        .map_err(|err| PenguinError::TransactionParse(Cow::Owned(err)))?;
+                }
+                Some(amount.round_dp(format.amount_decimals))
+            }
+            Some(_) => None,
+            // Only types that need an amount need its column.
+            None if matches!(
+                tx_type,
+                TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Interest
+            ) =>
+            {
+                return Err(missing(3));
+            }
+            None => None,
         };
+        let currency = parts
+            .next()
+            .filter(|raw| !raw.is_empty())
+            .map(str::to_owned);
+        let extra = parts.count();
+        if extra > 0 {
+            return Err(PenguinError::FieldCount {
+                line: 1,
+                expected: 5,
+                found: 5 + extra,
+            });
+        }
 
         Ok(Transaction {
             tx_type,
             client,
             tx,
             amount,
+            currency,
         })
     }
 }
 
 /// Current state for a client.
-#[derive(Debug)]
+///
+/// Fields are only readable through accessors so that a state always comes out of the
+/// engine with `total == available + held`; tests can build one with explicit balances
+/// through `ClientState::with_balances` under the `test-utils` feature.
+///
+/// ```compile_fail
+/// # use libpenguin::prelude::*;
+/// # use rust_decimal::Decimal;
+/// let state = ClientState {
+///     available: Decimal::ONE,
+///     ..ClientState::new(1)
+/// };
+/// ```
+#[derive(Debug, Clone)]
 pub struct ClientState {
     /// Client identifier.
-    pub client: u16,
+    pub(crate) client: u16,
+    /// Funds available for withdrawal.
+    pub(crate) available: Decimal,
+    /// Funds held due to disputes.
+    pub(crate) held: Decimal,
+    /// Total funds (available + held).
+    pub(crate) total: Decimal,
+    /// Whether the account is locked by a chargeback.
+    pub(crate) locked: bool,
+    /// Number of disputes applied to this account.
+    pub(crate) disputes_seen: u32,
+    /// Id of the last transaction that changed this account, ignored ones excluded.
+    pub(crate) last_tx: Option<u32>,
+    /// Id of the first transaction seen for this account, whether applied or ignored.
+    pub(crate) created_by_tx: Option<u32>,
+    /// Balances for transactions that carry an explicit currency, kept apart from the
+    /// default balances above and from each other.
+    pub(crate) currencies: BTreeMap<String, Balances>,
+}
+
+/// Funds of a client in a single currency.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Balances {
     /// Funds available for withdrawal.
     pub available: Decimal,
     /// Funds held due to disputes.
     pub held: Decimal,
     /// Total funds (available + held).
     pub total: Decimal,
+}
+
+/// A client state along with the disputed deposits behind its held funds, see
+/// [`Penguin::client_details`](crate::prelude::Penguin::client_details).
+#[derive(Debug, Clone)]
+pub struct ClientDetails {
+    /// The client state, as in the regular output.
+    pub state: ClientState,
+    /// Funds held by each disputed deposit in the default currency, keyed by transaction.
+    pub held_breakdown: HashMap<u32, Decimal>,
+}
+
+/// A single output row: one client in one currency.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientRow<'a> {
+    /// Client identifier.
+    pub client: u16,
+    /// Currency of the balances, `None` for the default balances. Left out of the
+    /// `Serialize` output like `created_by_tx`, a
+    /// [`StateSchema`](crate::prelude::StateSchema) can add it with
+    /// [`Column::Currency`](crate::prelude::Column::Currency).
+    pub currency: Option<&'a str>,
+    /// Balances in that currency.
+    pub balances: Balances,
     /// Whether the account is locked by a chargeback.
     pub locked: bool,
+    /// Number of disputes applied to this account.
+    pub disputes_seen: u32,
+    /// Id of the last transaction that changed this account.
+    pub last_tx: Option<u32>,
+    /// Id of the first transaction seen for this account. Left out of the `Serialize`
+    /// output, a [`StateSchema`](crate::prelude::StateSchema) can add it with
+    /// [`Column::CreatedByTx`](crate::prelude::Column::CreatedByTx).
+    pub created_by_tx: Option<u32>,
 }
 
-impl Serialize for ClientState {
+impl Serialize for ClientRow<'_> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let format_decimal = |value: Decimal| value.round_dp(4).normalize().to_string();
-
-        let mut state = serializer.serialize_struct("ClientState", 5)?;
+        let mut state = serializer.serialize_struct("ClientState", 7)?;
         state.serialize_field("client", &self.client)?;
-        state.serialize_field("available", &format_decimal(self.available))?;
-        state.serialize_field("held", &format_decimal(self.held))?;
-        state.serialize_field("total", &format_decimal(self.total))?;
+        state.serialize_field("available", &format_amount(self.balances.available))?;
+        state.serialize_field("held", &format_amount(self.balances.held))?;
+        state.serialize_field("total", &format_amount(self.balances.total))?;
         state.serialize_field("locked", &self.locked)?;
+        state.serialize_field("disputes_seen", &self.disputes_seen)?;
+        state.serialize_field("last_tx", &self.last_tx)?;
         state.end()
     }
 }
 
+/// Serializes the default-currency row. Use [`ClientState::rows`] to also emit the
+/// per-currency balances.
+impl Serialize for ClientState {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.row(None, self.balances(None)).serialize(serializer)
+    }
+}
+
+/// Decimal places amounts are kept with, anything further is rounded.
+pub(crate) const AMOUNT_DECIMALS: u32 = 4;
+
+/// Reject an amount with more significant decimal places than `decimals`.
+pub(crate) fn check_precision(amount: Decimal, decimals: u32) -> Result<(), String> {
+    if amount.normalize().scale() > decimals {
+        return Err(format!(
+            "amount `{amount}` has more than {decimals} decimal places"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Render an amount the way client states are written out: 4 decimals at most,
+/// without trailing zeros.
+pub(crate) fn format_amount(value: Decimal) -> String {
+    format_amount_dp(value, AMOUNT_DECIMALS)
+}
+
+/// Render an amount with `decimals` places at most, without trailing zeros.
+pub(crate) fn format_amount_dp(value: Decimal, decimals: u32) -> String {
+    value.round_dp(decimals).normalize().to_string()
+}
+
 impl ClientState {
     /// Create a new client state.
     pub fn new(client: u16) -> Self {
@@ -121,16 +789,290 @@ impl ClientState {
             held: Decimal::ZERO,
             total: Decimal::ZERO,
             locked: false,
+            disputes_seen: 0,
+            last_tx: None,
+            created_by_tx: None,
+            currencies: BTreeMap::new(),
+        }
+    }
+
+    /// Create a state with explicit default balances, `total` being their sum.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn with_balances(client: u16, available: Decimal, held: Decimal, locked: bool) -> Self {
+        Self {
+            available,
+            held,
+            total: available + held,
+            locked,
+            ..Self::new(client)
+        }
+    }
+
+    /// Client identifier.
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    /// Funds available for withdrawal.
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+
+    /// Funds held due to disputes.
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    /// Total funds (available + held).
+    pub fn total(&self) -> Decimal {
+        self.total
+    }
+
+    /// Whether the account is locked by a chargeback.
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Number of disputes applied to this account.
+    pub fn disputes_seen(&self) -> u32 {
+        self.disputes_seen
+    }
+
+    /// Id of the last transaction that changed this account, ignored ones excluded.
+    pub fn last_tx(&self) -> Option<u32> {
+        self.last_tx
+    }
+
+    /// Id of the first transaction seen for this account, whether applied or ignored.
+    pub fn created_by_tx(&self) -> Option<u32> {
+        self.created_by_tx
+    }
+
+    /// Balances in each explicit currency, see [`ClientState::balances`].
+    pub fn currencies(&self) -> &BTreeMap<String, Balances> {
+        &self.currencies
+    }
+
+    /// Whether the account is open and holds nothing, in any currency.
+    pub(crate) fn is_zero_balance(&self) -> bool {
+        !self.locked
+            && self.balances(None) == Balances::default()
+            && self
+                .currencies
+                .values()
+                .all(|balances| *balances == Balances::default())
+    }
+
+    /// Balances in `currency`, or the default balances when `None`.
+    pub fn balances(&self, currency: Option<&str>) -> Balances {
+        match currency {
+            None => Balances {
+                available: self.available,
+                held: self.held,
+                total: self.total,
+            },
+            Some(currency) => self.currencies.get(currency).copied().unwrap_or_default(),
+        }
+    }
+
+    /// Replace the balances in `currency`, or the default balances when `None`.
+    pub(crate) fn set_balances(&mut self, currency: Option<&str>, balances: Balances) {
+        match currency {
+            None => {
+                self.available = balances.available;
+                self.held = balances.held;
+                self.total = balances.total;
+            }
+            Some(currency) => {
+                self.currencies.insert(currency.to_owned(), balances);
+            }
+        }
+    }
+
+    /// One output row per currency, default balances first.
+    ///
+    /// The default row is skipped for clients that only ever used explicit currencies.
+    pub fn rows(&self) -> impl Iterator<Item = ClientRow<'_>> {
+        let default = self.balances(None);
+        let default = (self.currencies.is_empty() || default != Balances::default())
+            .then(|| self.row(None, default));
+
+        default.into_iter().chain(
+            self.currencies
+                .iter()
+                .map(|(currency, balances)| self.row(Some(currency.as_str()), *balances)),
+        )
+    }
+
+    fn row<'a>(&self, currency: Option<&'a str>, balances: Balances) -> ClientRow<'a> {
+        ClientRow {
+            client: self.client,
+            currency,
+            balances,
+            locked: self.locked,
+            disputes_seen: self.disputes_seen,
+            last_tx: self.last_tx,
+            created_by_tx: self.created_by_tx,
         }
     }
 }
 
+/// Hash map used on the hot path, keyed by small integers. The `fxhash` feature swaps
+/// SipHash for the much faster, non DoS-resistant, Fx hasher.
+#[cfg(not(feature = "fxhash"))]
+pub(crate) type Map<K, V> = std::collections::HashMap<K, V>;
+#[cfg(feature = "fxhash")]
+pub(crate) type Map<K, V> = rustc_hash::FxHashMap<K, V>;
+
+/// Hash set counterpart of [`Map`].
+#[cfg(not(feature = "fxhash"))]
+pub(crate) type Set<T> = std::collections::HashSet<T>;
+#[cfg(feature = "fxhash")]
+pub(crate) type Set<T> = rustc_hash::FxHashSet<T>;
+
 /// Convenience alias for (client_id, transaction_id)
 pub(crate) type ClientTx = (u16, u32);
 
+/// Convenience alias for (line_number, transaction) as sent to workers.
+pub(crate) type LineTx = (usize, Transaction);
+
+/// Transactions sent to a worker in a single channel message.
+pub(crate) type Batch = Vec<LineTx>;
+
+/// Client states plus the registry of disputable deposits, owned by a worker
+/// while running and by the engine in between runs.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Ledger {
+    pub(crate) client_states: Map<u16, ClientState>,
+    pub(crate) client_tx_registry: Map<ClientTx, Deposit>,
+    /// Deposits and withdrawals already applied, tracked for idempotent reprocessing.
+    pub(crate) processed: Set<ClientTx>,
+}
+
+/// A deposit kept in the registry so it can be disputed later.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Deposit {
+    pub(crate) amount: Decimal,
+    /// Part of the amount not disputed yet.
+    pub(crate) disputable: Decimal,
+    /// Part of the amount currently held under dispute.
+    pub(crate) held: Decimal,
+    /// Part of the amount taken back by a chargeback.
+    pub(crate) charged_back: Decimal,
+    /// Currency the deposit was made in.
+    pub(crate) currency: Option<String>,
+    /// How the deposit stopped being disputable, kept to tell later references to it
+    /// apart from references to unknown transactions.
+    pub(crate) settled: Option<Settlement>,
+}
+
+/// Final state of a deposit that can't be disputed anymore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Settlement {
+    /// Every disputed part was resolved and nothing is left to dispute.
+    Resolved,
+    /// The dispute ended in a chargeback.
+    ChargedBack,
+}
+
+impl Deposit {
+    pub(crate) fn new(amount: Decimal) -> Self {
+        Self {
+            amount,
+            disputable: amount,
+            held: Decimal::ZERO,
+            charged_back: Decimal::ZERO,
+            currency: None,
+            settled: None,
+        }
+    }
+
+    /// Whether any part of the deposit is currently under dispute.
+    pub(crate) fn disputed(&self) -> bool {
+        !self.held.is_zero()
+    }
+
+    /// Whether a dispute, resolve or chargeback in `currency` may reference this deposit.
+    /// Transactions without a currency inherit the deposit's.
+    pub(crate) fn accepts(&self, currency: Option<&str>) -> bool {
+        currency.is_none_or(|currency| self.currency.as_deref() == Some(currency))
+    }
+}
+
+impl Ledger {
+    /// Move every entry of `other` into this ledger.
+    ///
+    /// Workers own disjoint sets of clients, so a client in both ledgers means the
+    /// sharding is broken. Everything else is still absorbed, the state already in this
+    /// ledger is kept, and the client is reported as [`PenguinError::InvariantViolation`].
+    pub(crate) fn absorb(&mut self, other: Ledger) -> Result<(), PenguinError> {
+        let mut duplicate = None;
+        for (client, state) in other.client_states {
+            match self.client_states.entry(client) {
+                Entry::Vacant(entry) => {
+                    entry.insert(state);
+                }
+                Entry::Occupied(_) => {
+                    duplicate.get_or_insert(client);
+                }
+            }
+        }
+        self.client_tx_registry.extend(other.client_tx_registry);
+        self.processed.extend(other.processed);
+
+        match duplicate {
+            Some(client) => Err(PenguinError::InvariantViolation(
+                client,
+                "client was owned by more than one worker",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Move out the clients `client % num_workers == group` routes to, with their
+    /// deposits and processed ids.
+    pub(crate) fn take_group(&mut self, group: u16, num_workers: u16) -> Ledger {
+        let owned = |client: u16| client % num_workers == group;
+        Ledger {
+            client_states: self
+                .client_states
+                .extract_if(|&client, _| owned(client))
+                .collect(),
+            client_tx_registry: self
+                .client_tx_registry
+                .extract_if(|&(client, _), _| owned(client))
+                .collect(),
+            processed: self
+                .processed
+                .extract_if(|&(client, _)| owned(client))
+                .collect(),
+        }
+    }
+}
+
+/// Order of the client states returned by a run, see
+/// [`PenguinBuilder::with_output_order`](crate::prelude::PenguinBuilder::with_output_order).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputOrder {
+    /// Order in which clients first appeared in the input, over every run so far.
+    /// Clients restored from a snapshot come last, by ascending id.
+    FirstSeen,
+    /// Ascending client id.
+    ClientAsc,
+    /// Grouped by the worker each client is routed to, in worker order, then by ascending
+    /// id within a worker. The same input and worker count always give the same order,
+    /// whatever the hasher.
+    #[default]
+    WorkerNative,
+}
+
 /// Supported transaction types.
-#[derive(Debug, Deserialize, PartialEq)]
+///
+/// Non exhaustive, so handling new types doesn't break matches outside the crate. Until
+/// then they are read as [`TransactionType::Unknown`].
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
+#[non_exhaustive]
 pub enum TransactionType {
     /// Increase available funds.
     Deposit,
@@ -142,6 +1084,37 @@ pub enum TransactionType {
     Resolve,
     /// Finalize a dispute and lock the account.
     Chargeback,
+    /// Credit accrued interest to available funds. Unlike a deposit, it can't be disputed.
+    Interest,
+    /// Lift the lock a chargeback put on the account, see
+    /// [`PenguinBuilder::with_restore_disputes_on_unlock`](crate::prelude::PenguinBuilder::with_restore_disputes_on_unlock).
+    Unlock,
+    /// A type this version doesn't handle, only read with
+    /// [`TransactionFormat::with_unknown_types_skipped`]. The engine skips it.
+    #[serde(skip)]
+    Unknown,
+}
+
+impl TransactionType {
+    /// Lowercase name of the type, as read from the input and written by serde.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Interest => "interest",
+            TransactionType::Unlock => "unlock",
+            TransactionType::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 /// Errors emitted by the engine and helpers.
@@ -153,13 +1126,716 @@ pub enum PenguinError {
     /// Parsing failed at a given line number in the input.
     #[error("Error while parsing on line {0}")]
     Parse(usize),
-    /// Failed to send a transaction to a worker channel.
-    #[error("Error sending transaction to the channel: {0}")]
-    ChannelSend(#[from] SendError<Transaction>),
+    /// A worker stopped before taking the transactions of `client` it owns, e.g. after
+    /// a strict invariant violation or a panic.
+    #[error("Worker {worker} stopped before receiving the transactions of client {client}")]
+    WorkerChannelClosed { worker: u16, client: u16 },
+    /// A charged back transaction was disputed again, with strict disputes on.
+    #[error("Client {client} disputed transaction {tx}, which was already charged back")]
+    DisputeAfterChargeback { client: u16, tx: u32 },
     /// Deposit/withdrawal was missing an amount.
     #[error("Client {0} received a deposit/withdrawal transaction with no amount associated.")]
     DepositOrWithdrawalWithoutAmount(u16),
+    /// A row had too few or too many fields. `line` is counted from the start of the
+    /// input when the reader knows it, and is 1 for a single parsed line.
+    #[error("Line {line} has {found} fields, expected {expected}")]
+    FieldCount {
+        line: usize,
+        expected: usize,
+        found: usize,
+    },
+    /// A row of the input couldn't be used, for the reason in `source`. `line` is counted
+    /// like for [`PenguinError::Parse`].
+    #[error("Invalid row on line {line}: {source}")]
+    InvalidRow {
+        line: usize,
+        #[source]
+        source: Box<PenguinError>,
+    },
     /// Transaction text did not match the expected CSV-like format.
     #[error("Error parsing transaction: {0}")]
     TransactionParse(Cow<'static, str>),
+    /// A ledger invariant was broken, by a transaction (which was then skipped) or by
+    /// workers owning the same client.
+    #[error("Client {0} ledger invariant violated: {1}")]
+    InvariantViolation(u16, &'static str),
+    /// A client was locked in one partial output and active in another.
+    #[error("Client {0} is locked in one partial output and active in another")]
+    MergeConflict(u16),
+    /// More workers were requested than clients can be sharded over: client ids are
+    /// `u16`, so at most `u16::MAX` workers are supported.
+    #[error("{0} workers requested, at most {max} are supported", max = u16::MAX)]
+    TooManyWorkers(usize),
+    /// A negative withdrawal fee was configured, which would credit every withdrawal.
+    #[error("Withdrawal fee {0} is negative")]
+    NegativeFee(Decimal),
+    /// A worker task panicked or was cancelled, losing the clients it owned.
+    #[error("Worker task failed: {0}")]
+    WorkerPanicked(String),
+    /// A snapshot blob could not be decoded.
+    #[error("Invalid snapshot: {0}")]
+    Snapshot(Cow<'static, str>),
+    /// A line of the processed ids file isn't a `client,tx` pair.
+    #[error("Invalid processed id on line {line}: `{content}`")]
+    ProcessedId { line: usize, content: String },
+    /// An amount in the input has more digits than a [`Decimal`] can hold, e.g. more than
+    /// 28 decimal places or a value beyond `Decimal::MAX`.
+    #[error("Amount `{0}` is out of range")]
+    AmountOutOfRange(String),
+    /// An output sink failed to store client states.
+    #[error("Sink error: {0}")]
+    Sink(#[source] Box<dyn std::error::Error + Send + Sync>),
+    /// The state of `client` couldn't be written to a sink, even after retrying.
+    #[error("Failed to write client {client} to the sink: {source}")]
+    SinkWrite {
+        client: u16,
+        #[source]
+        source: Box<PenguinError>,
+    },
+}
+
+impl PenguinError {
+    /// Place the error on input `line`: errors that already name a line take this one,
+    /// any other is wrapped in a [`PenguinError::InvalidRow`].
+    pub(crate) fn at_line(self, line: usize) -> Self {
+        match self {
+            PenguinError::FieldCount {
+                expected, found, ..
+            } => PenguinError::FieldCount {
+                line,
+                expected,
+                found,
+            },
+            PenguinError::Parse(_) => PenguinError::Parse(line),
+            PenguinError::InvalidRow { source, .. } => PenguinError::InvalidRow { line, source },
+            other => PenguinError::InvalidRow {
+                line,
+                source: Box::new(other),
+            },
+        }
+    }
+}
+
+/// Lets readers that can't fail, like [`InMemoryReader`](crate::prelude::InMemoryReader),
+/// feed the engine.
+impl From<Infallible> for PenguinError {
+    fn from(never: Infallible) -> Self {
+        match never {}
+    }
+}
+
+/// Collapse an engine error into an [`io::Error`], e.g. to use `?` in I/O code.
+///
+/// I/O errors are unwrapped as they are. Bad input maps to [`io::ErrorKind::InvalidData`],
+/// workers that stopped to [`io::ErrorKind::BrokenPipe`], an unsupported configuration
+/// to [`io::ErrorKind::InvalidInput`] and anything else to [`io::ErrorKind::Other`], with
+/// the engine error as the source.
+impl From<PenguinError> for io::Error {
+    fn from(err: PenguinError) -> Self {
+        let kind = match err {
+            PenguinError::IO(err) => return err,
+            PenguinError::Parse(_)
+            | PenguinError::FieldCount { .. }
+            | PenguinError::InvalidRow { .. }
+            | PenguinError::TransactionParse(_)
+            | PenguinError::DepositOrWithdrawalWithoutAmount(_)
+            | PenguinError::Snapshot(_)
+            | PenguinError::ProcessedId { .. }
+            | PenguinError::MergeConflict(_)
+            | PenguinError::AmountOutOfRange(_)
+            | PenguinError::DisputeAfterChargeback { .. } => io::ErrorKind::InvalidData,
+            PenguinError::WorkerChannelClosed { .. } => io::ErrorKind::BrokenPipe,
+            PenguinError::InvariantViolation(..)
+            | PenguinError::WorkerPanicked(_)
+            | PenguinError::Sink(_)
+            | PenguinError::SinkWrite { .. } => io::ErrorKind::Other,
+            PenguinError::TooManyWorkers(_) | PenguinError::NegativeFee(_) => {
+                io::ErrorKind::InvalidInput
+            }
+        };
+
+        io::Error::new(kind, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_amounts_are_put_back_together() {
+        let format = TransactionFormat::default();
+
+        let tx = format
+            .parse_split_amount("deposit, 1, 1, 12, 3400")
+            .expect("valid line");
+        assert_eq!(
+            tx.amount.map(|amount| amount.to_string()).as_deref(),
+            Some("12.3400")
+        );
+
+        for (line, expected) in [
+            ("withdrawal, 1, 2, 0, 0034", Some(Decimal::new(34, 4))),
+            ("withdrawal, 1, 2, -1, 5", Some(Decimal::new(-15, 1))),
+            ("deposit, 1, 3, 7,", Some(Decimal::from(7))),
+            ("dispute, 1, 1, ,", None),
+        ] {
+            let tx = format.parse_split_amount(line).expect(line);
+            assert_eq!(tx.amount, expected, "{line}");
+        }
+
+        let tx = format
+            .with_delimiter(b';')
+            .with_decimal_separator(',')
+            .parse_split_amount("deposit; 1; 4; 12; 34; EUR")
+            .expect("valid line");
+        assert_eq!(tx.amount, Some(Decimal::new(1234, 2)));
+        assert_eq!(tx.currency.as_deref(), Some("EUR"));
+
+        for line in [
+            "deposit, 1, 1, 12.5, 0",
+            "deposit, 1, 1, 12, -34",
+            "deposit, 1, 1, , 34",
+        ] {
+            assert!(
+                matches!(
+                    format.parse_split_amount(line),
+                    Err(PenguinError::TransactionParse(_))
+                ),
+                "{line}"
+            );
+        }
+        assert!(matches!(
+            format.parse_split_amount("deposit, 1, 1, 12"),
+            Err(PenguinError::FieldCount { found: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn validate_checks_the_amount_of_each_type() {
+        use TransactionType as TType;
+
+        for tx_type in [
+            TType::Deposit,
+            TType::Withdrawal,
+            TType::Interest,
+            TType::Dispute,
+            TType::Resolve,
+            TType::Chargeback,
+            TType::Unlock,
+            TType::Unknown,
+        ] {
+            for amount in [Some(Decimal::ONE), None] {
+                let tx = Transaction::new(tx_type, u16::MAX, u32::MAX, amount);
+                let result = tx.validate();
+                match (tx_type, amount) {
+                    (TType::Deposit | TType::Withdrawal | TType::Interest, None) => {
+                        assert!(
+                            matches!(
+                                result,
+                                Err(PenguinError::DepositOrWithdrawalWithoutAmount(u16::MAX))
+                            ),
+                            "{tx:?}"
+                        );
+                    }
+                    _ => assert!(result.is_ok(), "{tx:?}"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn transaction_from_str_handles_quotes_and_whitespace() {
+        let tx: Transaction = "\"deposit\",\"1\",\"2\", 1.5   "
+            .parse()
+            .expect("valid transaction");
+
+        assert_eq!(tx.tx_type, TransactionType::Deposit);
+        assert_eq!(tx.client, 1);
+        assert_eq!(tx.tx, 2);
+        assert_eq!(tx.amount, Some(Decimal::from_str("1.5").unwrap()));
+
+        let tx: Transaction = "dispute, 1, 2,  ".parse().expect("valid transaction");
+        assert_eq!(tx.tx_type, TransactionType::Dispute);
+        assert_eq!(tx.amount, None);
+    }
+
+    #[test]
+    fn type_names_match_the_wire_spelling() {
+        for tx_type in [
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+            TransactionType::Interest,
+            TransactionType::Unlock,
+        ] {
+            let name = tx_type.as_str();
+            assert_eq!(tx_type.to_string(), name);
+            assert_eq!(
+                serde_json::to_string(&tx_type).unwrap(),
+                format!("\"{name}\"")
+            );
+            let tx: Transaction = format!("{name}, 1, 2, 1.0")
+                .parse()
+                .expect("valid transaction");
+            assert_eq!(tx.tx_type, tx_type);
+        }
+    }
+
+    #[test]
+    fn rows_with_a_wrong_number_of_fields_are_field_count_errors() {
+        let err = "deposit, 1, 2"
+            .parse::<Transaction>()
+            .expect_err("amount column is missing");
+        assert!(matches!(
+            err,
+            PenguinError::FieldCount {
+                line: 1,
+                expected: 4,
+                found: 3,
+            }
+        ));
+        assert_eq!(err.to_string(), "Line 1 has 3 fields, expected 4");
+
+        let err = "deposit, 1, 2, 1.0, EUR, note"
+            .parse::<Transaction>()
+            .expect_err("one column too many");
+        assert!(matches!(
+            err,
+            PenguinError::FieldCount {
+                line: 1,
+                expected: 5,
+                found: 6,
+            }
+        ));
+
+        // The currency column is optional, and so is the amount one when no amount is needed.
+        assert!("deposit, 1, 2, 1.0, EUR".parse::<Transaction>().is_ok());
+        assert!("dispute, 1, 2".parse::<Transaction>().is_ok());
+    }
+
+    #[test]
+    fn penguin_errors_convert_to_io_errors() {
+        let parse: io::Error = PenguinError::Parse(3).into();
+        assert_eq!(parse.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(parse.to_string(), "Error while parsing on line 3");
+
+        let parse: io::Error = "deposit, x, 1, 1.0"
+            .parse::<Transaction>()
+            .expect_err("invalid client")
+            .into();
+        assert_eq!(parse.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            parse
+                .get_ref()
+                .is_some_and(|inner| inner.is::<PenguinError>())
+        );
+
+        let io: io::Error =
+            PenguinError::IO(io::Error::new(io::ErrorKind::NotFound, "missing")).into();
+        assert_eq!(io.kind(), io::ErrorKind::NotFound);
+        assert_eq!(io.to_string(), "missing");
+
+        let closed: io::Error = PenguinError::WorkerChannelClosed {
+            worker: 1,
+            client: 2,
+        }
+        .into();
+        assert_eq!(closed.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn penguin_errors_chain_their_sources() {
+        use std::error::Error as _;
+
+        let err = PenguinError::IO(io::Error::other("disk gone"));
+        assert_eq!(
+            err.source().map(ToString::to_string).as_deref(),
+            Some("disk gone")
+        );
+
+        let err = PenguinError::Sink("table locked".into());
+        assert_eq!(
+            err.source().map(ToString::to_string).as_deref(),
+            Some("table locked")
+        );
+    }
+
+    #[test]
+    fn transaction_from_str_tolerates_crlf_and_blank_amounts() {
+        for line in [
+            "deposit,1,1,1.5\r",
+            "deposit,1,1,1.5\r\n",
+            "deposit, 1, 1, 1.5 \r",
+        ] {
+            let tx: Transaction = line.parse().expect("valid transaction");
+            assert_eq!(tx.amount, Some(Decimal::new(15, 1)), "{line:?}");
+        }
+
+        for line in [
+            "dispute,1,1,",
+            "dispute,1,1, ",
+            "dispute,1,1,\t",
+            "dispute,1,1,\r",
+            "dispute,1,1, \r\n",
+            "dispute,1,1\r",
+        ] {
+            let tx: Transaction = line.parse().expect("valid transaction");
+            assert_eq!(tx.amount, None, "{line:?}");
+            assert_eq!(
+                Transaction::parse_into(line, &mut ParseScratch::new()).map_err(|e| e.to_string()),
+                Ok(tx),
+                "{line:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn interest_is_read_by_from_str_and_serde() {
+        let tx: Transaction = "interest, 1, 2, 0.5".parse().expect("valid transaction");
+        assert_eq!(tx, Transaction::interest(1, 2, Decimal::new(5, 1)));
+
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader("type, client, tx, amount\ninterest, 1, 2, 0.5\n".as_bytes());
+        let record: Transaction = reader
+            .deserialize()
+            .next()
+            .expect("one record")
+            .expect("valid record");
+        assert_eq!(record, tx);
+    }
+
+    #[test]
+    fn too_precise_amounts_are_rounded_unless_strict() {
+        let lenient = TransactionFormat::default();
+        let tx = lenient
+            .parse("deposit, 1, 1, 1.23456")
+            .expect("rounded amount");
+        assert_eq!(tx.amount, Some(Decimal::new(12346, 4)));
+
+        let strict = lenient.with_strict_precision(true);
+        let err = strict
+            .parse("deposit, 1, 1, 1.23456")
+            .expect_err("too precise amount");
+        assert!(
+            matches!(err, PenguinError::TransactionParse(ref msg) if msg.contains("1.23456")),
+            "{err}"
+        );
+
+        // Trailing zeros don't count as precision.
+        for raw in ["1.2345", "1.234500", "1.2345e0"] {
+            let tx = strict
+                .parse(&format!("deposit, 1, 1, {raw}"))
+                .expect("precise enough");
+            assert_eq!(tx.amount, Some(Decimal::new(12345, 4)), "{raw}");
+        }
+    }
+
+    #[test]
+    fn amount_decimals_keep_token_precision() {
+        let format = TransactionFormat::default().with_amount_decimals(18);
+        let tx = format
+            .parse("deposit, 1, 1, 1.123456789012345678")
+            .expect("18 decimal amount");
+        assert_eq!(tx.amount, Some(Decimal::new(1_123_456_789_012_345_678, 18)));
+
+        let strict = format.with_strict_precision(true);
+        assert!(strict.parse("deposit, 1, 1, 0.000000000000000001").is_ok());
+        assert!(
+            strict
+                .parse("deposit, 1, 1, 0.0000000000000000001")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn unrepresentable_amounts_are_out_of_range() {
+        let format = TransactionFormat::default().with_amount_decimals(28);
+        for raw in [
+            "99999999999999999999999999999999",
+            "0.00000000000000000000000000001",
+        ] {
+            let err = format
+                .parse(&format!("deposit, 1, 1, {raw}"))
+                .expect_err("out of range");
+            assert!(
+                matches!(err, PenguinError::AmountOutOfRange(ref amount) if amount == raw),
+                "{err}"
+            );
+        }
+
+        let err = format
+            .parse("deposit, 1, 1, 1.2.3")
+            .expect_err("bad amount");
+        assert!(matches!(err, PenguinError::TransactionParse(_)), "{err}");
+    }
+
+    #[test]
+    fn parse_into_matches_from_str() {
+        let lines = [
+            "deposit, 1, 1, 1.5",
+            "withdrawal, 2, 2, 0.25",
+            "dispute, 1, 1,",
+            "dispute, 1, 1, 0.5",
+            "resolve, 1, 1",
+            "deposit, 1, 9, 1.0, EUR, x",
+            "chargeback, 3, 7,  ",
+            "interest, 3, 8, 0.01",
+            "\"deposit\",\"1\",\"2\", 1.5   ",
+            "deposit, 1, 3, \"1 000.50\"",
+            "deposit, 1, 4, 1.5e3, EUR\r\n",
+            "deposit, 1, 5, 1.5\n",
+            "deposit, 1, 6, 1.5\ndeposit, 1, 7, 2.5",
+            "teleport, 1, 1, 1.0",
+            "deposit, one, 1, 1.0",
+            "deposit, 1",
+            "",
+        ];
+        let mut scratch = ParseScratch::new();
+        // A long line forces the buffers to grow once.
+        let long = format!("deposit, 1, 8, 1.0, {}", "X".repeat(1_000));
+
+        for line in lines.iter().copied().chain([long.as_str()]) {
+            let expected = line.parse::<Transaction>();
+            match Transaction::parse_into(line, &mut scratch) {
+                Ok(tx) => assert_eq!(Ok(tx), expected.map_err(|err| err.to_string()), "{line}"),
+                Err(err) => assert_eq!(
+                    Some(err.to_string()),
+                    expected.err().map(|err| err.to_string()),
+                    "{line}"
+                ),
+            }
+        }
+
+        let format = TransactionFormat::default()
+            .with_delimiter(b';')
+            .with_decimal_separator(',');
+        let mut scratch = ParseScratch::with_format(format);
+        for line in ["deposit; 1; 1; 1,50", "withdrawal; 1; 2; 1.50"] {
+            assert_eq!(
+                Transaction::parse_into(line, &mut scratch).map_err(|err| err.to_string()),
+                format.parse(line).map_err(|err| err.to_string()),
+                "{line}"
+            );
+        }
+    }
+
+    #[test]
+    fn transaction_from_str_accepts_grouped_and_scientific_amounts() {
+        for (raw, expected) in [
+            ("1_000.50", "1000.50"),
+            ("\"1 000.50\"", "1000.50"),
+            ("1 000 000", "1000000"),
+            ("1.5e3", "1500"),
+            ("2.5E-2", "0.025"),
+            ("1_0e1", "100"),
+            ("1.23456e2", "123.4560"),
+        ] {
+            let tx: Transaction = format!("deposit, 1, 2, {raw}")
+                .parse()
+                .expect("valid transaction");
+            assert_eq!(
+                tx.amount,
+                Some(Decimal::from_str(expected).unwrap()),
+                "{raw}"
+            );
+        }
+
+        for raw in ["1e", "1__0x", "e3", "1.5e3.2"] {
+            let err = format!("deposit, 1, 2, {raw}")
+                .parse::<Transaction>()
+                .expect_err("expected amount parse error");
+            assert!(
+                matches!(&err, PenguinError::TransactionParse(msg) if msg.contains(raw)),
+                "unexpected error for {raw}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn transaction_from_str_keeps_quoted_commas_in_one_field() {
+        let err = "deposit,1,2,\"1,5\""
+            .parse::<Transaction>()
+            .expect_err("expected amount parse error");
+
+        match err {
+            PenguinError::TransactionParse(msg) => {
+                assert!(msg.contains("`1,5`"), "unexpected message: {msg}");
+            }
+            other => panic!("unexpected error: {other}"),
+        }
+    }
+
+    #[test]
+    fn transaction_format_reads_semicolons_and_decimal_commas() {
+        let format = TransactionFormat::default()
+            .with_delimiter(b';')
+            .with_decimal_separator(',');
+
+        let tx = format
+            .parse("withdrawal; 3; 7; 1,50; EUR")
+            .expect("valid transaction");
+        assert_eq!(tx.tx_type, TransactionType::Withdrawal);
+        assert_eq!(tx.client, 3);
+        assert_eq!(tx.tx, 7);
+        assert_eq!(tx.amount, Some(Decimal::from_str("1.50").unwrap()));
+        assert_eq!(tx.currency.as_deref(), Some("EUR"));
+
+        let tx = format.parse("dispute; 3; 7;").expect("valid transaction");
+        assert_eq!(tx.amount, None);
+
+        // A dot is ambiguous with a comma separator, so it's rejected.
+        assert!(format.parse("deposit; 3; 8; 1.50").is_err());
+        // The default format is untouched.
+        assert!("deposit; 3; 8; 1,50".parse::<Transaction>().is_err());
+    }
+
+    #[test]
+    fn unknown_types_fail_unless_skipped() {
+        let err = "transfer, 1, 5, 0.01"
+            .parse::<Transaction>()
+            .expect_err("expected unknown type to be rejected");
+        assert!(matches!(err, PenguinError::TransactionParse(_)));
+
+        let tx = TransactionFormat::default()
+            .with_unknown_types_skipped(true)
+            .parse("transfer, 1, 5, 0.01")
+            .expect("unknown type is read");
+        assert_eq!(tx.tx_type, TransactionType::Unknown);
+        assert_eq!(tx.client, 1);
+        assert_eq!(tx.tx, 5);
+    }
+
+    #[test]
+    fn transaction_from_str_reads_optional_currency() {
+        let tx: Transaction = "deposit, 1, 2, 1.5, EUR"
+            .parse()
+            .expect("valid transaction");
+        assert_eq!(tx.currency.as_deref(), Some("EUR"));
+
+        let tx: Transaction = "dispute, 1, 2, , ".parse().expect("valid transaction");
+        assert_eq!(tx.currency, None);
+    }
+
+    #[test]
+    fn transactions_round_trip_through_json() {
+        for tx in [
+            Transaction::deposit(1, 1, Decimal::new(15, 1)),
+            Transaction::withdrawal(1, 2, Decimal::ONE).with_currency("EUR"),
+            Transaction::dispute(1, 1),
+            Transaction::resolve(1, 1).with_amount(Decimal::ONE),
+            Transaction::chargeback(1, 1),
+        ] {
+            let json = serde_json::to_value(&tx).expect("serializable transaction");
+            assert!(json.get("type").is_some(), "unexpected JSON: {json}");
+            if tx.amount.is_none() {
+                assert_eq!(json["amount"], serde_json::Value::Null);
+            }
+
+            let parsed: Transaction = serde_json::from_value(json).expect("valid JSON");
+            assert_eq!(parsed, tx);
+        }
+
+        let tx: Transaction =
+            serde_json::from_str(r#"{"type":"dispute","client":1,"tx":2}"#).expect("valid JSON");
+        assert_eq!(tx, Transaction::dispute(1, 2));
+    }
+
+    #[test]
+    fn client_rows_emit_one_row_per_currency() {
+        use crate::schema::{Column, StateSchema};
+
+        let mut state = ClientState::new(4);
+        state.currencies.insert(
+            "USD".into(),
+            Balances {
+                available: Decimal::new(5, 0),
+                held: Decimal::ZERO,
+                total: Decimal::new(5, 0),
+            },
+        );
+        state.currencies.insert(
+            "EUR".into(),
+            Balances {
+                available: Decimal::new(1, 0),
+                held: Decimal::new(2, 0),
+                total: Decimal::new(3, 0),
+            },
+        );
+
+        let schema = StateSchema::new()
+            .column(Column::Client)
+            .column(Column::Currency)
+            .column(Column::Available)
+            .column(Column::Held)
+            .column(Column::Total);
+        let mut writer = csv::Writer::from_writer(vec![]);
+        schema.write_csv(&mut writer, &[state]).expect("write rows");
+        let output = String::from_utf8(writer.into_inner().expect("flush")).expect("utf8");
+
+        assert_eq!(
+            output,
+            "client,currency,available,held,total\n\
+             4,EUR,1,2,3\n\
+             4,USD,5,0,5\n"
+        );
+    }
+
+    #[test]
+    fn transaction_from_string_record() {
+        let record = csv::StringRecord::from(vec!["withdrawal", " 3", "7 ", " 1.25"]);
+
+        let tx = Transaction::try_from(&record).expect("valid record");
+
+        assert_eq!(tx.tx_type, TransactionType::Withdrawal);
+        assert_eq!(tx.client, 3);
+        assert_eq!(tx.tx, 7);
+        assert_eq!(tx.amount, Some(Decimal::from_str("1.25").unwrap()));
+    }
+
+    #[test]
+    fn transaction_from_string_record_names_bad_column() {
+        let record = csv::StringRecord::from(vec!["deposit", "abc", "1", "1.0"]);
+
+        let err = Transaction::try_from(&record).expect_err("expected bad client id");
+
+        match err {
+            PenguinError::TransactionParse(msg) => {
+                assert!(msg.starts_with("client"), "unexpected message: {msg}");
+                assert!(msg.contains("abc"), "unexpected message: {msg}");
+            }
+            other => panic!("unexpected error: {other}"),
+        }
+    }
+
+    #[test]
+    fn take_group_moves_out_the_clients_of_one_worker() {
+        let mut ledger = Ledger::default();
+        for client in 1..=3 {
+            ledger
+                .client_states
+                .insert(client, ClientState::new(client));
+            ledger
+                .client_tx_registry
+                .insert((client, u32::from(client)), Deposit::new(Decimal::ONE));
+            ledger.processed.insert((client, u32::from(client)));
+        }
+
+        let group = ledger.take_group(1, 2);
+
+        let mut clients: Vec<_> = group.client_states.into_keys().collect();
+        clients.sort_unstable();
+        assert_eq!(clients, [1, 3]);
+        assert_eq!(group.client_tx_registry.len(), 2);
+        assert_eq!(group.processed, Set::from_iter([(1, 1), (3, 3)]));
+        assert_eq!(ledger.client_states.keys().collect::<Vec<_>>(), [&2]);
+        assert_eq!(
+            ledger.client_tx_registry.keys().collect::<Vec<_>>(),
+            [&(2, 2)]
+        );
+        assert_eq!(ledger.processed, Set::from_iter([(2, 2)]));
+    }
 }
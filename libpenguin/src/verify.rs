@@ -0,0 +1,105 @@
+use crate::types::{Balances, ClientState, PenguinError};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::{collections::BTreeMap, io, path::Path};
+
+/// A client whose final balances or lock differ from the expected ones, see
+/// [`Penguin::verify_against`](crate::prelude::Penguin::verify_against).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    /// Client identifier.
+    pub client: u16,
+    /// Currency of the balances, `None` for the default balances.
+    pub currency: Option<String>,
+    /// Expected balances, `None` when the expectations don't list the client.
+    pub expected: Option<Balances>,
+    /// Balances after the run, `None` when the run never saw the client.
+    pub actual: Option<Balances>,
+    /// Expected lock, `None` when the expectations don't list the client or have no
+    /// `locked` column.
+    pub expected_locked: Option<bool>,
+    /// Lock after the run, `None` when the run never saw the client.
+    pub actual_locked: Option<bool>,
+}
+
+/// A row of the expectations file, in the shape of the CLI output.
+#[derive(Deserialize)]
+struct Expected {
+    client: u16,
+    #[serde(default)]
+    currency: Option<String>,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    #[serde(default)]
+    locked: Option<bool>,
+}
+
+/// Compare `states` to the balances listed in the CSV file at `path`, with amounts rounded
+/// to `decimals` places, returning the differences by client and currency. Locks are only
+/// compared when the file has a `locked` column.
+pub(crate) fn verify(
+    states: &[ClientState],
+    path: &Path,
+    decimals: u32,
+) -> Result<Vec<Mismatch>, PenguinError> {
+    let round = |balances: Balances| Balances {
+        available: balances.available.round_dp(decimals),
+        held: balances.held.round_dp(decimals),
+        total: balances.total.round_dp(decimals),
+    };
+
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(io::Error::from)?;
+    let mut expected = BTreeMap::new();
+    for row in reader.deserialize::<Expected>() {
+        let row = row.map_err(io::Error::from)?;
+        let balances = Balances {
+            available: row.available,
+            held: row.held,
+            total: row.total,
+        };
+        expected.insert((row.client, row.currency), (round(balances), row.locked));
+    }
+
+    let mut actual = BTreeMap::new();
+    for row in states.iter().flat_map(ClientState::rows) {
+        let key = (row.client, row.currency.map(str::to_owned));
+        actual.insert(key, (round(row.balances), row.locked));
+    }
+
+    let mut mismatches = Vec::new();
+    for (key, &(expected, expected_locked)) in &expected {
+        let actual = actual.get(key).copied();
+        let matches = actual.is_some_and(|(balances, locked)| {
+            balances == expected && expected_locked.is_none_or(|expected| expected == locked)
+        });
+        if !matches {
+            mismatches.push(Mismatch {
+                client: key.0,
+                currency: key.1.clone(),
+                expected: Some(expected),
+                actual: actual.map(|(balances, _)| balances),
+                expected_locked,
+                actual_locked: actual.map(|(_, locked)| locked),
+            });
+        }
+    }
+    for ((client, currency), (actual, locked)) in actual {
+        if !expected.contains_key(&(client, currency.clone())) {
+            mismatches.push(Mismatch {
+                client,
+                currency,
+                expected: None,
+                actual: Some(actual),
+                expected_locked: None,
+                actual_locked: Some(locked),
+            });
+        }
+    }
+    mismatches.sort_by(|a, b| (a.client, &a.currency).cmp(&(b.client, &b.currency)));
+
+    Ok(mismatches)
+}
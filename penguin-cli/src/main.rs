@@ -1,7 +1,14 @@
 use clap::Parser;
-use csv::{ReaderBuilder, Trim, WriterBuilder};
+use csv::{ReaderBuilder, StringRecord, Trim, WriterBuilder};
+use flate2::{Compression, write::GzEncoder};
 use libpenguin::prelude::*;
-use std::{io, num::NonZeroUsize};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufWriter},
+    num::NonZeroUsize,
+    path::PathBuf,
+};
 use thiserror::Error;
 
 /// Penguin CLI - A command line tool to process a list of transactions with Penguin Engine
@@ -9,8 +16,50 @@ use thiserror::Error;
 struct Args {
     /// Input CSV file
     input: String,
+    /// Print an aligned, human-readable table instead of CSV
+    #[arg(long)]
+    human: bool,
+    /// Also write a JSON summary of the run to this file
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+    /// Number of workers [default: available parallelism]
+    #[arg(long, value_name = "N")]
+    workers: Option<NonZeroUsize>,
+    /// Number of transactions each worker channel buffers [default: 1024]
+    #[arg(long, value_name = "N")]
+    channel_capacity: Option<NonZeroUsize>,
+    /// Decimal places of the output amounts, from 0 to 28
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u32).range(0..=28)
+    )]
+    precision: u32,
+    /// Write the output to this file instead of stdout, gzip-compressed if it ends in .gz
+    #[arg(long, short, value_name = "PATH")]
+    output: Option<PathBuf>,
+    /// Gzip-compress the output
+    #[arg(long)]
+    gzip: bool,
 }
 
+impl Args {
+    /// Whether the output is gzip-compressed, on request or by the output file extension.
+    fn gzip(&self) -> bool {
+        self.gzip
+            || self
+                .output
+                .as_ref()
+                .is_some_and(|path| path.extension().is_some_and(|ext| ext == "gz"))
+    }
+}
+
+/// Columns every input file must have, in any order.
+const REQUIRED_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+/// Columns an input file may have on top of the required ones.
+const OPTIONAL_COLUMNS: [&str; 1] = ["currency"];
+
 #[derive(Error, Debug)]
 enum CliError {
     #[error("Penguin error: {0}")]
@@ -19,32 +68,363 @@ enum CliError {
     Csv(#[from] csv::Error),
     #[error("I/O error: {0}")]
     IO(#[from] io::Error),
+    #[error(
+        "Invalid CSV headers: missing columns [{}], unexpected columns [{}]",
+        missing.join(", "),
+        unexpected.join(", ")
+    )]
+    Headers {
+        missing: Vec<String>,
+        unexpected: Vec<String>,
+    },
+}
+
+/// Lowercase the header names and check them against the expected columns.
+///
+/// Column order is free, but every required column must be there exactly once and
+/// nothing else is allowed, so typos fail loudly instead of silently misparsing.
+fn normalize_headers(headers: &StringRecord) -> Result<StringRecord, CliError> {
+    let normalized: StringRecord = headers
+        .iter()
+        .map(|header| header.trim().to_ascii_lowercase())
+        .collect();
+
+    let missing: Vec<String> = REQUIRED_COLUMNS
+        .iter()
+        .filter(|column| !normalized.iter().any(|header| header == **column))
+        .map(|column| column.to_string())
+        .collect();
+
+    let mut seen = HashSet::new();
+    let unexpected: Vec<String> = normalized
+        .iter()
+        .filter(|header| {
+            let known = REQUIRED_COLUMNS.contains(header) || OPTIONAL_COLUMNS.contains(header);
+            let first = seen.insert(*header);
+            !known || !first
+        })
+        .map(str::to_owned)
+        .collect();
+
+    if missing.is_empty() && unexpected.is_empty() {
+        Ok(normalized)
+    } else {
+        Err(CliError::Headers {
+            missing,
+            unexpected,
+        })
+    }
+}
+
+/// Run the engine over the input file with the options from the command line.
+async fn process(args: &Args) -> Result<Vec<ClientState>, CliError> {
+    let mut reader = ReaderBuilder::new()
+        .trim(Trim::All)
+        .from_path(&args.input)?;
+    let headers = normalize_headers(reader.headers()?)?;
+    reader.set_headers(headers);
+
+    let num_workers = args.workers.unwrap_or_else(|| {
+        std::thread::available_parallelism().unwrap_or(
+            NonZeroUsize::new(4).unwrap(), // Not zero, so cannot fail
+        )
+    });
+
+    let builder = PenguinBuilder::from_csv_reader(reader).with_num_workers(num_workers);
+    let builder = match args.channel_capacity {
+        Some(capacity) => builder.with_channel_capacity(capacity),
+        None => builder,
+    };
+    let builder = match &args.report {
+        Some(path) => builder.with_report_path(path).with_input_name(&args.input),
+        None => builder,
+    };
+    let mut penguin = builder.build()?;
+
+    Ok(penguin.run().await?)
+}
+
+/// Write the client states as CSV, or as a table with `--human`, rounding amounts to
+/// the requested precision.
+fn write_output(
+    args: &Args,
+    states: &[ClientState],
+    mut out: impl io::Write,
+) -> Result<(), CliError> {
+    if args.human {
+        write!(out, "{}", report_with_precision(states, args.precision))?;
+        return Ok(());
+    }
+
+    // Rows of a client in several currencies are told apart by their currency column.
+    let schema = StateSchema::default().with_precision(args.precision);
+    let schema = if states.iter().any(|state| !state.currencies().is_empty()) {
+        schema.column(Column::Currency)
+    } else {
+        schema
+    };
+    let mut writer = WriterBuilder::new().has_headers(true).from_writer(out);
+    schema.write_csv(&mut writer, states)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Write the output like [`write_output`], through a gzip encoder when requested, and
+/// finish the compressed stream.
+fn write_compressed(
+    args: &Args,
+    states: &[ClientState],
+    out: impl io::Write,
+) -> Result<(), CliError> {
+    if !args.gzip() {
+        return write_output(args, states, out);
+    }
+
+    let mut encoder = GzEncoder::new(out, Compression::default());
+    write_output(args, states, &mut encoder)?;
+    encoder.finish()?.flush()?;
+
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), CliError> {
     let args = Args::parse();
-    let mut reader = ReaderBuilder::new().trim(Trim::All).from_path(args.input)?;
-    let reader = reader.deserialize();
+    // Installed here rather than in `process` so tests can run it without a global subscriber.
+    let _logger = Logger::try_init_from_path("penguin.log", LogFormat::default())?;
+    let output = process(&args).await?;
 
-    let num_workers = std::thread::available_parallelism().unwrap_or(
-        NonZeroUsize::new(4).unwrap(), // Not zero, so cannot fail
-    );
+    match &args.output {
+        Some(path) => write_compressed(&args, &output, BufWriter::new(File::create(path)?)),
+        None => write_compressed(&args, &output, io::stdout().lock()),
+    }
+}
 
-    let mut penguin = PenguinBuilder::from_reader(reader)
-        .with_num_workers(num_workers)
-        .with_logger("penguin.log")
-        .build()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(line: &str) -> StringRecord {
+        line.split(',').collect()
+    }
 
-    let output = penguin.run().await?;
+    #[test]
+    fn reordered_headers_are_accepted() {
+        let input = "Amount, tx, TYPE, client\n1.5, 7, deposit, 2\n";
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(input.as_bytes());
+        let normalized = normalize_headers(reader.headers().unwrap()).expect("valid headers");
+        reader.set_headers(normalized);
 
-    let mut writer = WriterBuilder::new()
-        .has_headers(true)
-        .from_writer(io::stdout());
-    for state in output {
-        writer.serialize(state)?;
+        let tx: Transaction = reader
+            .deserialize()
+            .next()
+            .expect("one record")
+            .expect("valid record");
+        assert_eq!(tx.tx_type, TransactionType::Deposit);
+        assert_eq!(tx.client, 2);
+        assert_eq!(tx.tx, 7);
+        assert_eq!(
+            tx.amount.map(|amount| amount.to_string()),
+            Some("1.5".into())
+        );
+
+        assert!(normalize_headers(&headers("currency,tx,client,amount,type")).is_ok());
     }
-    writer.flush()?;
 
-    Ok(())
+    #[tokio::test]
+    async fn worker_and_channel_flags_keep_the_output() {
+        let path = std::env::temp_dir().join(format!("penguin-cli-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,2,2,5.0\n\
+             withdrawal,1,3,2.5\n\
+             dispute,2,2,\n\
+             deposit,3,4,1.0\n",
+        )
+        .unwrap();
+        let input = path.to_str().unwrap();
+        let run = |flags: &[&str]| {
+            let args = Args::try_parse_from(["penguin-cli"].iter().chain(flags).chain([&input]))
+                .expect("valid arguments");
+            async move {
+                let mut output = process(&args).await.expect("run should succeed");
+                output.sort_by_key(ClientState::client);
+                output
+                    .into_iter()
+                    .map(|state| {
+                        (
+                            state.client(),
+                            state.available(),
+                            state.held(),
+                            state.total(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let default = run(&[]).await;
+        let tuned = run(&["--workers", "1", "--channel-capacity", "1"]).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(default.len(), 3);
+        assert_eq!(tuned, default);
+
+        for flag in ["--workers", "--channel-capacity"] {
+            assert!(Args::try_parse_from(["penguin-cli", flag, "0", input]).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn precision_flag_rounds_the_output_columns() {
+        let path =
+            std::env::temp_dir().join(format!("penguin-cli-precision-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\n\
+             deposit,1,1,1.2345\n\
+             deposit,2,2,2.5\n\
+             dispute,2,2,\n",
+        )
+        .unwrap();
+        let input = path.to_str().unwrap();
+        let run = |precision: &str| {
+            let args = Args::try_parse_from([
+                "penguin-cli",
+                "--workers",
+                "1",
+                "--precision",
+                precision,
+                input,
+            ])
+            .expect("valid arguments");
+            async move {
+                let mut states = process(&args).await.expect("run should succeed");
+                states.sort_by_key(ClientState::client);
+                let mut out = Vec::new();
+                write_output(&args, &states, &mut out).expect("write output");
+                String::from_utf8(out).unwrap()
+            }
+        };
+
+        let two = run("2").await;
+        let four = run("4").await;
+        std::fs::remove_file(&path).unwrap();
+
+        let columns = |output: &str| {
+            output
+                .lines()
+                .map(|line| line.split(',').take(4).collect::<Vec<_>>().join(","))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            columns(&two),
+            [
+                "client,available,held,total",
+                "1,1.23,0,1.23",
+                "2,0,2.5,2.5"
+            ]
+        );
+        assert_eq!(
+            columns(&four),
+            [
+                "client,available,held,total",
+                "1,1.2345,0,1.2345",
+                "2,0,2.5,2.5"
+            ]
+        );
+
+        for precision in ["-1", "29", "two"] {
+            assert!(
+                Args::try_parse_from(["penguin-cli", "--precision", precision, input]).is_err()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn gzip_output_decompresses_to_the_plain_output() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let path =
+            std::env::temp_dir().join(format!("penguin-cli-gzip-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\n\
+             deposit,1,1,1.5\n\
+             deposit,2,2,2.5\n\
+             dispute,2,2,\n",
+        )
+        .unwrap();
+        let input = path.to_str().unwrap();
+        let run = |flags: &[&str]| {
+            let args = Args::try_parse_from(
+                ["penguin-cli", "--workers", "1"]
+                    .iter()
+                    .chain(flags)
+                    .chain([&input]),
+            )
+            .expect("valid arguments");
+            async move {
+                let mut states = process(&args).await.expect("run should succeed");
+                states.sort_by_key(ClientState::client);
+                let mut out = Vec::new();
+                write_compressed(&args, &states, &mut out).expect("write output");
+                out
+            }
+        };
+
+        let plain = run(&[]).await;
+        let compressed = run(&["--gzip"]).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_ne!(compressed, plain);
+
+        let mut decompressed = Vec::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .expect("valid gzip stream");
+        assert_eq!(decompressed, plain);
+
+        let args = Args::try_parse_from(["penguin-cli", "-o", "out.csv.gz", input]).unwrap();
+        assert!(args.gzip());
+        let args = Args::try_parse_from(["penguin-cli", "-o", "out.csv", input]).unwrap();
+        assert!(!args.gzip());
+    }
+
+    #[test]
+    fn missing_headers_are_named() {
+        let err = normalize_headers(&headers("type,client,amount")).expect_err("tx is missing");
+
+        match err {
+            CliError::Headers {
+                missing,
+                unexpected,
+            } => {
+                assert_eq!(missing, ["tx"]);
+                assert!(unexpected.is_empty());
+            }
+            other => panic!("unexpected error: {other}"),
+        }
+    }
+
+    #[test]
+    fn extra_and_misspelled_headers_are_named() {
+        let err = normalize_headers(&headers("type,clinet,tx,amount,note,tx"))
+            .expect_err("unexpected columns");
+
+        match err {
+            CliError::Headers {
+                missing,
+                unexpected,
+            } => {
+                assert_eq!(missing, ["client"]);
+                assert_eq!(unexpected, ["clinet", "note", "tx"]);
+            }
+            other => panic!("unexpected error: {other}"),
+        }
+    }
 }